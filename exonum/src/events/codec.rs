@@ -12,41 +12,148 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt;
 use std::io;
 
 use bytes::BytesMut;
 use byteorder::{LittleEndian, ByteOrder};
+use lz4;
 use tokio_io::codec::{Decoder, Encoder};
 
 use messages::{HEADER_LENGTH, MessageBuffer, RawMessage};
-use super::error::other_error;
+use super::error::invalid_data;
+use super::network::Compression;
 
+/// Offset of the total length of the message in the header, the length is a little-endian
+/// `u32` which ends the header.
+pub const LENGTH_OFFSET: usize = 6;
+/// Set in the length field of the header if the payload is compressed.
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+/// Header of the compressed frame, the size of the compressed payload follows the message header.
+pub const COMPRESSED_HEADER_LENGTH: usize = HEADER_LENGTH + 4;
+/// Service and message type of the frame which tells the peer that the compressed
+/// messages can be sent to us. It is swallowed by the decoder.
+pub const NEGOTIATION_SERVICE_ID: u16 = 0xffff;
+/// Message type of the compression ack within `NEGOTIATION_SERVICE_ID`.
+pub const COMPRESSION_ACK_TYPE: u16 = 0;
+
+/// Returns the frame which tells the peer that we accept the messages compressed
+/// with the given algorithm. It looks like a regular message for the older nodes,
+/// which ignore the data received over the outgoing connections.
+pub(crate) fn compression_ack(compression: Compression) -> RawMessage {
+    let mut data = vec![0; HEADER_LENGTH + 1];
+    LittleEndian::write_u16(&mut data[2..4], COMPRESSION_ACK_TYPE);
+    LittleEndian::write_u16(&mut data[4..6], NEGOTIATION_SERVICE_ID);
+    LittleEndian::write_u32(&mut data[LENGTH_OFFSET..HEADER_LENGTH], (HEADER_LENGTH + 1) as u32);
+    data[HEADER_LENGTH] = compression.id();
+    RawMessage::new(MessageBuffer::from_vec(data))
+}
+
+/// Returns the algorithm id if the message is the compression ack.
+fn parse_compression_ack(raw: &RawMessage) -> Option<u8> {
+    let is_ack = raw.len() == HEADER_LENGTH + 1 && raw.service_id() == NEGOTIATION_SERVICE_ID &&
+        raw.message_type() == COMPRESSION_ACK_TYPE;
+    if is_ack {
+        Some(raw.as_ref()[HEADER_LENGTH])
+    } else {
+        None
+    }
+}
+
+/// Codec which frames the messages of the peer connection.
+pub trait PeerCodec
+    : Encoder<Item = RawMessage, Error = io::Error>
+    + Decoder<Item = RawMessage, Error = io::Error> {
+}
+
+impl<T> PeerCodec for T
+where
+    T: Encoder<Item = RawMessage, Error = io::Error>
+        + Decoder<Item = RawMessage, Error = io::Error>,
+{
+}
+
+impl Decoder for Box<PeerCodec> {
+    type Item = RawMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RawMessage>, io::Error> {
+        Decoder::decode(&mut **self, buf)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<RawMessage>, io::Error> {
+        Decoder::decode_eof(&mut **self, buf)
+    }
+}
+
+impl Encoder for Box<PeerCodec> {
+    type Item = RawMessage;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: RawMessage, buf: &mut BytesMut) -> io::Result<()> {
+        Encoder::encode(&mut **self, msg, buf)
+    }
+}
+
+/// Creates the codec for each peer connection, in both directions.
+pub trait CodecFactory: fmt::Debug + Send {
+    /// Returns the codec which rejects the messages longer than `max_message_len` bytes.
+    /// The `compression` from `NetworkConfiguration` may be ignored by the codec.
+    fn new_codec(&self, max_message_len: u32, compression: Option<Compression>) -> Box<PeerCodec>;
+}
+
+/// Default factory which creates `MessagesCodec`.
+#[derive(Debug, Clone, Default)]
+pub struct MessagesCodecFactory;
+
+impl CodecFactory for MessagesCodecFactory {
+    fn new_codec(&self, max_message_len: u32, compression: Option<Compression>) -> Box<PeerCodec> {
+        Box::new(MessagesCodec::with_compression(max_message_len, compression))
+    }
+}
+
+/// Default codec, each frame is a message with the length in its header. The decoder is
+/// a standalone unit which can be fed with arbitrary bytes, e.g. by a fuzzer: it returns
+/// `Ok(None)` until the frame is complete and `Err` for the malformed data, but never panics.
 #[derive(Debug)]
 pub struct MessagesCodec {
     /// Maximum message length (in bytes), gets populated from `ConsensusConfig`.
     max_message_len: u32,
+    compression: Option<Compression>,
+    /// Set once the peer acknowledges that it accepts the compressed messages.
+    compress_output: bool,
 }
 
 impl MessagesCodec {
     pub fn new(max_message_len: u32) -> MessagesCodec {
-        MessagesCodec { max_message_len }
+        MessagesCodec::with_compression(max_message_len, None)
     }
-}
 
-impl Decoder for MessagesCodec {
-    type Item = RawMessage;
-    type Error = io::Error;
+    /// Creates the codec which compresses the messages once the peer sends
+    /// the compression ack. The compressed messages are decoded regardless of `compression`.
+    pub fn with_compression(
+        max_message_len: u32,
+        compression: Option<Compression>,
+    ) -> MessagesCodec {
+        MessagesCodec {
+            max_message_len,
+            compression,
+            compress_output: false,
+        }
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<RawMessage>, io::Error> {
         // Read header
         if buf.len() < HEADER_LENGTH {
             return Ok(None);
         }
         // Check payload len
-        let total_len = LittleEndian::read_u32(&buf[6..10]) as usize;
+        let len_field = LittleEndian::read_u32(&buf[LENGTH_OFFSET..HEADER_LENGTH]);
+        let compressed = len_field & COMPRESSED_FLAG != 0;
+        let total_len = (len_field & !COMPRESSED_FLAG) as usize;
 
         if total_len as u32 > self.max_message_len {
-            return Err(other_error(format!(
+            return Err(invalid_data(format!(
                 "Received message is too long: {}, maximum allowed length is {} bytes",
                 total_len,
                 self.max_message_len,
@@ -54,7 +161,7 @@ impl Decoder for MessagesCodec {
         }
 
         if total_len < HEADER_LENGTH {
-            return Err(other_error(format!(
+            return Err(invalid_data(format!(
                 "Received malicious message with insufficient \
                 size in header: {}, expected header size {}",
                 total_len,
@@ -62,6 +169,10 @@ impl Decoder for MessagesCodec {
             )));
         }
 
+        if compressed {
+            return Self::decode_compressed(buf, total_len);
+        }
+
         // Read message
         if buf.len() >= total_len {
             let data = buf.split_to(total_len).to_vec();
@@ -70,6 +181,92 @@ impl Decoder for MessagesCodec {
         }
         Ok(None)
     }
+
+    /// Decodes the compressed frame, `total_len` is the length of the original message.
+    fn decode_compressed(
+        buf: &mut BytesMut,
+        total_len: usize,
+    ) -> Result<Option<RawMessage>, io::Error> {
+        if buf.len() < COMPRESSED_HEADER_LENGTH {
+            return Ok(None);
+        }
+        let payload_len = total_len - HEADER_LENGTH;
+        let compressed_len = LittleEndian::read_u32(&buf[HEADER_LENGTH..]) as usize;
+        // Only the payloads which become smaller are compressed.
+        if compressed_len >= payload_len {
+            return Err(invalid_data(format!(
+                "Received compressed payload of {} bytes, which is not less \
+                 than the original size {}",
+                compressed_len,
+                payload_len
+            )));
+        }
+        if buf.len() < COMPRESSED_HEADER_LENGTH + compressed_len {
+            return Ok(None);
+        }
+
+        let frame = buf.split_to(COMPRESSED_HEADER_LENGTH + compressed_len);
+        let payload = lz4::block::decompress(
+            &frame[COMPRESSED_HEADER_LENGTH..],
+            Some(payload_len as i32),
+        ).map_err(|e| invalid_data(format!("Malformed compressed payload: {}", e)))?;
+        if payload.len() != payload_len {
+            return Err(invalid_data(format!(
+                "Decompressed payload has {} bytes, expected {}",
+                payload.len(),
+                payload_len
+            )));
+        }
+        let mut data = Vec::with_capacity(total_len);
+        data.extend_from_slice(&frame[..HEADER_LENGTH]);
+        LittleEndian::write_u32(&mut data[LENGTH_OFFSET..HEADER_LENGTH], total_len as u32);
+        data.extend_from_slice(&payload);
+        Ok(Some(RawMessage::new(MessageBuffer::from_vec(data))))
+    }
+
+    /// Writes the message with the compressed payload if it becomes smaller.
+    fn encode_compressed(msg: &RawMessage, buf: &mut BytesMut) -> io::Result<()> {
+        let data = msg.as_ref();
+        let payload = &data[HEADER_LENGTH..];
+        let compressed = lz4::block::compress(payload, None, false)?;
+        if compressed.len() >= payload.len() {
+            buf.extend_from_slice(data);
+            return Ok(());
+        }
+        let mut header = [0; COMPRESSED_HEADER_LENGTH];
+        header[..LENGTH_OFFSET].copy_from_slice(&data[..LENGTH_OFFSET]);
+        LittleEndian::write_u32(
+            &mut header[LENGTH_OFFSET..HEADER_LENGTH],
+            data.len() as u32 | COMPRESSED_FLAG,
+        );
+        LittleEndian::write_u32(&mut header[HEADER_LENGTH..], compressed.len() as u32);
+        buf.reserve(COMPRESSED_HEADER_LENGTH + compressed.len());
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(&compressed);
+        Ok(())
+    }
+}
+
+impl Decoder for MessagesCodec {
+    type Item = RawMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        loop {
+            let raw = match self.decode_frame(buf)? {
+                Some(raw) => raw,
+                None => return Ok(None),
+            };
+            match parse_compression_ack(&raw) {
+                Some(id) => {
+                    if self.compression.map(Compression::id) == Some(id) {
+                        self.compress_output = true;
+                    }
+                }
+                None => return Ok(Some(raw)),
+            }
+        }
+    }
 }
 
 impl Encoder for MessagesCodec {
@@ -77,6 +274,9 @@ impl Encoder for MessagesCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        if self.compress_output && msg.len() > HEADER_LENGTH {
+            return Self::encode_compressed(&msg, buf);
+        }
         buf.extend_from_slice(msg.as_ref());
         Ok(())
     }
@@ -84,28 +284,139 @@ impl Encoder for MessagesCodec {
 
 #[cfg(test)]
 mod test {
-    use super::MessagesCodec;
+    use super::{compression_ack, MessagesCodec, COMPRESSED_FLAG, LENGTH_OFFSET};
+
+    use std::cmp;
 
-    use messages::{MessageBuffer, RawMessage};
+    use messages::{HEADER_LENGTH, MessageBuffer, RawMessage};
     use bytes::BytesMut;
-    use tokio_io::codec::Decoder;
+    use byteorder::{LittleEndian, ByteOrder};
+    use quickcheck::{Arbitrary, Gen};
+    use tokio_io::codec::{Decoder, Encoder};
+    use events::network::Compression;
+
+    const MAX_MESSAGE_LEN: usize = 1000;
+
+    /// Frames with the random type tags and lengths, possibly compressed, the bodies may be
+    /// truncated or longer than the length.
+    #[derive(Debug, Clone)]
+    struct Frames(Vec<u8>);
+
+    impl Arbitrary for Frames {
+        fn arbitrary<G: Gen>(g: &mut G) -> Frames {
+            let frames = g.gen_range(1, 4);
+            Frames((0..frames).flat_map(|_| random_frame(g)).collect())
+        }
+
+        fn shrink(&self) -> Box<Iterator<Item = Frames>> {
+            Box::new(self.0.shrink().map(Frames))
+        }
+    }
+
+    fn random_frame<G: Gen>(g: &mut G) -> Vec<u8> {
+        let mut frame = vec![0; HEADER_LENGTH];
+        g.fill_bytes(&mut frame[..LENGTH_OFFSET]);
+        let len = g.gen_range(0, MAX_MESSAGE_LEN + 100);
+        let mut len_field = len as u32;
+        if g.gen_weighted_bool(4) {
+            len_field |= COMPRESSED_FLAG;
+        }
+        LittleEndian::write_u32(&mut frame[LENGTH_OFFSET..], len_field);
+        let body_len = g.gen_range(0, len + 16);
+        frame.extend((0..body_len).map(|_| g.gen::<u8>()));
+        frame
+    }
+
+    /// Feeds the input to the decoder by chunks, returns `false` if the decoded message
+    /// violates the limits or the decoder doesn't consume the input.
+    fn decodes_within_limits(input: &[u8], chunk_len: u8) -> bool {
+        let chunk_len = cmp::max(usize::from(chunk_len), 1);
+        let mut codec =
+            MessagesCodec::with_compression(MAX_MESSAGE_LEN as u32, Some(Compression::Lz4));
+        let mut bytes = BytesMut::new();
+        for chunk in input.chunks(chunk_len) {
+            bytes.extend_from_slice(chunk);
+            loop {
+                let len_before = bytes.len();
+                match codec.decode(&mut bytes) {
+                    Ok(Some(raw)) => {
+                        let valid = raw.len() >= HEADER_LENGTH && raw.len() <= MAX_MESSAGE_LEN &&
+                            bytes.len() < len_before;
+                        if !valid {
+                            return false;
+                        }
+                    }
+                    Ok(None) => break,
+                    // The connection is closed after the error.
+                    Err(_) => return true,
+                }
+            }
+        }
+        true
+    }
+
+    quickcheck! {
+        fn decode_arbitrary_bytes(input: Vec<u8>, chunk_len: u8) -> bool {
+            decodes_within_limits(&input, chunk_len)
+        }
+
+        fn decode_arbitrary_frames(input: Frames, chunk_len: u8) -> bool {
+            decodes_within_limits(&input.0, chunk_len)
+        }
+    }
 
     #[test]
     fn decode_message_valid_header_size() {
         let data = vec![0u8, 0, 0, 0, 0, 0, 10, 0, 0, 0];
         let mut bytes: BytesMut = data.as_slice().into();
-        let mut codec = MessagesCodec { max_message_len: 10000 };
+        let mut codec = MessagesCodec::new(10000);
         match codec.decode(&mut bytes) {
             Ok(Some(ref r)) if r == &RawMessage::new(MessageBuffer::from_vec(data)) => {}
             _ => panic!("Wrong input"),
         };
     }
 
+    #[test]
+    fn decode_message_too_long_size_in_header() {
+        // Only the header is received, the error should be returned without waiting for the body.
+        let data = vec![0u8, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        let mut bytes: BytesMut = data.as_slice().into();
+        let mut codec = MessagesCodec::new(10000);
+        assert!(codec.decode(&mut bytes).is_err());
+    }
+
     #[test]
     fn decode_message_small_size_in_header() {
         let data = vec![0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         let mut bytes: BytesMut = data.as_slice().into();
-        let mut codec = MessagesCodec { max_message_len: 10000 };
+        let mut codec = MessagesCodec::new(10000);
         assert!(codec.decode(&mut bytes).is_err());
     }
+
+    #[test]
+    fn compression_after_ack() {
+        let mut data = vec![0u8; 1000];
+        data[6..10].copy_from_slice(&[0xe8, 0x03, 0, 0]);
+        let msg = RawMessage::new(MessageBuffer::from_vec(data));
+        let mut codec = MessagesCodec::with_compression(10000, Some(Compression::Lz4));
+
+        // The messages are not compressed until the peer sends the ack.
+        let mut bytes = BytesMut::new();
+        codec.encode(msg.clone(), &mut bytes).unwrap();
+        assert_eq!(bytes.len(), msg.len());
+
+        // The ack is not yielded by the decoder.
+        let mut bytes = BytesMut::new();
+        codec.encode(compression_ack(Compression::Lz4), &mut bytes).unwrap();
+        assert!(codec.decode(&mut bytes).unwrap().is_none());
+        assert!(bytes.is_empty());
+
+        let mut bytes = BytesMut::new();
+        codec.encode(msg.clone(), &mut bytes).unwrap();
+        assert!(bytes.len() < msg.len());
+        // The compressed messages are decoded even if the compression is disabled.
+        let mut decoder = MessagesCodec::new(10000);
+        assert_eq!(decoder.decode(&mut bytes).unwrap(), Some(msg));
+        assert!(bytes.is_empty());
+    }
 }
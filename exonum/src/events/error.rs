@@ -16,7 +16,12 @@
 #![cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
 
 use std::error::Error as StdError;
+use std::fmt;
 use std::io;
+use std::net::SocketAddr;
+
+#[cfg(unix)]
+use libc;
 
 // Common error helpers (TODO move to helpers)
 
@@ -24,6 +29,10 @@ pub fn other_error<S: AsRef<str>>(s: S) -> io::Error {
     io::Error::new(io::ErrorKind::Other, s.as_ref())
 }
 
+pub fn invalid_data<S: AsRef<str>>(s: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, s.as_ref())
+}
+
 pub fn result_ok<T, E: StdError>(_: T) -> Result<(), E> {
     Ok(())
 }
@@ -36,6 +45,153 @@ pub fn into_other<E: StdError>(err: E) -> io::Error {
     other_error(&format!("An error occurred, {}", err.description()))
 }
 
+/// Returns `true` if the error is caused by the lack of the file descriptors, buffers
+/// or memory, so the next attempts are going to fail until the resources are released.
+pub fn is_resource_exhausted(e: &io::Error) -> bool {
+    e.raw_os_error().map_or(
+        false,
+        |code| RESOURCE_EXHAUSTED_ERRORS.contains(&code),
+    )
+}
+
+#[cfg(unix)]
+const RESOURCE_EXHAUSTED_ERRORS: &[i32] = &[
+    libc::EMFILE,
+    libc::ENFILE,
+    libc::ENOBUFS,
+    libc::ENOMEM,
+];
+// `WSAEMFILE` and `WSAENOBUFS`.
+#[cfg(windows)]
+const RESOURCE_EXHAUSTED_ERRORS: &[i32] = &[10_024, 10_055];
+#[cfg(not(any(unix, windows)))]
+const RESOURCE_EXHAUSTED_ERRORS: &[i32] = &[];
+
+/// Reason why the incoming connection is closed.
+///
+/// `HandshakeTimeout` and `Closed` are transient, the peer may connect again successfully.
+/// `BadFirstMessage` and `DecodeError` are terminal, the peer is going to send the same data.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// No `Connect` message is received in `incoming_handshake_timeout`.
+    HandshakeTimeout(io::Error),
+    /// The first message is not a valid `Connect` of the expected protocol version.
+    BadFirstMessage(io::Error),
+    /// The peer has sent the data which can't be decoded into a message.
+    DecodeError(io::Error),
+    /// The connection is closed by the peer or failed.
+    Closed(io::Error),
+}
+
+impl ConnectionError {
+    /// Returns the name of the category which is included into the log messages.
+    pub fn category(&self) -> &'static str {
+        match *self {
+            ConnectionError::HandshakeTimeout(_) => "handshake_timeout",
+            ConnectionError::BadFirstMessage(_) => "bad_first_message",
+            ConnectionError::DecodeError(_) => "decode_error",
+            ConnectionError::Closed(_) => "closed",
+        }
+    }
+
+    /// Returns `true` if the peer may connect successfully next time.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            ConnectionError::HandshakeTimeout(_) |
+            ConnectionError::Closed(_) => true,
+            ConnectionError::BadFirstMessage(_) |
+            ConnectionError::DecodeError(_) => false,
+        }
+    }
+
+    fn inner(&self) -> &io::Error {
+        match *self {
+            ConnectionError::HandshakeTimeout(ref e) |
+            ConnectionError::BadFirstMessage(ref e) |
+            ConnectionError::DecodeError(ref e) |
+            ConnectionError::Closed(ref e) => e,
+        }
+    }
+}
+
+/// The codec errors are `InvalidData`, the others are considered as the closed connection.
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> ConnectionError {
+        if e.kind() == io::ErrorKind::InvalidData {
+            ConnectionError::DecodeError(e)
+        } else {
+            ConnectionError::Closed(e)
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "category={}, {}", self.category(), self.inner())
+    }
+}
+
+impl StdError for ConnectionError {
+    fn description(&self) -> &str {
+        self.inner().description()
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        Some(self.inner())
+    }
+}
+
+/// Reason why the network part is stopped with an error.
+#[derive(Debug)]
+pub enum NetworkError {
+    /// No listen addresses are specified.
+    NoListenAddresses,
+    /// Unable to listen on the address, e.g. it is already in use or the permission is denied.
+    BindFailed {
+        /// Address which can't be bound.
+        addr: SocketAddr,
+        /// Underlying error.
+        source: io::Error,
+    },
+    /// Unable to set up TLS with the given configuration.
+    Tls(io::Error),
+    /// The event loop can't be created or has failed after the network part is started.
+    Runtime(io::Error),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetworkError::NoListenAddresses => f.write_str("no listen addresses specified"),
+            NetworkError::BindFailed { ref addr, ref source } => {
+                write!(f, "unable to listen on {}: {}", addr, source)
+            }
+            NetworkError::Tls(ref e) => write!(f, "unable to set up TLS: {}", e),
+            NetworkError::Runtime(ref e) => write!(f, "network error: {}", e),
+        }
+    }
+}
+
+impl StdError for NetworkError {
+    fn description(&self) -> &str {
+        match *self {
+            NetworkError::NoListenAddresses => "no listen addresses specified",
+            NetworkError::BindFailed { .. } => "unable to listen on the address",
+            NetworkError::Tls(_) => "unable to set up TLS",
+            NetworkError::Runtime(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            NetworkError::NoListenAddresses => None,
+            NetworkError::BindFailed { ref source, .. } => Some(source),
+            NetworkError::Tls(ref e) |
+            NetworkError::Runtime(ref e) => Some(e),
+        }
+    }
+}
+
 pub trait LogError {
     fn log_error(self);
 }
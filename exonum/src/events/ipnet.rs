@@ -0,0 +1,144 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! IP networks in the CIDR notation used to filter the incoming connections.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// IP network in the CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. The address without
+/// the prefix length denotes the single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Fails if the prefix length exceeds the number of bits of the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<IpNet, String> {
+        let max_prefix_len = max_prefix_len(&addr);
+        if prefix_len > max_prefix_len {
+            return Err(format!(
+                "prefix length {} exceeds {}",
+                prefix_len,
+                max_prefix_len
+            ));
+        }
+        Ok(IpNet { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Returns `true` if the address belongs to the network. The IPv4-mapped IPv6 addresses
+    /// belong to the IPv4 networks.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, unmap(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Converts the IPv4-mapped IPv6 address, e.g. accepted by the dual-stack listener, into IPv4.
+fn unmap(addr: &IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = *addr {
+        let segments = v6.segments();
+        if segments[..5].iter().all(|&s| s == 0) && segments[5] == 0xffff {
+            let octets = v6.octets();
+            return IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+        }
+    }
+    *addr
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if net[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+    let rest_bits = prefix_len % 8;
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xff_u8 << (8 - rest_bits);
+    net[full_bytes] & mask == addr[full_bytes] & mask
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpNet, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap_or_default();
+        let addr = IpAddr::from_str(addr).map_err(|e| {
+            format!("invalid address in {}: {}", s, e)
+        })?;
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => {
+                u8::from_str(prefix_len).map_err(|e| {
+                    format!("invalid prefix length in {}: {}", s, e)
+                })?
+            }
+            None => max_prefix_len(&addr),
+        };
+        IpNet::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpNet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNet {
+    fn deserialize<D>(deserializer: D) -> Result<IpNet, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,42 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hooks which let the embedders collect the metrics of the network part.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Receives the network part metrics, all methods do nothing by default.
+///
+/// The methods are called from the event loop of the network part,
+/// so they should not block.
+pub trait NetworkMetrics: fmt::Debug + Send {
+    /// Called once the connection with the peer is established, either outgoing or incoming.
+    fn record_connection_opened(&self, _peer: SocketAddr) {}
+
+    /// Called once the established connection with the peer is closed.
+    fn record_connection_closed(&self, _peer: SocketAddr) {}
+
+    /// Called for every message written to the outgoing connection.
+    fn record_message_sent(&self, _peer: SocketAddr, _bytes: usize) {}
+
+    /// Called once the outgoing connection can't be established after all retries.
+    fn record_connect_failure(&self, _peer: SocketAddr) {}
+}
+
+/// Metrics which are not collected.
+#[derive(Debug, Clone, Default)]
+pub struct NoopMetrics;
+
+impl NetworkMetrics for NoopMetrics {}
@@ -17,18 +17,43 @@ pub mod tests;
 pub mod codec;
 pub mod error;
 pub mod network;
+pub mod tls;
+pub mod socks5;
+pub mod ipnet;
+pub mod proxy_protocol;
+pub mod transport;
+pub mod timer;
+pub mod metrics;
+mod queue;
 pub mod internal;
 
 use std::time::SystemTime;
 use std::cmp::Ordering;
+use std::net::SocketAddr;
 
-use futures::{Future, Async, Poll, Stream};
+use futures::{future, stream, Future, Async, Poll, Sink, Stream};
 use futures::sink::Wait;
 use futures::sync::mpsc::{self, Sender};
 
 use node::{ExternalMessage, NodeTimeout};
-pub use self::network::{NetworkEvent, NetworkRequest, NetworkPart, NetworkConfiguration};
+use messages::Connect;
+pub use self::network::{NetworkEvent, NetworkRequest, NetworkPart, NetworkConfiguration,
+                        RetryStrategy, ConnectionStats, SendResult, ConnectionFilter,
+                        MessageVerifier, Priority, ShutdownHandle, OverflowPolicy,
+                        TcpKeepAliveConfig, NetworkConfigurationBuilder, Compression,
+                        RetryJitter, SinkCounter, NetworkRunSummary, RateLimitPolicy,
+                        RunningFlag, ConnectFailure};
 pub use self::internal::InternalPart;
+pub use self::tls::TlsConfig;
+pub use self::socks5::{ProxyConfig, ProxyAuth};
+pub use self::ipnet::IpNet;
+pub use self::transport::{Transport, TcpTransport, MemoryTransport, Endpoint};
+#[cfg(unix)]
+pub use self::transport::UnixTransport;
+pub use self::timer::{Timer, ReactorTimer, MockTimer};
+pub use self::metrics::{NetworkMetrics, NoopMetrics};
+pub use self::codec::{CodecFactory, MessagesCodec, MessagesCodecFactory, PeerCodec};
+pub use self::error::NetworkError;
 use helpers::{Height, Round};
 
 pub type SyncSender<T> = Wait<Sender<T>>;
@@ -60,6 +85,33 @@ pub enum Event {
 
 pub trait EventHandler {
     fn handle_event(&mut self, event: Event);
+
+    /// Handles the event and returns the requests which `HandlerPart` sends to the network,
+    /// e.g. to disconnect from the misbehaving peer. By default the event is handled
+    /// by `handle_event` and no requests are returned.
+    fn handle_event_with_requests(&mut self, event: Event) -> Vec<NetworkRequest> {
+        self.handle_event(event);
+        Vec::new()
+    }
+
+    /// Returns the future which handles the event, up to the concurrency of `HandlerPart`
+    /// of them are processed at once. The future resolves to the requests sent to the network.
+    /// By default the event is handled synchronously by `handle_event_with_requests`.
+    fn handle_event_async(
+        &mut self,
+        event: Event,
+    ) -> Box<Future<Item = Vec<NetworkRequest>, Error = ()>> {
+        Box::new(future::ok(self.handle_event_with_requests(event)))
+    }
+
+    /// Called by `HandlerPart` before the `PeerConnected` event is passed to `handle_event`,
+    /// `addr` is the address from the `Connect` message rather than the address of the socket.
+    fn on_peer_connected(&mut self, _addr: SocketAddr, _connect: &Connect) {}
+
+    /// Called by `HandlerPart` before the `PeerDisconnected` or `IncomingPeerDisconnected`
+    /// event is passed to `handle_event`. Both events contain the address from the `Connect`
+    /// message, so it matches the address passed to `on_peer_connected`.
+    fn on_peer_disconnected(&mut self, _addr: SocketAddr) {}
 }
 
 
@@ -69,16 +121,78 @@ pub struct HandlerPart<H: EventHandler> {
     pub internal_rx: mpsc::Receiver<InternalEvent>,
     pub network_rx: mpsc::Receiver<NetworkEvent>,
     pub api_rx: mpsc::Receiver<ExternalMessage>,
+    /// Receives the requests returned by the handler.
+    network_requests: mpsc::Sender<NetworkRequest>,
+    /// Maximum number of the events handled concurrently, `1` preserves the order
+    /// of the events and should be used if the handler depends on it.
+    concurrency: usize,
 }
 
 impl<H: EventHandler + 'static> HandlerPart<H> {
+    /// Creates the handler part which processes the events one by one, the requests
+    /// returned by the handler are sent to `network_requests`.
+    pub fn new(
+        handler: H,
+        internal_rx: mpsc::Receiver<InternalEvent>,
+        network_rx: mpsc::Receiver<NetworkEvent>,
+        api_rx: mpsc::Receiver<ExternalMessage>,
+        network_requests: mpsc::Sender<NetworkRequest>,
+    ) -> HandlerPart<H> {
+        Self::with_concurrency(handler, internal_rx, network_rx, api_rx, network_requests, 1)
+    }
+
+    /// Creates the handler part which processes up to `concurrency` events at once,
+    /// the events are still passed to the handler in order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `concurrency` is zero.
+    pub fn with_concurrency(
+        handler: H,
+        internal_rx: mpsc::Receiver<InternalEvent>,
+        network_rx: mpsc::Receiver<NetworkEvent>,
+        api_rx: mpsc::Receiver<ExternalMessage>,
+        network_requests: mpsc::Sender<NetworkRequest>,
+        concurrency: usize,
+    ) -> HandlerPart<H> {
+        assert!(concurrency > 0, "Concurrency should be positive");
+        HandlerPart {
+            handler,
+            internal_rx,
+            network_rx,
+            api_rx,
+            network_requests,
+            concurrency,
+        }
+    }
+
     pub fn run(self) -> Box<Future<Item = (), Error = ()>> {
         let mut handler = self.handler;
+        let concurrency = self.concurrency;
+        let network_requests = self.network_requests;
 
         let fut = EventsAggregator::new(self.internal_rx, self.network_rx, self.api_rx)
-            .for_each(move |event| {
-                handler.handle_event(event);
-                Ok(())
+            .map(move |event| {
+                if let Event::Network(ref network_event) = event {
+                    match *network_event {
+                        NetworkEvent::PeerConnected(_, ref connect) => {
+                            handler.on_peer_connected(connect.addr(), connect)
+                        }
+                        NetworkEvent::PeerDisconnected { addr, .. } |
+                        NetworkEvent::IncomingPeerDisconnected { addr, .. } => {
+                            handler.on_peer_disconnected(addr)
+                        }
+                        _ => {}
+                    }
+                }
+                handler.handle_event_async(event)
+            })
+            .buffer_unordered(concurrency)
+            .for_each(move |requests| {
+                let requests = stream::iter_ok::<_, mpsc::SendError<NetworkRequest>>(requests);
+                network_requests.clone().send_all(requests).map(drop).map_err(
+                    |_| error!("Unable to send network requests, the receiver is dropped."),
+                )
             });
 
         to_box(fut)
@@ -12,54 +12,513 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
+use std::fmt;
 use std::io;
-use std::net::SocketAddr;
-use std::time::Duration;
-use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::iter;
+use std::sync::Arc;
+use std::sync::mpsc as sync_mpsc;
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use futures::{future, unsync, Future, IntoFuture, Sink, Stream, Poll};
-use futures::future::Either;
-use futures::sync::mpsc;
-use tokio_core::net::{TcpListener, TcpStream};
-use tokio_core::reactor::Handle;
+use futures::{future, stream, unsync, Async, AsyncSink, Canceled, Future, IntoFuture, Sink,
+              Stream, Poll};
+use futures::future::{Either, Loop};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::{Core, Handle};
 use tokio_io::AsyncRead;
-use tokio_retry::Retry;
-use tokio_retry::strategy::{jitter, FixedInterval};
+use tokio_io::codec::{Decoder, Encoder};
+use bytes::BytesMut;
+use tokio_retry::strategy::{jitter, ExponentialBackoff, FixedInterval};
 
+use crypto::PublicKey;
 use messages::{Any, Connect, RawMessage, Message};
 use helpers::Milliseconds;
 use super::to_box;
-use super::error::{into_other, log_error, other_error, result_ok};
-use super::codec::MessagesCodec;
+use super::error::{into_other, invalid_data, is_resource_exhausted, log_error, other_error,
+                   result_ok, ConnectionError, NetworkError};
+use super::codec::{compression_ack, CodecFactory, MessagesCodecFactory, PeerCodec};
+use super::tls::{self, TlsConfig, TlsContext};
+use super::socks5::ProxyConfig;
+use super::ipnet::IpNet;
+use super::proxy_protocol;
+use super::transport::{IncomingConnections, PeerStream, TcpTransport, Transport};
+use super::timer::{Delay, ReactorTimer, Ticks, Timer};
+use super::metrics::{NetworkMetrics, NoopMetrics};
+use super::queue::{self, PushError};
 
-const OUTGOING_CHANNEL_SIZE: usize = 10;
+/// Interval of checking whether the receiver of the network events is dropped.
+const NETWORK_TX_CHECK_INTERVAL: Milliseconds = 1_000;
+/// Capacity of the channel returned by `NetworkPart::control_channel`.
+const CONTROL_REQUESTS_CAPACITY: usize = 16;
+/// Capacity of the channels returned by `NetworkPart::subscribe`.
+const SUBSCRIBER_CAPACITY: usize = 256;
+/// Number of the recently connected incoming peers remembered to detect their reconnections.
+const SEEN_PEERS_CAPACITY: usize = 4_096;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum NetworkEvent {
     MessageReceived(SocketAddr, RawMessage),
+    /// The messages read from the incoming connection in one go, emitted instead of
+    /// `MessageReceived` if `incoming_batch_size` is set.
+    MessagesReceived(SocketAddr, Vec<RawMessage>),
+    /// The first message received over the incoming connection after `Connect`, emitted
+    /// instead of `MessageReceived` if `report_first_message` is set.
+    FirstMessage(SocketAddr, RawMessage),
     PeerConnected(SocketAddr, Connect),
-    PeerDisconnected(SocketAddr),
+    /// The outgoing connection with the peer is closed, `duration` is the time since
+    /// the connection has been established.
+    PeerDisconnected { addr: SocketAddr, duration: Duration },
     UnableConnectToPeer(SocketAddr),
+    /// All attempts to establish an outgoing connection with the peer have failed.
+    /// Unlike `PeerDisconnected`, the connection has never been established.
+    ConnectFailed(SocketAddr, ConnectFailure),
+    /// The incoming connection is closed because the peer has sent malformed data,
+    /// contains the reason of the error.
+    PeerErrored(SocketAddr, String),
+    /// The outgoing TCP connection with the peer is established, `connect_duration`
+    /// includes the time spent on all the connection attempts.
+    PeerConnectedOutgoing {
+        addr: SocketAddr,
+        connect_duration: Duration,
+    },
+    /// The message is dropped because the outgoing queue of the peer is full.
+    SendQueueOverflow(SocketAddr),
+    /// The previous attempt to connect with the peer has failed, `attempt` is the number
+    /// of the next attempt starting from 1.
+    ConnectRetry { addr: SocketAddr, attempt: u32 },
+    /// The number of the outgoing connections has reached `max_outgoing_connections`,
+    /// emitted once until the limit is relieved.
+    OutgoingLimitReached,
+    /// The number of the outgoing connections has dropped below `max_outgoing_connections`
+    /// after the limit has been reached.
+    OutgoingLimitRelieved,
+    /// The message sent by `SendMessageTracked` is written to the socket of the peer.
+    MessageSent { addr: SocketAddr, token: u64 },
+    /// The peer with the key from its `Connect` message has been connected recently,
+    /// emitted right before the `PeerConnected` event of the new connection. Contains
+    /// the address from the `Connect` message.
+    PeerReconnected(SocketAddr),
+    /// The message sent by `SendMessageToKey` is dropped because no peer with the key
+    /// has connected to us.
+    UnknownPeerKey(PublicKey),
+    /// The message sent by `SendMessageToHost` is dropped because the host has not been
+    /// resolved into any address.
+    UnresolvedHost(String),
+    /// The incoming connection from the peer with the address from its `Connect` message
+    /// is closed, `duration` is the time since the connection has been accepted.
+    /// The outgoing connection with the same peer, if any, is not affected.
+    IncomingPeerDisconnected { addr: SocketAddr, duration: Duration },
+    /// The incoming connection has sent the message allowed by `pre_connect_messages`
+    /// before the `Connect` message.
+    PreConnectMessage(SocketAddr, RawMessage),
 }
 
-#[derive(Debug, Clone)]
+/// Reason why the outgoing connection can't be established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectFailure {
+    /// The peer hasn't accepted the connection in any of the attempts.
+    Unreachable,
+    /// The node has run out of the file descriptors or memory, the rest of the attempts
+    /// are skipped.
+    ResourceExhausted,
+}
+
+#[derive(Debug)]
 pub enum NetworkRequest {
+    /// Sends the message with the normal priority.
     SendMessage(SocketAddr, RawMessage),
+    /// Sends the message using the queue of the given priority.
+    SendMessagePriority(SocketAddr, RawMessage, Priority),
+    /// Sends the message with the normal priority, overriding `outgoing_overflow_policy`.
+    SendMessageWithPolicy(SocketAddr, RawMessage, OverflowPolicy),
+    /// Sends the message with the normal priority to the peer with the given key, using
+    /// the address from the latest `Connect` message received from the peer.
+    /// The `UnknownPeerKey` event is emitted if there is no such peer.
+    SendMessageToKey(PublicKey, RawMessage),
+    /// Sends the message with the normal priority to the peer with the `host:port` endpoint.
+    /// The host is resolved again on each attempt to connect with the peer, so the changes
+    /// of its address are taken into account once the connection is reestablished.
+    /// The `UnresolvedHost` event is emitted if the host has no addresses.
+    SendMessageToHost(String, RawMessage),
+    /// Sends the message with the normal priority, the `MessageSent` event with the given
+    /// token is emitted once the message is written to the socket.
+    SendMessageTracked(SocketAddr, RawMessage, u64),
+    /// Sends the message with the normal priority and closes the connection once all
+    /// the buffered messages, including this one, are written to the socket. The
+    /// `PeerDisconnected` event is emitted after that, the new messages to the peer
+    /// are dropped until then.
+    SendMessageAndDisconnect(SocketAddr, RawMessage),
+    /// Sends the messages with the normal priority in the given order, the messages
+    /// of the batch are queued before any other request is handled.
+    SendMessages(SocketAddr, Vec<RawMessage>),
+    /// Sends the message without waiting for the free space in the peer's queue
+    /// and reports whether the message has been queued.
+    SendMessageAck(SocketAddr, RawMessage, oneshot::Sender<SendResult>),
+    /// Opens the connection with the peer if there is none, only our `Connect` message
+    /// is sent. The `PeerConnectedOutgoing` event is emitted once it is established.
+    Connect(SocketAddr),
+    /// Registers the established connection with the peer which discards all the messages,
+    /// counting them with the given counter. Replaces the existing connection with the peer.
+    /// Intended for testing the code which sends the messages without a real peer.
+    RegisterSinkPeer(SocketAddr, SinkCounter),
+    /// Replaces the peers, e.g. the validators, whose outgoing connections are reestablished
+    /// automatically if the socket fails. The `PeerDisconnected` event is emitted, but
+    /// the messages buffered for the peer are kept and written to the new connection.
+    /// Applied to the new connections only.
+    SetPersistentPeers(Vec<SocketAddr>),
     DisconnectWithPeer(SocketAddr),
+    /// Stops accepting new messages for the peer and closes the connection
+    /// after the buffered messages are written.
+    DisconnectWithPeerGraceful(SocketAddr),
+    /// Closes all outgoing connections, the `PeerDisconnected` event is emitted for each
+    /// established one. If `graceful` is set, the buffered messages are written before
+    /// the connections are closed, but the pool is emptied immediately in any case.
+    DisconnectAll { graceful: bool },
+    /// Closes the write half of the connection with the peer after the buffered messages
+    /// are written, the connection is kept until the peer closes it. New messages to the peer
+    /// are dropped until then.
+    StopSending(SocketAddr),
+    /// Stops the event loop after all buffered outgoing messages are written
+    /// or the timeout (in milliseconds) expires.
+    GracefulShutdown { timeout: Milliseconds },
+    /// Replaces the network configuration. The limits, `verify_connect_address` and
+    /// `outgoing_overflow_policy` take effect immediately, the socket options, timeouts,
+    /// retries, queue length and proxy are applied to the new connections only.
+    /// The `tls`, `outgoing_idle_timeout` and the listener parameters require restart.
+    Reconfigure(NetworkConfiguration),
+    /// Requests the current connection statistics.
+    QueryConnections(oneshot::Sender<ConnectionStats>),
+    /// Checks whether the outgoing connection with the peer is established,
+    /// the pending connections are not counted.
+    IsConnected(SocketAddr, oneshot::Sender<bool>),
+    /// Requests the number of messages sent to each connected peer.
+    QuerySendCounts(oneshot::Sender<HashMap<SocketAddr, u64>>),
+    /// Requests the numbers of bytes sent to and received from each connected peer,
+    /// including the framing and the compression. The counters are reset when the peer
+    /// reconnects.
+    QueryTraffic(oneshot::Sender<HashMap<SocketAddr, (u64, u64)>>),
+    /// Requests the time elapsed since the last message received from each peer
+    /// over the incoming connection. The peers which have sent only `Connect` are omitted.
+    QueryLastReceived(oneshot::Sender<HashMap<SocketAddr, Duration>>),
+    /// Requests the number of the messages waiting in the queues of each outgoing connection,
+    /// including the pending ones. The steadily growing depth indicates the slow peer.
+    QueryQueueDepths(oneshot::Sender<HashMap<SocketAddr, usize>>),
     Shutdown,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+/// Priority of the outgoing message, the messages with the high priority are written
+/// to the socket before the normal ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Time-critical messages, e.g. consensus ones.
+    High,
+    /// Other messages, e.g. transactions gossip.
+    Normal,
+}
+
+/// Decides which message is dropped when the outgoing queue of the peer is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The new message is dropped.
+    DropNew,
+    /// The oldest queued message is dropped to make room for the new one,
+    /// suitable for the gossip where the recent messages are more valuable.
+    DropOld,
+}
+
+/// Decides what happens with the incoming connection which exceeds the messages rate.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// The messages are not read until the rate allows, so the peer is slowed down
+    /// by the TCP flow control.
+    Throttle,
+    /// The connection is closed with the `PeerErrored` event.
+    Disconnect,
+}
+
+/// Algorithm used to compress the messages sent to the peers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4 block compression of the message payload.
+    Lz4,
+}
+
+impl Compression {
+    /// Identifier of the algorithm sent to the peer during the negotiation.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::Lz4 => 1,
+        }
+    }
+}
+
+/// Result of the `SendMessageAck` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendResult {
+    /// The message is added to the peer's queue.
+    Queued,
+    /// The message is dropped.
+    Rejected { reason: String },
+}
+
+/// Snapshot of the network connections state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Number of the outgoing connections.
+    pub outgoing_count: usize,
+    /// Number of the incoming connections.
+    pub incoming_count: usize,
+    /// Addresses of the peers with outgoing connections, sorted in the ascending order.
+    pub outgoing_peers: Vec<SocketAddr>,
+}
+
+/// Totals of the network part session, returned by `NetworkPart::run` once it is stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkRunSummary {
+    /// Number of the accepted incoming sockets, including the rejected ones.
+    pub connections_accepted: u64,
+    /// Number of the established outgoing connections.
+    pub connections_dialed: u64,
+    /// Number of the outgoing connections which couldn't be established after all retries.
+    pub dial_failures: u64,
+    /// Number of the messages written to the outgoing connections.
+    pub messages_sent: u64,
+    /// Number of the messages read from both the incoming and outgoing connections.
+    pub messages_received: u64,
+    /// Number of the bytes of the encoded frames written to both the incoming
+    /// and outgoing connections.
+    pub bytes_sent: u64,
+    /// Number of the bytes of the encoded frames read from both the incoming
+    /// and outgoing connections.
+    pub bytes_received: u64,
+}
+
+/// Number of the messages discarded by the sink peer, see `NetworkRequest::RegisterSinkPeer`.
+#[derive(Debug, Clone, Default)]
+pub struct SinkCounter(Arc<AtomicUsize>);
+
+impl SinkCounter {
+    pub fn new() -> SinkCounter {
+        SinkCounter::default()
+    }
+
+    /// Returns the number of the messages discarded so far.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Strategy used to compute delays between outgoing connection attempts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryStrategy {
+    /// Every retry happens `tcp_connect_retry_timeout` milliseconds after the previous one.
+    Fixed,
+    /// The `n`-th retry happens `tcp_connect_retry_timeout * base^n` milliseconds after
+    /// the previous one, but no later than `max_interval` milliseconds.
+    Exponential {
+        base: u64,
+        max_interval: Milliseconds,
+    },
+}
+
+impl RetryStrategy {
+    pub(crate) fn delays(
+        &self,
+        timeout: Milliseconds,
+        max_tries: usize,
+        jitter: RetryJitter,
+    ) -> iter::Take<Box<Iterator<Item = Duration>>> {
+        let delays: Box<Iterator<Item = Duration>> = match *self {
+            RetryStrategy::Fixed => Box::new(FixedInterval::from_millis(timeout)),
+            RetryStrategy::Exponential { base, max_interval } => {
+                let strategy = ExponentialBackoff::from_millis(base)
+                    .factor(timeout)
+                    .max_delay(Duration::from_millis(max_interval));
+                Box::new(strategy)
+            }
+        };
+        let delays: Box<Iterator<Item = Duration>> = match jitter {
+            RetryJitter::None => delays,
+            jitter => Box::new(delays.map(move |delay| jitter.apply(delay))),
+        };
+        delays.take(max_tries)
+    }
+}
+
+/// Randomization of the delays between the outgoing connection attempts, which prevents
+/// the nodes from reconnecting at the same time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryJitter {
+    /// The delays are exactly the ones computed by the retry strategy.
+    None,
+    /// Every delay is a random duration up to the computed one.
+    Full,
+    /// Every delay is a half of the computed one plus a random duration up to the other half.
+    Equal,
+}
+
+impl RetryJitter {
+    fn apply(self, delay: Duration) -> Duration {
+        match self {
+            RetryJitter::None => delay,
+            RetryJitter::Full => jitter(delay),
+            RetryJitter::Equal => {
+                let half = delay / 2;
+                half + jitter(delay - half)
+            }
+        }
+    }
+}
+
+/// TCP keepalive parameters, the OS defaults are used for the parameters which are not set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TcpKeepAliveConfig {
+    /// Time the connection should be idle before the first probe is sent.
+    pub idle: Milliseconds,
+    /// Time between the probes, rounded down to seconds.
+    pub interval: Option<Milliseconds>,
+    /// Number of the unacknowledged probes after which the connection is closed.
+    pub retries: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NetworkConfiguration {
     // TODO: think more about config parameters (ECR-162)
     pub max_incoming_connections: usize,
     pub max_outgoing_connections: usize,
     pub tcp_nodelay: bool,
-    pub tcp_keep_alive: Option<u64>,
     pub tcp_connect_retry_timeout: Milliseconds,
     pub tcp_connect_max_retries: u64,
+    pub tcp_connect_retry_strategy: RetryStrategy,
+    /// Randomization applied to the delays of `tcp_connect_retry_strategy`.
+    pub tcp_connect_retry_jitter: RetryJitter,
+    /// Maximum time of a single attempt to establish the outgoing connection,
+    /// the next attempt is made according to the retry strategy if it is exceeded.
+    pub tcp_connect_timeout: Milliseconds,
+    /// Maximum total time of the attempts to establish the outgoing connection, including
+    /// the delays between them. The connection fails with `ConnectFailed` once either
+    /// the deadline or `tcp_connect_max_retries` is reached. Not limited if not set.
+    pub tcp_connect_deadline: Option<Milliseconds>,
+    /// Time during which the resolved addresses of the `host:port` peer endpoints are reused.
+    pub dns_cache_ttl: Milliseconds,
+    /// Maximum number of messages buffered for a single outgoing connection.
+    pub outgoing_message_queue_len: usize,
+    /// Maximum number of new outgoing connections to the same peer per minute,
+    /// `0` disables the limit. Disabled by default.
+    pub max_reconnects_per_minute: u32,
+    /// Time during which the incoming connection should send the `Connect` message.
+    pub incoming_handshake_timeout: Milliseconds,
+    /// Maximum time to flush a single outgoing message, the connection is closed
+    /// if the peer doesn't accept it in time.
+    pub write_timeout: Option<Milliseconds>,
+    /// Outgoing connections which don't send messages during this time are closed.
+    pub outgoing_idle_timeout: Option<Milliseconds>,
+    /// Maximum lifetime of the incoming and outgoing connections. Once it is reached,
+    /// the outgoing connection is closed after the buffered messages are written and
+    /// the incoming one is closed at once, so the next connection makes a new handshake.
+    pub max_connection_lifetime: Option<Milliseconds>,
+    /// Holds the `PeerDisconnected` and `IncomingPeerDisconnected` events for the given time.
+    /// If the peer connects again in the same direction meanwhile, both the disconnection
+    /// and the following `PeerConnectedOutgoing` or `PeerReconnected` and `PeerConnected`
+    /// events are dropped, so the flapping peer looks continuously connected. The tradeoff
+    /// is that the handler learns about the real disconnections later by the same time.
+    pub flap_dampening: Option<Milliseconds>,
+    /// Size of the socket send buffer (`SO_SNDBUF`), the OS default is used if not set.
+    pub socket_send_buffer: Option<usize>,
+    /// Size of the socket receive buffer (`SO_RCVBUF`), the OS default is used if not set.
+    pub socket_recv_buffer: Option<usize>,
+    /// Maximum number of the incoming and outgoing connections which have not completed
+    /// the handshake yet, new connections are rejected if the limit is reached.
+    pub max_pending_connections: usize,
+    /// Networks which the incoming connections are accepted from, any address which is not
+    /// denied is accepted if empty.
+    pub allowed_cidrs: Vec<IpNet>,
+    /// Networks which the incoming connections are rejected from, even if they are allowed.
+    pub denied_cidrs: Vec<IpNet>,
+    /// If set, the incoming connections start with the PROXY protocol v2 header sent by
+    /// the load balancer, and the address of the client from the header replaces the address
+    /// of the socket in the events and the checks. The connections without the valid header
+    /// are rejected.
+    pub proxy_protocol: bool,
+    /// Maximum number of the incoming connections which have not sent `Connect` yet.
+    /// If set, such connections don't count toward `max_incoming_connections` until
+    /// the handshake is completed, so the slow handshakes don't take the slots of
+    /// the established connections. Otherwise, all incoming connections are counted together.
+    pub max_handshaking_incoming: Option<usize>,
+    /// Maximum number of the incoming connections accepted per second. The connections
+    /// beyond the rate are left in the listen backlog until the rate allows to accept them,
+    /// short bursts up to the rate are accepted at once. Not limited if not set or zero.
+    pub incoming_accept_rate: Option<u32>,
+    /// Maximum number of the outgoing connections which are being established at the same
+    /// time, including the retries. The connections beyond the limit wait until the earlier
+    /// ones are established or failed. Not limited if not set.
+    pub max_concurrent_outgoing_dials: Option<usize>,
+    /// Maximum number of the messages which are being written at the same time across all
+    /// the outgoing connections. The connections waiting to write take turns in round-robin
+    /// order, so a single busy peer can't take all the writes. If not set, every connection
+    /// writes independently.
+    pub max_concurrent_writes: Option<usize>,
+    /// Rejects the incoming connections if the IP address from the `Connect` message differs
+    /// from the address of the socket. The ports are not compared since the outgoing
+    /// connections use ephemeral ports. Should be disabled if the peers are behind NAT.
+    pub verify_connect_address: bool,
+    /// Checks the messages received over the incoming connections with
+    /// `NetworkPart::message_verifier`, if it is set. The connection which has sent
+    /// the invalid message is closed with the `PeerErrored` event.
+    pub verify_messages: bool,
+    /// Messages which the incoming connections may send before `Connect`, identified
+    /// by the service id and the message type. They are reported with the `PreConnectMessage`
+    /// event, any other message before `Connect` closes the connection.
+    pub pre_connect_messages: Vec<(u16, u16)>,
+    /// Policy applied to the messages sent to the peer with the full outgoing queue.
+    pub outgoing_overflow_policy: OverflowPolicy,
+    /// Maximum number of the messages per second received over each incoming connection,
+    /// short bursts up to the rate are allowed. Not limited if not set or zero.
+    pub incoming_message_rate: Option<u32>,
+    /// Policy applied to the incoming connection which exceeds `incoming_message_rate`.
+    pub incoming_rate_policy: RateLimitPolicy,
+    /// Maximum number of the messages which are already read from the incoming connection
+    /// and are reported by the single `MessagesReceived` event. Each message is reported
+    /// by the separate `MessageReceived` event if not set.
+    pub incoming_batch_size: Option<usize>,
+    /// Reports the first message received over each incoming connection after `Connect`
+    /// by the `FirstMessage` event, the rest of the messages are reported as usual.
+    pub report_first_message: bool,
+    /// Size of the queue of the incoming connections which have not been accepted yet,
+    /// the OS default is used if not set.
+    pub listen_backlog: Option<i32>,
+    /// Sets `SO_REUSEADDR` on the listening sockets, so the node can be restarted while
+    /// the sockets of the previous run are in the `TIME_WAIT` state. Unix only.
+    pub reuse_address: bool,
+    /// Sets `SO_REUSEPORT` on the listening sockets, which allows several processes
+    /// to listen on the same port. Unix only.
+    pub reuse_port: bool,
+    /// Maximum total size in bytes of the messages buffered in the queues of all outgoing
+    /// connections, the new messages are rejected with the `SendQueueOverflow` event
+    /// if the limit is reached. The message is counted until it is flushed to the socket.
+    /// Not limited if not set.
+    pub max_total_outgoing_bytes: Option<usize>,
+    /// Compresses the messages sent over the outgoing connections if the peer has
+    /// the same compression enabled, the peer acknowledges it once our `Connect` message
+    /// is accepted. The compressed messages are always accepted. Disabled if not set.
+    pub compression: Option<Compression>,
+    // Sections should follow the plain values to be serialized into TOML.
+    /// Enables TCP keepalive probes on the outgoing connections.
+    pub tcp_keep_alive: Option<TcpKeepAliveConfig>,
+    /// Enables TLS encryption of the peer connections.
+    pub tls: Option<TlsConfig>,
+    /// SOCKS5 proxy for the outgoing connections.
+    pub proxy: Option<ProxyConfig>,
 }
 
 impl Default for NetworkConfiguration {
@@ -67,343 +526,3226 @@ impl Default for NetworkConfiguration {
         NetworkConfiguration {
             max_incoming_connections: 128,
             max_outgoing_connections: 128,
-            tcp_keep_alive: None,
             tcp_nodelay: true,
             tcp_connect_retry_timeout: 15_000,
             tcp_connect_max_retries: 10,
+            tcp_connect_retry_strategy: RetryStrategy::Fixed,
+            tcp_connect_retry_jitter: RetryJitter::Full,
+            tcp_connect_timeout: 15_000,
+            tcp_connect_deadline: None,
+            dns_cache_ttl: 10_000,
+            outgoing_message_queue_len: 10,
+            max_reconnects_per_minute: 0,
+            incoming_handshake_timeout: 10_000,
+            write_timeout: None,
+            outgoing_idle_timeout: None,
+            max_connection_lifetime: None,
+            flap_dampening: None,
+            socket_send_buffer: None,
+            socket_recv_buffer: None,
+            max_pending_connections: 256,
+            allowed_cidrs: Vec::new(),
+            denied_cidrs: Vec::new(),
+            proxy_protocol: false,
+            max_handshaking_incoming: None,
+            incoming_accept_rate: None,
+            max_concurrent_outgoing_dials: None,
+            max_concurrent_writes: None,
+            verify_connect_address: false,
+            verify_messages: false,
+            pre_connect_messages: Vec::new(),
+            outgoing_overflow_policy: OverflowPolicy::DropNew,
+            incoming_message_rate: None,
+            incoming_rate_policy: RateLimitPolicy::Throttle,
+            incoming_batch_size: None,
+            report_first_message: false,
+            listen_backlog: None,
+            reuse_address: true,
+            reuse_port: false,
+            max_total_outgoing_bytes: None,
+            compression: None,
+            tcp_keep_alive: None,
+            tls: None,
+            proxy: None,
+        }
+    }
+}
+
+impl NetworkConfiguration {
+    /// Returns the builder which starts from the default configuration.
+    pub fn builder() -> NetworkConfigurationBuilder {
+        NetworkConfigurationBuilder::new()
+    }
+
+    /// Returns `true` if the incoming connections are accepted from the address
+    /// according to `allowed_cidrs` and `denied_cidrs`.
+    pub fn allows_ip(&self, addr: &IpAddr) -> bool {
+        if self.denied_cidrs.iter().any(|net| net.contains(addr)) {
+            return false;
         }
+        self.allowed_cidrs.is_empty() || self.allowed_cidrs.iter().any(|net| net.contains(addr))
+    }
+}
+
+/// Generates the builder setters which replace the fields of the same name.
+macro_rules! config_setters {
+    ($($field:ident: $ty:ty,)*) => {
+        $(
+            pub fn $field(mut self, $field: $ty) -> NetworkConfigurationBuilder {
+                self.config.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+/// Builds `NetworkConfiguration`, the fields which are not set have the default values.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConfigurationBuilder {
+    config: NetworkConfiguration,
+}
+
+impl NetworkConfigurationBuilder {
+    pub fn new() -> NetworkConfigurationBuilder {
+        NetworkConfigurationBuilder::default()
+    }
+
+    config_setters! {
+        max_incoming_connections: usize,
+        max_outgoing_connections: usize,
+        tcp_nodelay: bool,
+        tcp_connect_retry_timeout: Milliseconds,
+        tcp_connect_max_retries: u64,
+        tcp_connect_retry_strategy: RetryStrategy,
+        tcp_connect_retry_jitter: RetryJitter,
+        tcp_connect_timeout: Milliseconds,
+        tcp_connect_deadline: Option<Milliseconds>,
+        dns_cache_ttl: Milliseconds,
+        outgoing_message_queue_len: usize,
+        max_reconnects_per_minute: u32,
+        incoming_handshake_timeout: Milliseconds,
+        write_timeout: Option<Milliseconds>,
+        outgoing_idle_timeout: Option<Milliseconds>,
+        max_connection_lifetime: Option<Milliseconds>,
+        flap_dampening: Option<Milliseconds>,
+        socket_send_buffer: Option<usize>,
+        socket_recv_buffer: Option<usize>,
+        max_pending_connections: usize,
+        allowed_cidrs: Vec<IpNet>,
+        denied_cidrs: Vec<IpNet>,
+        proxy_protocol: bool,
+        max_handshaking_incoming: Option<usize>,
+        incoming_accept_rate: Option<u32>,
+        max_concurrent_outgoing_dials: Option<usize>,
+        max_concurrent_writes: Option<usize>,
+        verify_connect_address: bool,
+        verify_messages: bool,
+        pre_connect_messages: Vec<(u16, u16)>,
+        outgoing_overflow_policy: OverflowPolicy,
+        incoming_message_rate: Option<u32>,
+        incoming_rate_policy: RateLimitPolicy,
+        incoming_batch_size: Option<usize>,
+        report_first_message: bool,
+        listen_backlog: Option<i32>,
+        reuse_address: bool,
+        reuse_port: bool,
+        max_total_outgoing_bytes: Option<usize>,
+        compression: Option<Compression>,
+        tcp_keep_alive: Option<TcpKeepAliveConfig>,
+        tls: Option<TlsConfig>,
+        proxy: Option<ProxyConfig>,
+    }
+
+    pub fn build(self) -> NetworkConfiguration {
+        self.config
     }
 }
 
 #[derive(Debug)]
 pub struct NetworkPart {
     pub our_connect_message: Connect,
-    pub listen_address: SocketAddr,
+    /// Protocol version expected in the `Connect` messages of the incoming connections.
+    pub protocol_version: u8,
+    pub listen_addresses: Vec<SocketAddr>,
     pub network_config: NetworkConfiguration,
     pub max_message_len: u32,
     pub network_requests: (mpsc::Sender<NetworkRequest>, mpsc::Receiver<NetworkRequest>),
+    /// Requests which are handled before the ones from `network_requests`, so `Shutdown`
+    /// or `DisconnectWithPeer` are not delayed by the queued messages.
+    /// See `NetworkPart::control_channel`.
+    pub control_requests: Option<mpsc::Receiver<NetworkRequest>>,
     pub network_tx: mpsc::Sender<NetworkEvent>,
+    /// Receives the actual addresses of the listeners once they are bound,
+    /// useful if the port `0` is used.
+    pub local_addresses_tx: Option<oneshot::Sender<Vec<SocketAddr>>>,
+    /// Notified once the node starts accepting the incoming connections,
+    /// dropped if the listeners can't be bound.
+    pub ready_tx: Option<oneshot::Sender<()>>,
+    /// Decides whether to accept the incoming connections.
+    pub connection_filter: Option<Box<ConnectionFilter>>,
+    /// Checks the messages received after `Connect` if `verify_messages` is enabled.
+    pub message_verifier: Option<Box<MessageVerifier>>,
+    /// Stops the network part once it is resolved, see `NetworkPart::shutdown_handle`.
+    pub shutdown_rx: Option<oneshot::Receiver<()>>,
+    /// Transport of the peer connections, `TcpTransport` is used if it is not set.
+    pub transport: Option<Box<Transport>>,
+    /// Timer used for the timeouts and retries, `ReactorTimer` is used if it is not set.
+    pub timer: Option<Box<Timer>>,
+    /// Receives the connection and traffic metrics, nothing is collected if it is not set.
+    pub metrics: Option<Box<NetworkMetrics>>,
+    /// Creates the codecs of the peer connections, `MessagesCodecFactory` is used
+    /// if it is not set.
+    pub codec_factory: Option<Box<CodecFactory>>,
+    /// Senders of the copies of all network events in addition to `network_tx`,
+    /// see `NetworkPart::subscribe`.
+    pub subscribers: Vec<mpsc::Sender<NetworkEvent>>,
+    /// Set while the network part is running, see `NetworkPart::running_flag`.
+    pub running: Option<RunningFlag>,
 }
 
-#[derive(Debug, Default, Clone)]
-struct ConnectionsPool {
-    inner: Rc<RefCell<HashMap<SocketAddr, mpsc::Sender<RawMessage>>>>,
-}
+/// Shows whether the network part is running, i.e. it has bound the listeners
+/// and has not stopped yet either by the request or because of an error.
+#[derive(Debug, Clone, Default)]
+pub struct RunningFlag(Arc<AtomicBool>);
 
-impl ConnectionsPool {
-    fn new() -> ConnectionsPool {
-        ConnectionsPool::default()
+impl RunningFlag {
+    pub fn is_running(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
     }
 
-    fn insert(&self, peer: SocketAddr, sender: &mpsc::Sender<RawMessage>) {
-        self.inner.borrow_mut().insert(peer, sender.clone());
+    fn set(&self, running: bool) {
+        self.0.store(running, Ordering::SeqCst);
     }
+}
 
-    fn remove(&self, peer: &SocketAddr) -> Result<mpsc::Sender<RawMessage>, &'static str> {
-        self.inner.borrow_mut().remove(peer).ok_or(
-            "there is no sender in the connection pool",
-        )
+/// Stops the network part when `shutdown` is called or when the handle is dropped.
+#[derive(Debug)]
+pub struct ShutdownHandle(oneshot::Sender<()>);
+
+impl ShutdownHandle {
+    /// Immediately stops the network part, the queued requests are not processed.
+    pub fn shutdown(self) {
+        // The receiver is dropped only if the network part is already stopped.
+        self.0.send(()).ok();
     }
+}
+
+/// A filter that is consulted when the incoming connection sends its `Connect` message,
+/// denied connections are closed before the `PeerConnected` event is emitted.
+pub trait ConnectionFilter: ::std::fmt::Debug + Send {
+    /// Returns `true` if the connection with the peer should be accepted.
+    fn allow(&self, addr: SocketAddr, connect: &Connect) -> bool;
+}
+
+/// Verifies the messages received over the incoming connections, e.g. their signatures,
+/// before they are passed to the node.
+pub trait MessageVerifier: ::std::fmt::Debug + Send {
+    /// Returns the reason why the message is invalid.
+    fn verify(&self, raw: &RawMessage) -> Result<(), String>;
+}
+
+/// Senders of the outgoing connection queues.
+#[derive(Debug, Clone)]
+struct PrioritySender<T = RawMessage> {
+    high: queue::Sender<T>,
+    normal: queue::Sender<T>,
+}
 
-    fn get(&self, peer: SocketAddr) -> Option<mpsc::Sender<RawMessage>> {
-        self.inner.borrow_mut().get(&peer).cloned()
+impl<T> PrioritySender<T> {
+    fn lane(&self, priority: Priority) -> &queue::Sender<T> {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Normal => &self.normal,
+        }
     }
 
     fn len(&self) -> usize {
-        self.inner.borrow_mut().len()
+        self.high.len() + self.normal.len()
     }
+}
 
-    fn connect_to_peer(
-        self,
-        network_config: NetworkConfiguration,
-        max_message_len: u32,
-        peer: SocketAddr,
-        network_tx: mpsc::Sender<NetworkEvent>,
-        handle: &Handle,
-    ) -> Option<mpsc::Sender<RawMessage>> {
+/// Receives the messages from both queues of the outgoing connection,
+/// the high priority queue is always polled first.
+#[derive(Debug)]
+pub(crate) struct PriorityReceiver<T = RawMessage> {
+    /// Message received before the queued ones.
+    front: Option<queue::Item<T>>,
+    high: stream::Fuse<queue::Receiver<T>>,
+    normal: stream::Fuse<queue::Receiver<T>>,
+}
 
-        let limit = network_config.max_outgoing_connections;
-        if self.len() >= limit {
-            warn!(
-                "Rejected outgoing connection with peer={}, \
-                                     connections limit reached.",
-                peer
-            );
-            return None;
+impl<T: AsRef<[u8]>> PriorityReceiver<T> {
+    pub(crate) fn new(high: queue::Receiver<T>, normal: queue::Receiver<T>) -> PriorityReceiver<T> {
+        PriorityReceiver {
+            front: None,
+            high: high.fuse(),
+            normal: normal.fuse(),
         }
-        // Register outgoing channel.
-        let (conn_tx, conn_rx) = mpsc::channel(OUTGOING_CHANNEL_SIZE);
-        self.insert(peer, &conn_tx);
-        // Enable retry feature for outgoing connection.
-        let timeout = network_config.tcp_connect_retry_timeout;
-        let max_tries = network_config.tcp_connect_max_retries as usize;
-        let strategy = FixedInterval::from_millis(timeout).map(jitter).take(
-            max_tries,
-        );
-        let handle_clonned = handle.clone();
+    }
 
-        let action = move || TcpStream::connect(&peer, &handle_clonned);
-        let connect_handle = Retry::spawn(handle.clone(), strategy, action)
-            .map_err(into_other)
-            // Configure socket
-            .and_then(move |sock| {
-                sock.set_nodelay(network_config.tcp_nodelay)?;
-                let duration =
-                    network_config.tcp_keep_alive.map(Duration::from_millis);
-                sock.set_keepalive(duration)?;
-                Ok(sock)
-            })
-            // Connect socket with the outgoing channel
-            .and_then(move |sock| {
-                trace!("Established connection with peer={}", peer);
+    /// Makes the message the next one to be received, replacing the previously prepended one.
+    pub(crate) fn prepend(&mut self, msg: T) {
+        self.front = Some((msg, None));
+    }
+}
 
-                let stream = sock.framed(MessagesCodec::new(max_message_len));
-                let (sink, stream) = stream.split();
+impl<T: AsRef<[u8]>> Stream for PriorityReceiver<T> {
+    type Item = queue::Item<T>;
+    type Error = ();
 
-                let writer = conn_rx
-                    .map_err(|_| other_error("Can't send data into socket"))
-                    .forward(sink);
-                let reader = stream.for_each(result_ok);
+    fn poll(&mut self) -> Poll<Option<queue::Item<T>>, ()> {
+        if let Some(msg) = self.front.take() {
+            return Ok(Async::Ready(Some(msg)));
+        }
+        if let Async::Ready(Some(msg)) = self.high.poll()? {
+            return Ok(Async::Ready(Some(msg)));
+        }
+        match self.normal.poll()? {
+            // The stream is finished only when both queues are closed.
+            Async::Ready(None) if !self.high.is_done() => Ok(Async::NotReady),
+            other => Ok(other),
+        }
+    }
+}
 
-                reader
-                    .select2(writer)
-                    .map_err(|_| other_error("Socket error"))
-                    .and_then(|res| match res {
-                        Either::A((_, _reader)) => Ok("by reader"),
-                        Either::B((_, _writer)) => Ok("by writer"),
-                    })
-            })
-            .then(move |res| {
-                trace!(
-                    "Disconnection with peer={}, reason={:?}",
-                    peer,
-                    res
-                );
-                self.disconnect_with_peer(peer, network_tx.clone())
-            })
-            .map_err(log_error);
-        handle.spawn(connect_handle);
-        Some(conn_tx)
+/// Receives the network requests, the control requests are always polled first.
+#[derive(Debug)]
+pub(crate) struct PriorityRequests {
+    control: stream::Fuse<mpsc::Receiver<NetworkRequest>>,
+    requests: stream::Fuse<mpsc::Receiver<NetworkRequest>>,
+}
+
+impl PriorityRequests {
+    pub(crate) fn new(
+        control: mpsc::Receiver<NetworkRequest>,
+        requests: mpsc::Receiver<NetworkRequest>,
+    ) -> PriorityRequests {
+        PriorityRequests {
+            control: control.fuse(),
+            requests: requests.fuse(),
+        }
     }
+}
 
-    fn disconnect_with_peer(
-        &self,
-        peer: SocketAddr,
-        network_tx: mpsc::Sender<NetworkEvent>,
-    ) -> Box<Future<Item = (), Error = io::Error>> {
-        let fut = self.remove(&peer)
-            .into_future()
-            .map_err(other_error)
-            .and_then(move |_| {
-                network_tx
-                    .send(NetworkEvent::PeerDisconnected(peer))
-                    .map_err(|_| other_error("can't send disconnect"))
-            })
-            .map(drop);
-        to_box(fut)
+impl Stream for PriorityRequests {
+    type Item = NetworkRequest;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<NetworkRequest>, ()> {
+        if let Async::Ready(Some(request)) = self.control.poll()? {
+            return Ok(Async::Ready(Some(request)));
+        }
+        match self.requests.poll()? {
+            // The stream is finished only when both channels are closed.
+            Async::Ready(None) if !self.control.is_done() => Ok(Async::NotReady),
+            other => Ok(other),
+        }
     }
 }
 
-impl NetworkPart {
-    pub fn run(self, handle: &Handle) -> Box<Future<Item = (), Error = io::Error>> {
-        let network_config = self.network_config;
-        // Cancelation token
-        let (cancel_sender, cancel_handler) = unsync::oneshot::channel();
-        let cancel_sender = Some(cancel_sender);
-
-        let requests_handle = RequestHandler::new(
-            self.our_connect_message,
-            network_config,
-            self.max_message_len,
-            self.network_tx.clone(),
-            handle.clone(),
-            self.network_requests.1,
-            cancel_sender,
-        );
-        // TODO Don't use unwrap here!
-        let server = Listener::bind(
-            network_config,
-            self.max_message_len,
-            self.listen_address,
-            handle.clone(),
-            &self.network_tx,
-        ).unwrap();
+/// Reason why the message can't be added to the connection queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrySendError {
+    NoConnection,
+    QueueFull,
+    BudgetExceeded,
+    Closed,
+}
 
-        let cancel_handler = cancel_handler.map_err(|_| other_error("can't cancel routine"));
-        let fut = server
-            .join(requests_handle)
-            .map(drop)
-            .select(cancel_handler)
-            .map_err(|(e, _)| e);
-        to_box(fut)
+impl fmt::Display for TrySendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            TrySendError::NoConnection => "there is no connection with the peer",
+            TrySendError::QueueFull => "the queue of the peer is full",
+            TrySendError::BudgetExceeded => "the limit of the buffered outgoing bytes is reached",
+            TrySendError::Closed => "the connection is closed",
+        };
+        f.write_str(description)
     }
 }
 
-struct RequestHandler(
-    // TODO: Replace with concrete type
-    Box<Future<Item = (), Error = io::Error>>
-);
+/// Outgoing connection registered in the pool before the socket is established.
+#[derive(Debug, Clone)]
+struct OutgoingConnection<T = RawMessage> {
+    sender: PrioritySender<T>,
+    /// Set until the socket is established, also distinguishes the connection
+    /// from the later ones with the same peer.
+    pending: Rc<Cell<bool>>,
+    activity: Rc<ConnectionActivity>,
+    /// Closes the socket without writing the buffered messages.
+    close_tx: Rc<RefCell<Option<unsync::oneshot::Sender<()>>>>,
+    /// Set if only the write half of the socket should be closed after the queues are drained.
+    sending_stopped: Rc<Cell<bool>>,
+}
 
-impl RequestHandler {
-    fn new(
-        connect_message: Connect,
-        network_config: NetworkConfiguration,
-        max_message_len: u32,
-        network_tx: mpsc::Sender<NetworkEvent>,
-        handle: Handle,
-        receiver: mpsc::Receiver<NetworkRequest>,
-        mut cancel_sender: Option<unsync::oneshot::Sender<()>>,
-    ) -> RequestHandler {
-        let outgoing_connections = ConnectionsPool::new();
-        let requests_handler = receiver
-            .map_err(|_| other_error("no network requests"))
-            .for_each(move |request| {
-                match request {
-                    NetworkRequest::SendMessage(peer, msg) => {
-
-                        let conn_tx = outgoing_connections
-                            .get(peer)
-                            .map(|conn_tx| conn_fut(Ok(conn_tx).into_future()))
-                            .or_else(|| {
-                                outgoing_connections
-                                    .clone()
-                                    .connect_to_peer(
-                                        network_config,
-                                        max_message_len,
-                                        peer,
-                                        network_tx.clone(),
-                                        &handle,
-                                    )
-                                    .map(|conn_tx|
-                                        // if we create new connect, we should send connect message
-                                        if &msg != connect_message.raw() {
-                                            conn_fut(conn_tx.send(connect_message.raw().clone())
-                                                           .map_err(|_| {
-                                                other_error("can't send message to a connection")
-                                            }))
-                                        }
-                                        else {
-                                            conn_fut(Ok(conn_tx).into_future())
-                                    })
-                            });
-                        if let Some(conn_tx) = conn_tx {
-                            let fut = conn_tx.and_then(|conn_tx| {
-                                conn_tx.send(msg).map_err(|_| {
-                                    other_error("can't send message to a connection")
-                                })
-                            });
-                            to_box(fut)
-                        } else {
-                            let event = NetworkEvent::UnableConnectToPeer(peer);
-                            let fut = network_tx
-                                .clone()
-                                .send(event)
-                                .map_err(|_| other_error("can't send network event"))
-                                .into_future();
-                            to_box(fut)
-                        }
-                    }
-                    NetworkRequest::DisconnectWithPeer(peer) => {
-                        outgoing_connections.disconnect_with_peer(peer, network_tx.clone())
-                    }
-                    // Immediately stop the event loop.
-                    NetworkRequest::Shutdown => {
-                        let fut = cancel_sender
-                            .take()
-                            .ok_or_else(|| other_error("shutdown twice"))
-                            .and_then(|sender| {
-                                sender.send(()).map_err(
-                                    |_| other_error("can't send shutdown signal"),
-                                )
-                            })
-                            .into_future();
-                        to_box(fut)
-                    }
-                }
-            });
-        RequestHandler(to_box(requests_handler))
+impl<T> OutgoingConnection<T> {
+    fn close(&self) {
+        if let Some(close_tx) = self.close_tx.borrow_mut().take() {
+            // The receiver is dropped only if the connection is already closed.
+            close_tx.send(()).ok();
+        }
+    }
+
+    fn stop_sending(&self) {
+        self.sending_stopped.set(true);
+        self.sender.high.close();
+        self.sender.normal.close();
     }
 }
 
-impl Future for RequestHandler {
-    type Item = ();
-    type Error = io::Error;
+/// Outgoing messages statistics of the connection.
+#[derive(Debug)]
+struct ConnectionActivity {
+    /// Number of messages passed to the sender.
+    sent_messages: Cell<u64>,
+    last_send: Cell<Instant>,
+    /// Number of bytes of the encoded frames written to the socket.
+    sent_bytes: Cell<u64>,
+    /// Number of bytes of the encoded frames read from the socket.
+    received_bytes: Cell<u64>,
+    /// Time of the last message passed to the node, except `Connect`.
+    last_receive: Cell<Option<Instant>>,
+    /// Time when the socket of the outgoing connection has been established.
+    established: Cell<Option<Instant>>,
+    /// Totals of all the connections.
+    totals: Rc<RefCell<NetworkRunSummary>>,
+}
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll()
+impl ConnectionActivity {
+    fn new(totals: Rc<RefCell<NetworkRunSummary>>) -> ConnectionActivity {
+        ConnectionActivity {
+            sent_messages: Cell::new(0),
+            last_send: Cell::new(Instant::now()),
+            sent_bytes: Cell::new(0),
+            received_bytes: Cell::new(0),
+            last_receive: Cell::new(None),
+            established: Cell::new(None),
+            totals,
+        }
+    }
+
+    /// Returns the time since the connection has been established, zero if it hasn't.
+    fn connected_for(&self) -> Duration {
+        self.established.get().map_or(
+            Duration::from_secs(0),
+            |established| established.elapsed(),
+        )
+    }
+
+    fn message_sent(&self) {
+        self.sent_messages.set(self.sent_messages.get() + 1);
+        self.last_send.set(Instant::now());
+    }
+
+    fn message_written(&self) {
+        self.totals.borrow_mut().messages_sent += 1;
+    }
+
+    fn message_read(&self) {
+        self.totals.borrow_mut().messages_received += 1;
+    }
+
+    fn bytes_sent(&self, len: usize) {
+        self.sent_bytes.set(self.sent_bytes.get() + len as u64);
+        self.totals.borrow_mut().bytes_sent += len as u64;
+    }
+
+    fn bytes_received(&self, len: usize) {
+        self.received_bytes.set(self.received_bytes.get() + len as u64);
+        self.totals.borrow_mut().bytes_received += len as u64;
+    }
+
+    fn message_received(&self) {
+        self.last_receive.set(Some(Instant::now()));
     }
 }
 
+/// Counts the bytes of the frames encoded and decoded by the codec of the connection,
+/// so the traffic includes the framing and the compression.
+struct CountingCodec {
+    codec: Box<PeerCodec>,
+    activity: Rc<ConnectionActivity>,
+}
 
-struct Listener(Box<Future<Item = (), Error = io::Error>>);
+impl CountingCodec {
+    fn new(codec: Box<PeerCodec>, activity: Rc<ConnectionActivity>) -> CountingCodec {
+        CountingCodec { codec, activity }
+    }
+}
 
-impl Listener {
-    fn bind(
-        network_config: NetworkConfiguration,
-        max_message_len: u32,
-        listen_address: SocketAddr,
-        handle: Handle,
-        network_tx: &mpsc::Sender<NetworkEvent>,
-    ) -> Result<Listener, io::Error> {
-        // Incoming connections limiter
-        let incoming_connections_limit = network_config.max_incoming_connections;
-        // The reference counter is used to automatically count the number of the open connections.
-        let incoming_connections_counter: Rc<()> = Rc::default();
-        // Incoming connections handler
-        let listener = TcpListener::bind(&listen_address, &handle)?;
-        let network_tx = network_tx.clone();
-        let server = listener.incoming().for_each(move |(sock, addr)| {
-            let holder = Rc::downgrade(&incoming_connections_counter);
-            // Check incoming connections count
-            let connections_count = Rc::weak_count(&incoming_connections_counter);
-            if connections_count > incoming_connections_limit {
-                warn!(
-                    "Rejected incoming connection with peer={}, \
-                     connections limit reached.",
-                    addr
-                );
-                return to_box(future::ok(()));
-            }
-            trace!("Accepted incoming connection with peer={}", addr);
-            let stream = sock.framed(MessagesCodec::new(max_message_len));
-            let (_, stream) = stream.split();
-            let network_tx = network_tx.clone();
-            let connection_handler = stream
-                .into_future()
-                .map_err(|e| e.0)
-                .and_then(move |(raw, stream)| match raw.map(Any::from_raw) {
-                    Some(Ok(Any::Connect(msg))) => Ok((msg, stream)),
-                    Some(Ok(other)) => Err(other_error(
-                        &format!("First message is not Connect, got={:?}", other),
-                    )),
-                    Some(Err(e)) => Err(into_other(e)),
-                    None => Err(other_error("Incoming socket closed")),
-                })
-                .and_then(move |(connect, stream)| {
-                    trace!("Received handshake message={:?}", connect);
-                    let event = NetworkEvent::PeerConnected(addr, connect);
-                    let stream = network_tx
-                        .clone()
-                        .send(event)
-                        .map_err(into_other)
-                        .and_then(move |_| Ok(stream))
-                        .flatten_stream();
+impl Decoder for CountingCodec {
+    type Item = RawMessage;
+    type Error = io::Error;
 
-                    stream.for_each(move |raw| {
-                        let event = NetworkEvent::MessageReceived(addr, raw);
-                        network_tx.clone().send(event).map_err(into_other).map(drop)
-                    })
-                })
-                .map(|_| {
-                    // Ensure that holder lives until the stream ends.
-                    let _holder = holder;
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RawMessage>, io::Error> {
+        let len = buf.len();
+        let res = self.codec.decode(buf);
+        self.activity.bytes_received(len - buf.len());
+        res
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<RawMessage>, io::Error> {
+        let len = buf.len();
+        let res = self.codec.decode_eof(buf);
+        self.activity.bytes_received(len - buf.len());
+        res
+    }
+}
+
+impl Encoder for CountingCodec {
+    type Item = RawMessage;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: RawMessage, buf: &mut BytesMut) -> io::Result<()> {
+        let len = buf.len();
+        let res = self.codec.encode(msg, buf);
+        self.activity.bytes_sent(buf.len() - len);
+        res
+    }
+}
+
+/// Counts the connection as pending until the guard is dropped.
+#[derive(Debug)]
+struct PendingGuard(Rc<Cell<usize>>);
+
+impl PendingGuard {
+    /// Returns `None` if the number of the pending connections has reached the limit.
+    fn acquire(counter: &Rc<Cell<usize>>, limit: usize) -> Option<PendingGuard> {
+        if counter.get() >= limit {
+            return None;
+        }
+        counter.set(counter.get() + 1);
+        Some(PendingGuard(Rc::clone(counter)))
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// Number of the operations in progress, such as the outgoing dials or the writes,
+/// and the operations waiting for their turn in order.
+#[derive(Debug, Default)]
+pub(crate) struct SlotsLimiter {
+    in_flight: usize,
+    waiting: VecDeque<unsync::oneshot::Sender<SlotPermit>>,
+}
+
+/// Counts the operation as in progress until the permit is dropped.
+#[derive(Debug)]
+pub(crate) struct SlotPermit(Rc<RefCell<SlotsLimiter>>);
+
+impl SlotPermit {
+    /// Resolves once the number of the operations in progress is below the limit.
+    pub(crate) fn acquire(
+        limiter: &Rc<RefCell<SlotsLimiter>>,
+        limit: Option<usize>,
+    ) -> Box<Future<Item = SlotPermit, Error = io::Error>> {
+        let mut inner = limiter.borrow_mut();
+        if limit.map_or(true, |limit| inner.in_flight < limit) {
+            inner.in_flight += 1;
+            return Box::new(future::ok(SlotPermit(Rc::clone(limiter))));
+        }
+        let (tx, rx) = unsync::oneshot::channel();
+        inner.waiting.push_back(tx);
+        Box::new(rx.map_err(|_| other_error("slots limiter is dropped")))
+    }
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) {
+        let next = self.0.borrow_mut().waiting.pop_front();
+        match next {
+            // Passes the slot to the first operation in the queue. If it isn't waiting
+            // anymore, the permit is dropped and the slot is passed further.
+            Some(tx) => {
+                tx.send(SlotPermit(Rc::clone(&self.0))).ok();
+            }
+            None => self.0.borrow_mut().in_flight -= 1,
+        }
+    }
+}
+
+/// Host lookup sent to the resolver thread together with the sender of the result.
+type HostLookup = (String, oneshot::Sender<io::Result<SocketAddr>>);
+
+/// Resolves the `host:port` endpoints of the peers, the addresses are cached for `ttl`.
+/// The lookups are blocking, so they are performed by the dedicated thread, which is started
+/// on the first lookup and stopped once the resolver is dropped. The cache is kept
+/// on the reactor thread.
+#[derive(Debug)]
+pub(crate) struct HostResolver {
+    ttl: Cell<Duration>,
+    cache: Rc<RefCell<HashMap<String, (SocketAddr, Instant)>>>,
+    lookups: RefCell<Option<sync_mpsc::Sender<HostLookup>>>,
+}
+
+impl HostResolver {
+    pub(crate) fn new(ttl: Duration) -> HostResolver {
+        HostResolver {
+            ttl: Cell::new(ttl),
+            cache: Rc::default(),
+            lookups: RefCell::new(None),
+        }
+    }
+
+    /// Changes the time to live of the cached addresses, including the ones cached before.
+    pub(crate) fn set_ttl(&self, ttl: Duration) {
+        self.ttl.set(ttl);
+    }
+
+    /// Returns the first address of the host, resolving it again if the cached one
+    /// is expired. Fails if the host has no addresses.
+    pub(crate) fn resolve(&self, host: &str) -> Box<Future<Item = SocketAddr, Error = io::Error>> {
+        if let Some(&(addr, resolved)) = self.cache.borrow().get(host) {
+            if resolved.elapsed() < self.ttl.get() {
+                return Box::new(future::ok(addr));
+            }
+        }
+        let (result_tx, result_rx) = oneshot::channel();
+        if let Err(e) = self.send_lookup((host.to_owned(), result_tx)) {
+            return Box::new(future::err(e));
+        }
+        let cache = Rc::clone(&self.cache);
+        let host = host.to_owned();
+        let fut = result_rx
+            .map_err(|_| other_error("host resolver thread is stopped"))
+            .and_then(move |addr| {
+                let addr = addr?;
+                cache.borrow_mut().insert(host, (addr, Instant::now()));
+                Ok(addr)
+            });
+        Box::new(fut)
+    }
+
+    fn send_lookup(&self, lookup: HostLookup) -> io::Result<()> {
+        let mut lookups = self.lookups.borrow_mut();
+        if lookups.is_none() {
+            let (lookups_tx, lookups_rx) = sync_mpsc::channel::<HostLookup>();
+            thread::Builder::new()
+                .name("host-resolver".to_owned())
+                .spawn(move || for (host, result_tx) in lookups_rx {
+                    result_tx.send(lookup_host(&host)).ok();
+                })?;
+            *lookups = Some(lookups_tx);
+        }
+        let lookups_tx = lookups.as_ref().expect("host resolver thread is started");
+        lookups_tx.send(lookup).map_err(
+            |_| other_error("host resolver thread is stopped"),
+        )
+    }
+}
+
+/// Performs the blocking lookup of the host, returns its first address.
+fn lookup_host(host: &str) -> io::Result<SocketAddr> {
+    host.to_socket_addrs()?.next().ok_or_else(|| {
+        other_error(format!("host={} has no addresses", host))
+    })
+}
+
+/// Outgoing connections with the peers, `T` is the type of the queued messages.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionsPool<T = RawMessage> {
+    inner: Rc<RefCell<HashMap<SocketAddr, OutgoingConnection<T>>>>,
+    tls: Option<TlsContext>,
+    transport: Rc<Transport>,
+    timer: Rc<Timer>,
+    metrics: Rc<NetworkMetrics>,
+    codecs: Rc<CodecFactory>,
+    pending_connections: Rc<Cell<usize>>,
+    dials: Rc<RefCell<SlotsLimiter>>,
+    writes: Rc<RefCell<SlotsLimiter>>,
+    /// Total size of the messages buffered in the queues of all connections.
+    buffered_bytes: Rc<Cell<usize>>,
+    max_buffered_bytes: Rc<Cell<Option<usize>>>,
+    max_outgoing_connections: Rc<Cell<usize>>,
+    /// Set while the number of the connections is at the limit.
+    outgoing_limit_reached: Rc<Cell<bool>>,
+    /// Activity of the incoming connections by the addresses from their `Connect` messages.
+    incoming: Rc<RefCell<HashMap<SocketAddr, Rc<ConnectionActivity>>>>,
+    /// Keys from the `Connect` messages of the recently accepted incoming connections.
+    seen_incoming: Rc<RefCell<SeenPeers>>,
+    /// Addresses of the peers by the keys from their latest `Connect` messages.
+    peer_addresses: Rc<RefCell<HashMap<PublicKey, SocketAddr>>>,
+    /// Peers which are reconnected automatically if the established connection fails.
+    persistent_peers: Rc<RefCell<HashSet<SocketAddr>>>,
+    /// Totals of the session, shared by the activities of all connections.
+    totals: Rc<RefCell<NetworkRunSummary>>,
+    /// Hosts of the peers sent to by `SendMessageToHost`, by the addresses which
+    /// the connections are registered with.
+    hosts: Rc<RefCell<HashMap<SocketAddr, String>>>,
+    resolver: Rc<HostResolver>,
+}
+
+impl<T: AsRef<[u8]>> ConnectionsPool<T> {
+    pub(crate) fn new(
+        tls: Option<TlsContext>,
+        transport: Rc<Transport>,
+        timer: Rc<Timer>,
+        metrics: Rc<NetworkMetrics>,
+        codecs: Rc<CodecFactory>,
+        pending_connections: Rc<Cell<usize>>,
+        max_buffered_bytes: Option<usize>,
+        max_outgoing_connections: usize,
+        dns_cache_ttl: Milliseconds,
+    ) -> ConnectionsPool<T> {
+        ConnectionsPool {
+            inner: Rc::default(),
+            tls,
+            transport,
+            timer,
+            metrics,
+            codecs,
+            pending_connections,
+            dials: Rc::default(),
+            writes: Rc::default(),
+            buffered_bytes: Rc::default(),
+            max_buffered_bytes: Rc::new(Cell::new(max_buffered_bytes)),
+            max_outgoing_connections: Rc::new(Cell::new(max_outgoing_connections)),
+            outgoing_limit_reached: Rc::default(),
+            incoming: Rc::default(),
+            seen_incoming: Rc::new(RefCell::new(SeenPeers::new(SEEN_PEERS_CAPACITY))),
+            peer_addresses: Rc::default(),
+            persistent_peers: Rc::default(),
+            totals: Rc::default(),
+            hosts: Rc::default(),
+            resolver: Rc::new(HostResolver::new(Duration::from_millis(dns_cache_ttl))),
+        }
+    }
+
+    /// Registers the incoming connection from the peer, replacing the previous one.
+    /// Returns `true` if the peer with the same key has been connected recently.
+    fn insert_incoming(
+        &self,
+        peer: SocketAddr,
+        key: PublicKey,
+        activity: &Rc<ConnectionActivity>,
+    ) -> bool {
+        self.incoming.borrow_mut().insert(peer, Rc::clone(activity));
+        !self.seen_incoming.borrow_mut().insert(key)
+    }
+
+    /// Removes the incoming connection unless it has been replaced by the newer one.
+    fn remove_incoming(&self, peer: &SocketAddr, activity: &Rc<ConnectionActivity>) {
+        let mut incoming = self.incoming.borrow_mut();
+        let is_same = incoming.get(peer).map_or(
+            false,
+            |current| Rc::ptr_eq(current, activity),
+        );
+        if is_same {
+            incoming.remove(peer);
+        }
+    }
+
+    fn insert(&self, peer: SocketAddr, connection: OutgoingConnection<T>) {
+        self.inner.borrow_mut().insert(peer, connection);
+    }
+
+    /// Registers the pending connection with the peer, replacing the previous one.
+    /// Returns the receiver of the queued messages and the receiver of the close signal.
+    pub(crate) fn register(
+        &self,
+        peer: SocketAddr,
+        queue_len: usize,
+    ) -> (PriorityReceiver<T>, unsync::oneshot::Receiver<()>) {
+        let (high_tx, high_rx) =
+            queue::channel_with_counter(queue_len, Rc::clone(&self.buffered_bytes));
+        let (normal_tx, normal_rx) =
+            queue::channel_with_counter(queue_len, Rc::clone(&self.buffered_bytes));
+        let (close_tx, close_rx) = unsync::oneshot::channel();
+        self.insert(
+            peer,
+            OutgoingConnection {
+                sender: PrioritySender {
+                    high: high_tx,
+                    normal: normal_tx,
+                },
+                pending: Rc::new(Cell::new(true)),
+                activity: Rc::new(ConnectionActivity::new(Rc::clone(&self.totals))),
+                close_tx: Rc::new(RefCell::new(Some(close_tx))),
+                sending_stopped: Rc::new(Cell::new(false)),
+            },
+        );
+        (PriorityReceiver::new(high_rx, normal_rx), close_rx)
+    }
+
+    fn remove(&self, peer: &SocketAddr) -> Result<OutgoingConnection<T>, &'static str> {
+        self.inner.borrow_mut().remove(peer).ok_or(
+            "there is no sender in the connection pool",
+        )
+    }
+
+    /// Removes the connection only if it has not been replaced by a newer one.
+    fn remove_connection(
+        &self,
+        peer: &SocketAddr,
+        pending: &Rc<Cell<bool>>,
+    ) -> Result<OutgoingConnection<T>, &'static str> {
+        let is_same = match self.inner.borrow().get(peer) {
+            Some(connection) => Rc::ptr_eq(&connection.pending, pending),
+            None => false,
+        };
+        if is_same {
+            self.remove(peer)
+        } else {
+            Err("the connection has been replaced in the connection pool")
+        }
+    }
+
+    /// Marks the failed connection as pending again, so the buffered messages are written
+    /// to the new socket. Returns `None` if the connection has been removed, replaced or
+    /// stopped sending, or if the pending connections limit is reached.
+    fn prepare_reconnect(
+        &self,
+        peer: SocketAddr,
+        pending: &Rc<Cell<bool>>,
+        pending_limit: usize,
+    ) -> Option<(PendingGuard, unsync::oneshot::Receiver<()>)> {
+        let inner = self.inner.borrow();
+        let connection = match inner.get(&peer) {
+            Some(connection) if Rc::ptr_eq(&connection.pending, pending) => connection,
+            _ => return None,
+        };
+        if connection.sending_stopped.get() {
+            return None;
+        }
+        let pending_guard = PendingGuard::acquire(&self.pending_connections, pending_limit)?;
+        let (close_tx, close_rx) = unsync::oneshot::channel();
+        *connection.close_tx.borrow_mut() = Some(close_tx);
+        connection.pending.set(true);
+        Some((pending_guard, close_rx))
+    }
+
+    /// Returns the address of the connection with the host, if it exists or is pending.
+    fn host_peer(&self, host: &str) -> Option<SocketAddr> {
+        self.hosts
+            .borrow()
+            .iter()
+            .find(|&(peer, peer_host)| peer_host == host && self.contains(*peer))
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Resolves the host and remembers it for the connection with the resolved address,
+    /// the address is resolved again on each attempt to connect with the peer.
+    /// The address of the existing connection with the host is used as is.
+    fn resolve_host(&self, host: String) -> Box<Future<Item = SocketAddr, Error = io::Error>> {
+        if let Some(peer) = self.host_peer(&host) {
+            return Box::new(future::ok(peer));
+        }
+        let hosts = Rc::clone(&self.hosts);
+        let fut = self.resolver.resolve(&host).map(move |peer| {
+            let mut hosts = hosts.borrow_mut();
+            hosts.retain(|_, peer_host| *peer_host != host);
+            hosts.insert(peer, host);
+            peer
+        });
+        Box::new(fut)
+    }
+
+    /// Replaces the peers which are reconnected automatically, the flag is taken
+    /// into account for the new connections only.
+    fn set_persistent_peers(&self, peers: Vec<SocketAddr>) {
+        *self.persistent_peers.borrow_mut() = peers.into_iter().collect();
+    }
+
+    /// Returns `true` if the connection with the peer is either established or pending,
+    /// all the requests to the peer share this connection.
+    pub(crate) fn contains(&self, peer: SocketAddr) -> bool {
+        self.inner.borrow().contains_key(&peer)
+    }
+
+    fn is_connected(&self, peer: SocketAddr) -> bool {
+        self.inner.borrow().get(&peer).map_or(
+            false,
+            |connection| !connection.pending.get(),
+        )
+    }
+
+    /// Adds the message to the connection queue, returns the message evicted from
+    /// the full queue according to the `policy`. The `token` is reported by the
+    /// `MessageSent` event once the message is written.
+    pub(crate) fn try_send(
+        &self,
+        peer: SocketAddr,
+        msg: T,
+        token: Option<u64>,
+        priority: Priority,
+        policy: OverflowPolicy,
+    ) -> Result<Option<T>, TrySendError> {
+        let inner = self.inner.borrow();
+        let connection = inner.get(&peer).ok_or(TrySendError::NoConnection)?;
+        if connection.pending.get() {
+            trace!("Reusing pending connection with peer={}", peer);
+        }
+        if let Some(limit) = self.max_buffered_bytes.get() {
+            if self.buffered_bytes.get() + msg.as_ref().len() > limit {
+                return Err(TrySendError::BudgetExceeded);
+            }
+        }
+        let evicted = connection.sender.lane(priority).push_with_token(msg, token, policy).map_err(
+            |e| match e {
+                PushError::Full => TrySendError::QueueFull,
+                PushError::Closed => TrySendError::Closed,
+            },
+        )?;
+        connection.activity.message_sent();
+        Ok(evicted)
+    }
+
+    fn send_counts(&self) -> HashMap<SocketAddr, u64> {
+        self.inner
+            .borrow()
+            .iter()
+            .map(|(peer, connection)| {
+                (*peer, connection.activity.sent_messages.get())
+            })
+            .collect()
+    }
+
+    /// Returns the number of the messages queued for each peer, which are not taken
+    /// by the writer of the connection yet.
+    fn queue_depths(&self) -> HashMap<SocketAddr, usize> {
+        self.inner
+            .borrow()
+            .iter()
+            .map(|(peer, connection)| (*peer, connection.sender.len()))
+            .collect()
+    }
+
+    /// Returns the numbers of sent and received bytes of both the outgoing and incoming
+    /// connections with each peer.
+    fn traffic(&self) -> HashMap<SocketAddr, (u64, u64)> {
+        let mut traffic = HashMap::new();
+        let connections = self.inner.borrow();
+        let outgoing = connections.iter().map(|(peer, connection)| {
+            (peer, &connection.activity)
+        });
+        let incoming = self.incoming.borrow();
+        for (peer, activity) in outgoing.chain(incoming.iter()) {
+            let entry = traffic.entry(*peer).or_insert((0, 0));
+            entry.0 += activity.sent_bytes.get();
+            entry.1 += activity.received_bytes.get();
+        }
+        traffic
+    }
+
+    /// Returns the time elapsed since the last message received over the incoming
+    /// connection with each peer.
+    fn last_received(&self) -> HashMap<SocketAddr, Duration> {
+        self.incoming
+            .borrow()
+            .iter()
+            .filter_map(|(peer, activity)| {
+                activity.last_receive.get().map(|time| (*peer, time.elapsed()))
+            })
+            .collect()
+    }
+
+    /// Removes the established connections which have not sent messages during `idle_timeout`,
+    /// returns the peers together with the durations of the removed connections.
+    fn evict_idle(&self, idle_timeout: Duration) -> Vec<(SocketAddr, Duration)> {
+        let mut inner = self.inner.borrow_mut();
+        let idle_peers: Vec<_> = inner
+            .iter()
+            .filter(|&(_, connection)| {
+                !connection.pending.get() &&
+                    connection.activity.last_send.get().elapsed() >= idle_timeout
+            })
+            .map(|(peer, connection)| (*peer, connection.activity.connected_for()))
+            .collect();
+        for &(ref peer, _) in &idle_peers {
+            inner.remove(peer);
+        }
+        idle_peers
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.borrow_mut().len()
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.inner.borrow().keys().cloned().collect()
+    }
+
+    fn clear(&self) {
+        self.inner.borrow_mut().clear();
+    }
+
+    /// Closes the write half of the connection after the buffered messages are written.
+    fn stop_sending(&self, peer: SocketAddr) -> Result<(), &'static str> {
+        let inner = self.inner.borrow();
+        let connection = inner.get(&peer).ok_or(
+            "there is no sender in the connection pool",
+        )?;
+        connection.stop_sending();
+        Ok(())
+    }
+
+    /// Closes the queues of the connection, so the connection is closed once
+    /// the buffered messages are written.
+    fn close_after_flush(&self, peer: SocketAddr) -> Result<(), &'static str> {
+        let inner = self.inner.borrow();
+        let connection = inner.get(&peer).ok_or(
+            "there is no sender in the connection pool",
+        )?;
+        connection.sender.high.close();
+        connection.sender.normal.close();
+        Ok(())
+    }
+
+    /// Removes the connection from the pool, the buffered messages are written
+    /// only if the disconnection is graceful.
+    fn disconnect_with_peer(
+        &self,
+        peer: SocketAddr,
+        graceful: bool,
+        network_tx: mpsc::Sender<NetworkEvent>,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let removed = self.remove(&peer);
+        if let Ok(ref connection) = removed {
+            if !graceful {
+                connection.close();
+            }
+        }
+        let limit = self.notify_outgoing_limit(network_tx.clone());
+        let fut = Self::notify_disconnected(removed, peer, network_tx).and_then(move |_| limit);
+        to_box(fut)
+    }
+
+    /// Sends `PeerDisconnected` only if the removed connection has been established,
+    /// so the event is emitted once per connection.
+    fn notify_disconnected(
+        removed: Result<OutgoingConnection<T>, &'static str>,
+        peer: SocketAddr,
+        network_tx: mpsc::Sender<NetworkEvent>,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        match removed {
+            Ok(ref connection) if !connection.pending.get() => {
+                let event = NetworkEvent::PeerDisconnected {
+                    addr: peer,
+                    duration: connection.activity.connected_for(),
+                };
+                let fut = network_tx.send(event).map_err(
+                    |_| other_error("can't send network event"),
+                );
+                to_box(fut)
+            }
+            Ok(_) => {
+                trace!("Closed pending connection with peer={}", peer);
+                to_box(future::ok(()))
+            }
+            Err(reason) => {
+                trace!("Skipped disconnection with peer={}, {}", peer, reason);
+                to_box(future::ok(()))
+            }
+        }
+    }
+
+    /// Sends `OutgoingLimitReached` or `OutgoingLimitRelieved` if the number of the connections
+    /// has crossed the limit since the last call. The check is done immediately,
+    /// the returned future only sends the event.
+    fn notify_outgoing_limit(
+        &self,
+        network_tx: mpsc::Sender<NetworkEvent>,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let reached = self.len() >= self.max_outgoing_connections.get();
+        if reached == self.outgoing_limit_reached.get() {
+            return to_box(future::ok(()));
+        }
+        self.outgoing_limit_reached.set(reached);
+        let event = if reached {
+            NetworkEvent::OutgoingLimitReached
+        } else {
+            NetworkEvent::OutgoingLimitRelieved
+        };
+        let fut = network_tx.send(event).map_err(
+            |_| other_error("can't send network event"),
+        );
+        to_box(fut)
+    }
+
+    /// Sends the event if the connection has been removed from the pool.
+    fn notify_removed(
+        removed: Result<(), &'static str>,
+        event: NetworkEvent,
+        network_tx: mpsc::Sender<NetworkEvent>,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let fut = removed
+            .into_future()
+            .map_err(other_error)
+            .and_then(move |_| {
+                network_tx.send(event).map_err(
+                    |_| other_error("can't send network event"),
+                )
+            })
+            .map(drop);
+        to_box(fut)
+    }
+}
+
+impl ConnectionsPool {
+    /// Registers the established connection which discards the messages instead of
+    /// writing them, the messages are counted once they are taken from the queues.
+    fn register_sink(
+        &self,
+        peer: SocketAddr,
+        queue_len: usize,
+        counter: SinkCounter,
+        handle: &Handle,
+    ) {
+        let (conn_rx, _close_rx) = self.register(peer, queue_len);
+        self.inner.borrow()[&peer].pending.set(false);
+        trace!("Registered sink connection with peer={}", peer);
+        // The receiver is finished once the connection is removed from the pool.
+        handle.spawn(conn_rx.for_each(move |_| {
+            counter.increment();
+            Ok(())
+        }));
+    }
+
+    /// Opens the connection with the peer. If the peer is persistent, the connection is
+    /// reestablished once it fails, our `Connect` message is written to the new socket
+    /// before the buffered messages.
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    fn connect_to_peer(
+        self,
+        network_config: NetworkConfiguration,
+        max_message_len: u32,
+        peer: SocketAddr,
+        connect_message: RawMessage,
+        network_tx: mpsc::Sender<NetworkEvent>,
+        connections_tracker: unsync::mpsc::UnboundedSender<()>,
+        handle: &Handle,
+    ) -> Option<PrioritySender> {
+
+        let limit = network_config.max_outgoing_connections;
+        if self.len() >= limit {
+            warn!(
+                "Rejected outgoing connection with peer={}, \
+                                     connections limit reached.",
+                peer
+            );
+            return None;
+        }
+        let pending_limit = network_config.max_pending_connections;
+        let pending_guard = match PendingGuard::acquire(&self.pending_connections, pending_limit) {
+            Some(guard) => guard,
+            None => {
+                warn!(
+                    "Rejected outgoing connection with peer={}, \
+                     pending connections limit reached.",
+                    peer
+                );
+                return None;
+            }
+        };
+        // Register outgoing channels.
+        let (conn_rx, close_rx) = self.register(peer, network_config.outgoing_message_queue_len);
+        let (conn_tx, pending, activity, sending_stopped) = {
+            let inner = self.inner.borrow();
+            let connection = &inner[&peer];
+            (
+                connection.sender.clone(),
+                Rc::clone(&connection.pending),
+                Rc::clone(&connection.activity),
+                Rc::clone(&connection.sending_stopped),
+            )
+        };
+        let persistent = self.persistent_peers.borrow().contains(&peer);
+        let conn_rx = Rc::new(RefCell::new(conn_rx));
+        let dial = self.dial(&network_config, peer, network_tx.clone(), handle);
+        let writer_handle = handle.clone();
+        let connection = future::loop_fn(
+            (dial, pending_guard, Some(connections_tracker), close_rx, Instant::now()),
+            move |(dial, pending_guard, tracker, close_rx, connect_started)| {
+                let pool = self.clone();
+                let network_config = network_config.clone();
+                let network_tx = network_tx.clone();
+                let pending = Rc::clone(&pending);
+                let activity = Rc::clone(&activity);
+                let sending_stopped = Rc::clone(&sending_stopped);
+                let conn_rx = Rc::clone(&conn_rx);
+                let connect_message = connect_message.clone();
+                let handle = writer_handle.clone();
+                // The reconnecting peer is waited for by the graceful shutdown as well.
+                let reconnect_tracker = tracker.clone();
+                dial.then(move |res| {
+                    drop(pending_guard);
+                    res
+                }).then(move |res| match res {
+                    Ok(sock) => {
+                        pending.set(false);
+                        pool.totals.borrow_mut().connections_dialed += 1;
+                        let connect_duration = connect_started.elapsed();
+                        trace!(
+                            "Connected with peer={} in {:?}",
+                            peer,
+                            connect_duration
+                        );
+                        pool.metrics.record_connection_opened(peer);
+                        let event = NetworkEvent::PeerConnectedOutgoing {
+                            addr: peer,
+                            connect_duration,
+                        };
+                        let codec =
+                            pool.codecs.new_codec(max_message_len, network_config.compression);
+                        // The lifetime timer is cancelled once the sender is dropped
+                        // with the connection.
+                        let lifetime_tx = network_config.max_connection_lifetime.map(|lifetime| {
+                            let (closed_tx, closed_rx) = unsync::oneshot::channel();
+                            let expire = pool.expire_after(
+                                peer,
+                                &pending,
+                                lifetime,
+                                closed_rx,
+                                network_tx.clone(),
+                                &handle,
+                            );
+                            handle.spawn(expire.map_err(log_error));
+                            closed_tx
+                        });
+                        let fut = network_tx
+                            .clone()
+                            .send(event)
+                            .map_err(into_other)
+                            .and_then(move |_| {
+                                Self::process_connection(
+                                    sock,
+                                    pool.tls.clone(),
+                                    Rc::clone(&conn_rx),
+                                    close_rx,
+                                    sending_stopped,
+                                    network_config.clone(),
+                                    codec,
+                                    peer,
+                                    Rc::clone(&activity),
+                                    Rc::clone(&pool.timer),
+                                    Rc::clone(&pool.metrics),
+                                    Rc::clone(&pool.writes),
+                                    network_tx.clone(),
+                                    handle.clone(),
+                                ).then(move |res| {
+                                    drop(lifetime_tx);
+                                    trace!(
+                                        "Disconnection with peer={}, reason={:?}",
+                                        peer,
+                                        res
+                                    );
+                                    pool.metrics.record_connection_closed(peer);
+                                    let reconnect = match res {
+                                        Err(ref e) if persistent => {
+                                            warn!(
+                                                "Connection with persistent peer={} failed: \
+                                                 {}, reconnecting.",
+                                                peer,
+                                                e
+                                            );
+                                            pool.prepare_reconnect(
+                                                peer,
+                                                &pending,
+                                                network_config.max_pending_connections,
+                                            )
+                                        }
+                                        _ => None,
+                                    };
+                                    match reconnect {
+                                        Some((pending_guard, close_rx)) => {
+                                            // The buffered messages are kept, the new
+                                            // socket starts with our `Connect` message.
+                                            conn_rx.borrow_mut().prepend(connect_message);
+                                            let event = NetworkEvent::PeerDisconnected {
+                                                addr: peer,
+                                                duration: activity.connected_for(),
+                                            };
+                                            let dial = pool.dial(
+                                                &network_config,
+                                                peer,
+                                                network_tx.clone(),
+                                                &handle,
+                                            );
+                                            let fut = network_tx
+                                                .send(event)
+                                                .map_err(into_other)
+                                                .map(move |_| {
+                                                    Loop::Continue((
+                                                        dial,
+                                                        pending_guard,
+                                                        reconnect_tracker,
+                                                        close_rx,
+                                                        Instant::now(),
+                                                    ))
+                                                });
+                                            Either::A(fut)
+                                        }
+                                        None => {
+                                            let removed =
+                                                pool.remove_connection(&peer, &pending);
+                                            let limit =
+                                                pool.notify_outgoing_limit(network_tx.clone());
+                                            let fut = Self::notify_disconnected(
+                                                removed,
+                                                peer,
+                                                network_tx,
+                                            ).and_then(move |_| limit)
+                                                .map(|_| Loop::Break(()));
+                                            Either::B(fut)
+                                        }
+                                    }
+                                })
+                            });
+                        Either::A(fut)
+                    }
+                    Err(e) => {
+                        warn!("Unable to connect with peer={}: {}", peer, e);
+                        pool.metrics.record_connect_failure(peer);
+                        pool.totals.borrow_mut().dial_failures += 1;
+                        let reason = if is_resource_exhausted(&e) {
+                            ConnectFailure::ResourceExhausted
+                        } else {
+                            ConnectFailure::Unreachable
+                        };
+                        let removed = pool.remove_connection(&peer, &pending).map(drop);
+                        let limit = pool.notify_outgoing_limit(network_tx.clone());
+                        let fut = Self::notify_removed(
+                            removed,
+                            NetworkEvent::ConnectFailed(peer, reason),
+                            network_tx,
+                        ).and_then(move |_| limit)
+                            .map(|_| Loop::Break(()));
+                        Either::B(fut)
+                    }
+                })
+                    .then(move |res| {
+                        // The tracker lives until the writer has flushed the queues,
+                        // so the graceful shutdown waits for it.
+                        drop(tracker);
+                        res
+                    })
+            },
+        );
+        handle.spawn(connection.map_err(log_error));
+        Some(conn_tx)
+    }
+
+    /// Disconnects gracefully from the peer once the connection has lived for `lifetime`,
+    /// does nothing if the connection is closed or replaced before.
+    fn expire_after(
+        &self,
+        peer: SocketAddr,
+        pending: &Rc<Cell<bool>>,
+        lifetime: Milliseconds,
+        closed: unsync::oneshot::Receiver<()>,
+        network_tx: mpsc::Sender<NetworkEvent>,
+        handle: &Handle,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let pool = self.clone();
+        let pending = Rc::clone(pending);
+        let delay = self.timer.delay(Duration::from_millis(lifetime), handle);
+        let fut = delay.select2(closed).then(move |res| match res {
+            Ok(Either::A(_)) => {
+                let is_same = pool.inner.borrow().get(&peer).map_or(false, |connection| {
+                    Rc::ptr_eq(&connection.pending, &pending)
+                });
+                if !is_same {
+                    return to_box(future::ok(()));
+                }
+                trace!("Closing connection with peer={}, lifetime is expired", peer);
+                pool.disconnect_with_peer(peer, true, network_tx)
+            }
+            Err(Either::A((e, _))) => to_box(future::err(e)),
+            // The connection is closed.
+            Ok(Either::B(_)) |
+            Err(Either::B(_)) => to_box(future::ok(())),
+        });
+        to_box(fut)
+    }
+
+    /// Establishes the socket with the peer, retrying according to the configured strategy.
+    /// The deadline of the attempts is counted from the call.
+    fn dial(
+        &self,
+        network_config: &NetworkConfiguration,
+        peer: SocketAddr,
+        network_tx: mpsc::Sender<NetworkEvent>,
+        handle: &Handle,
+    ) -> Box<Future<Item = Box<PeerStream>, Error = io::Error>> {
+        // Enable retry feature for outgoing connection.
+        let timeout = network_config.tcp_connect_retry_timeout;
+        let max_tries = network_config.tcp_connect_max_retries as usize;
+        let strategy = network_config.tcp_connect_retry_strategy.delays(
+            timeout,
+            max_tries,
+            network_config.tcp_connect_retry_jitter,
+        );
+        let handle_clonned = handle.clone();
+
+        let transport = Rc::clone(&self.transport);
+        let connect_timer = Rc::clone(&self.timer);
+        let connect_timeout = network_config.tcp_connect_timeout;
+        let host = self.hosts.borrow().get(&peer).cloned();
+        let resolver = Rc::clone(&self.resolver);
+        let attempt = Cell::new(0);
+        let retry_tx = network_tx;
+        let action = move || {
+            attempt.set(attempt.get() + 1);
+            trace!(
+                "Connecting to peer={}, attempt {}/{}",
+                peer,
+                attempt.get(),
+                max_tries + 1
+            );
+            // The failed resolution fails the attempt, so it is retried as well.
+            let addr = match host {
+                Some(ref host) => Either::A(resolver.resolve(host)),
+                None => Either::B(future::ok(peer)),
+            };
+            let transport = Rc::clone(&transport);
+            let connect_timer = Rc::clone(&connect_timer);
+            let handle = handle_clonned.clone();
+            let connect = addr.and_then(move |addr| {
+                let dial_timeout = Duration::from_millis(connect_timeout);
+                let dial_timeout = connect_timer.delay(dial_timeout, &handle);
+                transport
+                    .connect(addr, &handle)
+                    .select2(dial_timeout)
+                    .then(move |res| match res {
+                        Ok(Either::A((sock, _))) => Ok(sock),
+                        Ok(Either::B(_)) => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("connection timeout of {} ms is exceeded", connect_timeout),
+                        )),
+                        Err(Either::A((e, _))) |
+                        Err(Either::B((e, _))) => Err(e),
+                    })
+            });
+            if attempt.get() == 1 {
+                return Either::A(connect);
+            }
+            let event = NetworkEvent::ConnectRetry {
+                addr: peer,
+                attempt: attempt.get(),
+            };
+            let fut = retry_tx.clone().send(event).map_err(into_other).and_then(
+                move |_| connect,
+            );
+            Either::B(fut)
+        };
+        let timer = Rc::clone(&self.timer);
+        let retry_handle = handle.clone();
+        let connect_deadline = network_config.tcp_connect_deadline.map(|deadline| {
+            let delay = self.timer.delay(Duration::from_millis(deadline), handle);
+            (deadline, delay)
+        });
+        let dials_limit = network_config.max_concurrent_outgoing_dials;
+        let dial_permit = SlotPermit::acquire(&self.dials, dials_limit);
+        let retries = dial_permit.and_then(move |permit| {
+            let attempts = future::loop_fn((strategy, action), move |(mut strategy, mut action)| {
+                let timer = Rc::clone(&timer);
+                let handle = retry_handle.clone();
+                action().then(move |res| match res {
+                    Ok(sock) => Either::A(future::ok(Loop::Break(sock))),
+                    // If the resources are exhausted, the next attempts are going to fail
+                    // the same way.
+                    Err(e) => match strategy.next() {
+                        Some(delay) if !is_resource_exhausted(&e) => {
+                            let fut = timer.delay(delay, &handle).map(move |_| {
+                                Loop::Continue((strategy, action))
+                            });
+                            Either::B(fut)
+                        }
+                        _ => Either::A(future::err(e)),
+                    },
+                })
+            });
+            // The deadline is counted from the request, the time waiting for the dial
+            // permit is included.
+            let attempts = match connect_deadline {
+                Some((deadline, delay)) => {
+                    let fut = attempts.select2(delay).then(move |res| match res {
+                        Ok(Either::A((sock, _))) => Ok(sock),
+                        Ok(Either::B(_)) => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("connection deadline of {} ms is exceeded", deadline),
+                        )),
+                        Err(Either::A((e, _))) |
+                        Err(Either::B((e, _))) => Err(e),
+                    });
+                    Either::A(fut)
+                }
+                None => Either::B(attempts),
+            };
+            attempts.then(move |res| {
+                drop(permit);
+                res
+            })
+        });
+        Box::new(retries)
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    fn process_connection(
+        sock: Box<PeerStream>,
+        tls: Option<TlsContext>,
+        conn_rx: Rc<RefCell<PriorityReceiver>>,
+        close_rx: unsync::oneshot::Receiver<()>,
+        sending_stopped: Rc<Cell<bool>>,
+        network_config: NetworkConfiguration,
+        codec: Box<PeerCodec>,
+        peer: SocketAddr,
+        activity: Rc<ConnectionActivity>,
+        timer: Rc<Timer>,
+        metrics: Rc<NetworkMetrics>,
+        writes: Rc<RefCell<SlotsLimiter>>,
+        network_tx: mpsc::Sender<NetworkEvent>,
+        handle: Handle,
+    ) -> Box<Future<Item = &'static str, Error = io::Error>> {
+        let write_timeout = network_config.write_timeout;
+        let writes_limit = network_config.max_concurrent_writes;
+        let fut = tls::connect(tls.as_ref(), peer, sock)
+            // Connect socket with the outgoing channel
+            .and_then(move |sock| {
+                trace!("Established connection with peer={}", peer);
+                activity.established.set(Some(Instant::now()));
+
+                let stream = sock.framed(CountingCodec::new(codec, Rc::clone(&activity)));
+                let (sink, stream) = stream.split();
+                let sent = Rc::clone(&activity);
+                let sink = sink.with(move |raw: RawMessage| {
+                    sent.message_written();
+                    metrics.record_message_sent(peer, raw.as_ref().len());
+                    Ok::<_, io::Error>(raw)
+                });
+
+                // The receiver is shared, so the buffered messages outlive the socket.
+                let writer = stream::poll_fn(move || conn_rx.borrow_mut().poll())
+                    .map_err(|_| other_error("Can't send data into socket"))
+                    .fold(sink, move |sink, (raw, token)| {
+                        let network_tx = network_tx.clone();
+                        let timer = Rc::clone(&timer);
+                        let handle = handle.clone();
+                        // The permit is acquired for every message, so the connection
+                        // waits behind the others which are ready to write.
+                        SlotPermit::acquire(&writes, writes_limit)
+                            .and_then(move |permit| {
+                                let timer = &*timer;
+                                Self::write_message(sink, raw, peer, write_timeout, timer, &handle)
+                                    .then(move |res| {
+                                        drop(permit);
+                                        res
+                                    })
+                            })
+                            .and_then(move |sink| match token {
+                                Some(token) => {
+                                    let event = NetworkEvent::MessageSent { addr: peer, token };
+                                    let fut = network_tx
+                                        .send(event)
+                                        .map_err(into_other)
+                                        .map(move |_| sink);
+                                    Either::A(fut)
+                                }
+                                None => Either::B(future::ok(sink)),
+                            })
+                    })
+                    .and_then(move |mut sink| if sending_stopped.get() {
+                        // The reader is kept until the peer closes the connection.
+                        trace!("Closed write half of connection with peer={}", peer);
+                        let fut = future::poll_fn(move || sink.close()).and_then(|_| {
+                            future::empty::<(), io::Error>()
+                        });
+                        Either::A(fut)
+                    } else {
+                        Either::B(future::ok(()))
+                    });
+                let reader = stream
+                    .inspect(move |_| activity.message_read())
+                    .for_each(result_ok);
+                let closed = close_rx.then(|res| match res {
+                    Ok(()) => to_box(future::ok(())),
+                    // Otherwise the writer closes the connection after flushing the buffer.
+                    Err(_) => to_box(future::empty::<(), io::Error>()),
+                });
+
+                reader
+                    .select2(writer)
+                    .map_err(|_| other_error("Socket error"))
+                    .and_then(|res| match res {
+                        Either::A((_, _reader)) => Ok("by reader"),
+                        Either::B((_, _writer)) => Ok("by writer"),
+                    })
+                    .select2(closed)
+                    .then(|res| match res {
+                        Ok(Either::A((reason, _))) => Ok(reason),
+                        Ok(Either::B(_)) => Ok("by request"),
+                        Err(Either::A((e, _))) |
+                        Err(Either::B((e, _))) => Err(e),
+                    })
+            });
+        Box::new(fut)
+    }
+
+    /// Sends the message into the sink, fails if it isn't flushed in `write_timeout`.
+    fn write_message<S>(
+        sink: S,
+        raw: RawMessage,
+        peer: SocketAddr,
+        write_timeout: Option<Milliseconds>,
+        timer: &Timer,
+        handle: &Handle,
+    ) -> Box<Future<Item = S, Error = io::Error>>
+    where
+        S: Sink<SinkItem = RawMessage, SinkError = io::Error> + 'static,
+    {
+        let send = sink.send(raw);
+        let write_timeout = match write_timeout {
+            Some(write_timeout) => write_timeout,
+            None => return Box::new(send),
+        };
+        let timeout = timer.delay(Duration::from_millis(write_timeout), handle);
+        let fut = send.select2(timeout).then(move |res| match res {
+            Ok(Either::A((sink, _))) => Ok(sink),
+            Ok(Either::B(_)) => {
+                warn!(
+                    "Unable to write message to peer={} in {} ms, closing the connection.",
+                    peer,
+                    write_timeout
+                );
+                Err(other_error("Write timeout"))
+            }
+            Err(Either::A((e, _))) |
+            Err(Either::B((e, _))) => Err(e),
+        });
+        Box::new(fut)
+    }
+}
+
+/// Keys of the recently connected peers, the least recently connected ones are forgotten
+/// once the capacity is reached.
+#[derive(Debug)]
+pub(crate) struct SeenPeers {
+    capacity: usize,
+    keys: HashSet<PublicKey>,
+    /// Keys ordered from the least to the most recently connected.
+    order: VecDeque<PublicKey>,
+}
+
+impl SeenPeers {
+    pub(crate) fn new(capacity: usize) -> SeenPeers {
+        SeenPeers {
+            capacity,
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Marks the peer as the most recently connected one. Returns `false` if the peer
+    /// has been seen before.
+    pub(crate) fn insert(&mut self, key: PublicKey) -> bool {
+        if !self.keys.insert(key) {
+            if let Some(position) = self.order.iter().position(|seen| *seen == key) {
+                self.order.remove(position);
+            }
+            self.order.push_back(key);
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Token bucket that limits the rate of new outgoing connections to each peer.
+/// The buckets which haven't been used for a minute are full, so they are removed.
+#[derive(Debug)]
+pub(crate) struct ReconnectsLimiter {
+    max_per_minute: u32,
+    buckets: HashMap<SocketAddr, (f64, Instant)>,
+    swept: Instant,
+}
+
+impl ReconnectsLimiter {
+    pub(crate) fn new(max_per_minute: u32) -> ReconnectsLimiter {
+        ReconnectsLimiter {
+            max_per_minute,
+            buckets: HashMap::new(),
+            swept: Instant::now(),
+        }
+    }
+
+    /// Returns the number of the peers with the partially used buckets.
+    pub(crate) fn peers_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Changes the rate limit, the tokens accumulated by the peers are preserved.
+    pub(crate) fn set_max_per_minute(&mut self, max_per_minute: u32) {
+        self.max_per_minute = max_per_minute;
+    }
+
+    /// Takes a token from the bucket of the given peer. Returns `false` if the bucket is empty.
+    pub(crate) fn acquire(&mut self, peer: SocketAddr) -> bool {
+        self.acquire_at(peer, Instant::now())
+    }
+
+    /// Takes a token at the given time, the stale buckets are removed at most once a minute.
+    pub(crate) fn acquire_at(&mut self, peer: SocketAddr, now: Instant) -> bool {
+        if self.max_per_minute == 0 {
+            return true;
+        }
+
+        let minute = Duration::from_secs(60);
+        if now.duration_since(self.swept) >= minute {
+            self.buckets.retain(
+                |_, bucket| now.duration_since(bucket.1) < minute,
+            );
+            self.swept = now;
+        }
+
+        let capacity = f64::from(self.max_per_minute);
+        let bucket = self.buckets.entry(peer).or_insert((capacity, now));
+        let elapsed = now.duration_since(bucket.1);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        bucket.0 = (bucket.0 + elapsed_secs * capacity / 60.0).min(capacity);
+        bucket.1 = now;
+
+        if bucket.0 >= 1.0 {
+            bucket.0 -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Token bucket limiting the rate of the messages received over the incoming connection.
+#[derive(Debug)]
+struct MessageRateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl MessageRateLimiter {
+    fn new(rate: u32) -> MessageRateLimiter {
+        MessageRateLimiter {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + elapsed_secs * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+
+    /// Takes a token from the bucket even if it is empty. Returns the time until
+    /// the taken token is refilled, if the bucket has been empty.
+    fn acquire(&mut self) -> Option<Duration> {
+        self.refill();
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            return None;
+        }
+        let wait_secs = -self.tokens / self.rate;
+        let wait = Duration::new(wait_secs as u64, (wait_secs.fract() * 1e9) as u32);
+        Some(wait)
+    }
+
+    /// Takes a token from the bucket. Returns `false` if the bucket is empty.
+    fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl NetworkPart {
+    /// Returns the handle which stops the network part, replacing the previous one.
+    pub fn shutdown_handle(&mut self) -> ShutdownHandle {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        self.shutdown_rx = Some(shutdown_rx);
+        ShutdownHandle(shutdown_tx)
+    }
+
+    /// Returns the flag which is set while the network part is running.
+    pub fn running_flag(&mut self) -> RunningFlag {
+        self.running.get_or_insert_with(RunningFlag::default).clone()
+    }
+
+    /// Returns the sender of the control requests, replacing the previous one.
+    pub fn control_channel(&mut self) -> mpsc::Sender<NetworkRequest> {
+        let (control_tx, control_rx) = mpsc::channel(CONTROL_REQUESTS_CAPACITY);
+        self.control_requests = Some(control_rx);
+        control_tx
+    }
+
+    /// Returns the receiver of the copies of all network events, e.g. for the metrics
+    /// exporters. The subscriber doesn't slow down the primary receiver, so the events
+    /// are dropped if the subscriber falls behind. The messages are not copied,
+    /// `RawMessage` is a reference counted pointer.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<NetworkEvent> {
+        let (subscriber_tx, subscriber_rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.push(subscriber_tx);
+        subscriber_rx
+    }
+
+    /// Passes the events to `network_tx` and their copies to the subscribers.
+    fn forward_events(
+        events_rx: mpsc::Receiver<NetworkEvent>,
+        network_tx: mpsc::Sender<NetworkEvent>,
+        subscribers: Vec<mpsc::Sender<NetworkEvent>>,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let fut = events_rx.fold(
+            (network_tx, subscribers),
+            |(network_tx, mut subscribers), event| {
+                subscribers.retain(|subscriber| !subscriber.is_closed());
+                for subscriber in &mut subscribers {
+                    if let Err(e) = subscriber.try_send(event.clone()) {
+                        if e.is_full() {
+                            warn!("Dropped network event, the queue of the subscriber is full.");
+                        }
+                    }
+                }
+                network_tx.send(event).map_err(drop).map(
+                    move |network_tx| (network_tx, subscribers),
+                )
+            },
+        );
+        to_box(fut)
+    }
+
+    /// Runs the network part on its own event loop, blocking the current thread.
+    /// Returns the totals of the session once the network part is stopped.
+    pub fn run(self) -> Result<NetworkRunSummary, NetworkError> {
+        let mut core = Core::new().map_err(NetworkError::Runtime)?;
+        let fut = self.into_future(core.handle());
+        core.run(fut)
+    }
+
+    /// Periodically closes the idle outgoing connections.
+    fn evict_idle_connections(
+        idle_timeout: Milliseconds,
+        outgoing_connections: ConnectionsPool,
+        network_tx: mpsc::Sender<NetworkEvent>,
+        timer: &Timer,
+        handle: &Handle,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let idle_timeout = Duration::from_millis(idle_timeout);
+        let interval = timer.interval(idle_timeout / 2, handle);
+        let fut = interval.for_each(move |_| {
+            let network_tx = network_tx.clone();
+            let idle_peers = outgoing_connections.evict_idle(idle_timeout);
+            let limit = outgoing_connections.notify_outgoing_limit(network_tx.clone());
+            stream::iter_ok(idle_peers)
+                .for_each(move |(peer, duration)| {
+                    trace!("Closed idle connection with peer={}", peer);
+                    network_tx
+                        .clone()
+                        .send(NetworkEvent::PeerDisconnected {
+                            addr: peer,
+                            duration,
+                        })
+                        .map(drop)
+                        .map_err(|_| other_error("can't send disconnect"))
+                })
+                .and_then(move |_| limit)
+        });
+        to_box(fut)
+    }
+
+    /// Returns the future which handles the network part on the given event loop,
+    /// the future resolves to the totals of the session once the network part is stopped.
+    pub fn into_future(
+        self,
+        handle: Handle,
+    ) -> Box<Future<Item = NetworkRunSummary, Error = NetworkError>> {
+        let network_config = self.network_config;
+        let outgoing_idle_timeout = network_config.outgoing_idle_timeout;
+        // Number of the open incoming connections.
+        let incoming_connections_counter: Rc<Cell<usize>> = Rc::default();
+        // Number of the connections which have not completed the handshake.
+        let pending_connections: Rc<Cell<usize>> = Rc::default();
+        // Cancelation token
+        let (cancel_sender, cancel_handler) = unsync::oneshot::channel();
+        // Every outgoing connection holds a clone of this sender, so the receiver
+        // is closed once all of them are finished.
+        let (connections_tracker, connections_tracker_rx) = unsync::mpsc::unbounded();
+
+        let tls = match network_config.tls {
+            Some(ref tls_config) => match TlsContext::new(tls_config) {
+                Ok(tls) => Some(tls),
+                Err(e) => return Box::new(future::err(NetworkError::Tls(e))),
+            },
+            None => None,
+        };
+
+        let transport: Rc<Transport> = match self.transport {
+            Some(transport) => Rc::from(transport),
+            None => Rc::new(TcpTransport::new(network_config.clone())),
+        };
+        let timer: Rc<Timer> = match self.timer {
+            Some(timer) => Rc::from(timer),
+            None => Rc::new(ReactorTimer),
+        };
+        let metrics: Rc<NetworkMetrics> = match self.metrics {
+            Some(metrics) => Rc::from(metrics),
+            None => Rc::new(NoopMetrics),
+        };
+        let codecs: Rc<CodecFactory> = match self.codec_factory {
+            Some(codecs) => Rc::from(codecs),
+            None => Rc::new(MessagesCodecFactory),
+        };
+        // The events are passed through the forwarder only if there are subscribers.
+        let network_tx = if self.subscribers.is_empty() {
+            self.network_tx
+        } else {
+            let (events_tx, events_rx) = mpsc::channel(0);
+            handle.spawn(Self::forward_events(
+                events_rx,
+                self.network_tx,
+                self.subscribers,
+            ));
+            events_tx
+        };
+        let network_tx = match network_config.flap_dampening {
+            Some(window) => {
+                let (events_tx, events_rx) = mpsc::channel(0);
+                let window = Duration::from_millis(window);
+                let damper = FlapDamper::new(events_rx, network_tx, window, &timer, &handle);
+                handle.spawn(damper);
+                events_tx
+            }
+            None => network_tx,
+        };
+        let outgoing_connections: ConnectionsPool = ConnectionsPool::new(
+            tls.clone(),
+            Rc::clone(&transport),
+            Rc::clone(&timer),
+            metrics,
+            codecs,
+            pending_connections,
+            network_config.max_total_outgoing_bytes,
+            network_config.max_outgoing_connections,
+            network_config.dns_cache_ttl,
+        );
+        let totals = Rc::clone(&outgoing_connections.totals);
+        let network_handler = NetworkHandler {
+            connect_message: self.our_connect_message,
+            reconnects_limiter: ReconnectsLimiter::new(network_config.max_reconnects_per_minute),
+            network_config: Rc::new(RefCell::new(network_config)),
+            max_message_len: self.max_message_len,
+            network_tx: network_tx.clone(),
+            handle: handle.clone(),
+            outgoing_connections: outgoing_connections.clone(),
+            incoming_connections_counter: Rc::clone(&incoming_connections_counter),
+            cancel_sender: Rc::new(RefCell::new(Some(cancel_sender))),
+            connections_tracker: Some(connections_tracker),
+            connections_tracker_rx: Some(connections_tracker_rx),
+            tls,
+            transport,
+            timer: Rc::clone(&timer),
+        };
+        let (server, local_addresses) =
+            match Listener::bind(
+                &network_handler,
+                &self.listen_addresses,
+                self.protocol_version,
+                self.connection_filter,
+                self.message_verifier,
+            ) {
+                Ok(listener) => listener,
+                Err(e) => return Box::new(future::err(e)),
+            };
+        if let Some(local_addresses_tx) = self.local_addresses_tx {
+            if local_addresses_tx.send(local_addresses).is_err() {
+                warn!("Unable to send local addresses, the receiver is dropped.");
+            }
+        }
+        if let Some(ready_tx) = self.ready_tx {
+            if ready_tx.send(()).is_err() {
+                warn!("Unable to send ready signal, the receiver is dropped.");
+            }
+        }
+        let running = self.running;
+        if let Some(ref running) = running {
+            running.set(true);
+        }
+        // The closed channel is used if there are no control requests.
+        let control_requests = self.control_requests.unwrap_or_else(|| mpsc::channel(0).1);
+        let requests = PriorityRequests::new(control_requests, self.network_requests.1);
+        let requests_handle = RequestHandler::new(network_handler, requests);
+
+        let idle_connections_handler = match outgoing_idle_timeout {
+            Some(idle_timeout) => {
+                Self::evict_idle_connections(
+                    idle_timeout,
+                    outgoing_connections,
+                    network_tx.clone(),
+                    &*timer,
+                    &handle,
+                )
+            }
+            None => to_box(future::ok(())),
+        };
+
+        let cancel_handler: Box<Future<Item = (), Error = Canceled>> = match self.shutdown_rx {
+            // Dropping the shutdown handle also stops the network part.
+            Some(shutdown_rx) => {
+                let shutdown = shutdown_rx.then(|_| Ok(()));
+                Box::new(cancel_handler.select(shutdown).map(drop).map_err(
+                    |(e, _)| e,
+                ))
+            }
+            None => Box::new(cancel_handler),
+        };
+        let cancel_handler = cancel_handler.map_err(|_| other_error("can't cancel routine"));
+        // There is no point to handle the connections if their events go nowhere.
+        let network_tx_closed = timer
+            .interval(Duration::from_millis(NETWORK_TX_CHECK_INTERVAL), &handle)
+            .take_while(move |_| Ok(!network_tx.is_closed()))
+            .for_each(|_| Ok(()))
+            .map(|_| {
+                warn!("Network events receiver is dropped, stopping the network part.")
+            });
+        let cancel_handler = cancel_handler.select(network_tx_closed).map(drop).map_err(
+            |(e, _)| e,
+        );
+        let fut = server
+            .join3(requests_handle, idle_connections_handler)
+            .map(drop)
+            .select(cancel_handler)
+            .map(move |_| *totals.borrow())
+            .map_err(|(e, _)| NetworkError::Runtime(e))
+            .then(move |res| {
+                if let Some(running) = running {
+                    running.set(false);
+                }
+                res
+            });
+        Box::new(fut)
+    }
+}
+
+struct NetworkHandler {
+    connect_message: Connect,
+    // Shared with the listener, can be replaced by the `Reconfigure` request.
+    network_config: Rc<RefCell<NetworkConfiguration>>,
+    max_message_len: u32,
+    network_tx: mpsc::Sender<NetworkEvent>,
+    handle: Handle,
+    outgoing_connections: ConnectionsPool,
+    reconnects_limiter: ReconnectsLimiter,
+    incoming_connections_counter: Rc<Cell<usize>>,
+    cancel_sender: Rc<RefCell<Option<unsync::oneshot::Sender<()>>>>,
+    // Is `None` after the graceful shutdown is initiated.
+    connections_tracker: Option<unsync::mpsc::UnboundedSender<()>>,
+    connections_tracker_rx: Option<unsync::mpsc::UnboundedReceiver<()>>,
+    tls: Option<TlsContext>,
+    transport: Rc<Transport>,
+    timer: Rc<Timer>,
+}
+
+impl NetworkHandler {
+    fn handle_request(
+        &mut self,
+        request: NetworkRequest,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        match request {
+            NetworkRequest::SendMessage(peer, msg) => {
+                let policy = self.network_config.borrow().outgoing_overflow_policy;
+                self.send_message(peer, msg, Priority::Normal, policy)
+            }
+            NetworkRequest::SendMessagePriority(peer, msg, priority) => {
+                let policy = self.network_config.borrow().outgoing_overflow_policy;
+                self.send_message(peer, msg, priority, policy)
+            }
+            NetworkRequest::SendMessageWithPolicy(peer, msg, policy) => {
+                self.send_message(peer, msg, Priority::Normal, policy)
+            }
+            NetworkRequest::SendMessageToKey(key, msg) => {
+                let peer = self.outgoing_connections.peer_addresses.borrow().get(&key).cloned();
+                match peer {
+                    Some(peer) => {
+                        let policy = self.network_config.borrow().outgoing_overflow_policy;
+                        self.send_message(peer, msg, Priority::Normal, policy)
+                    }
+                    None => {
+                        warn!("Dropped message to peer with key={:?}, it is unknown.", key);
+                        self.send_event(NetworkEvent::UnknownPeerKey(key))
+                    }
+                }
+            }
+            // The host is resolved by `RequestHandler`, which passes the message
+            // to the resolved address as `SendMessage`.
+            NetworkRequest::SendMessageToHost(host, _) => {
+                warn!("Dropped message to host={}, it is not resolved.", host);
+                self.send_event(NetworkEvent::UnresolvedHost(host))
+            }
+            NetworkRequest::SendMessageTracked(peer, msg, token) => {
+                let policy = self.network_config.borrow().outgoing_overflow_policy;
+                self.send_message_with_token(peer, msg, Some(token), Priority::Normal, policy)
+            }
+            NetworkRequest::SendMessages(peer, msgs) => self.send_messages(peer, msgs),
+            NetworkRequest::SendMessageAndDisconnect(peer, msg) => {
+                let policy = self.network_config.borrow().outgoing_overflow_policy;
+                let fut = self.send_message(peer, msg, Priority::Normal, policy);
+                if let Err(e) = self.outgoing_connections.close_after_flush(peer) {
+                    warn!("Unable to disconnect from peer={}: {}", peer, e);
+                }
+                fut
+            }
+            NetworkRequest::SendMessageAck(peer, msg, result_tx) => {
+                let result = self.try_send_message(peer, msg);
+                if result_tx.send(result).is_err() {
+                    warn!("Unable to send the message result, the receiver is dropped.");
+                }
+                self.outgoing_connections.notify_outgoing_limit(self.network_tx.clone())
+            }
+            NetworkRequest::Connect(peer) => self.connect(peer),
+            NetworkRequest::RegisterSinkPeer(peer, counter) => {
+                let queue_len = self.network_config.borrow().outgoing_message_queue_len;
+                self.outgoing_connections.register_sink(peer, queue_len, counter, &self.handle);
+                to_box(future::ok(()))
+            }
+            NetworkRequest::SetPersistentPeers(peers) => {
+                self.outgoing_connections.set_persistent_peers(peers);
+                to_box(future::ok(()))
+            }
+            NetworkRequest::DisconnectWithPeer(peer) => {
+                self.outgoing_connections.disconnect_with_peer(
+                    peer,
+                    false,
+                    self.network_tx.clone(),
+                )
+            }
+            NetworkRequest::DisconnectWithPeerGraceful(peer) => {
+                self.outgoing_connections.disconnect_with_peer(
+                    peer,
+                    true,
+                    self.network_tx.clone(),
+                )
+            }
+            NetworkRequest::DisconnectAll { graceful } => self.disconnect_all(graceful),
+            NetworkRequest::StopSending(peer) => {
+                if let Err(e) = self.outgoing_connections.stop_sending(peer) {
+                    warn!("Unable to stop sending to peer={}: {}", peer, e);
+                }
+                to_box(future::ok(()))
+            }
+            NetworkRequest::QueryConnections(stats_tx) => {
+                let mut outgoing_peers = self.outgoing_connections.peers();
+                outgoing_peers.sort();
+                let stats = ConnectionStats {
+                    outgoing_count: self.outgoing_connections.len(),
+                    incoming_count: self.incoming_connections_counter.get(),
+                    outgoing_peers,
+                };
+                if stats_tx.send(stats).is_err() {
+                    warn!("Unable to send connection stats, the receiver is dropped.");
+                }
+                to_box(future::ok(()))
+            }
+            NetworkRequest::IsConnected(peer, connected_tx) => {
+                let connected = self.outgoing_connections.is_connected(peer);
+                if connected_tx.send(connected).is_err() {
+                    warn!("Unable to send connection state, the receiver is dropped.");
+                }
+                to_box(future::ok(()))
+            }
+            NetworkRequest::QuerySendCounts(counts_tx) => {
+                let counts = self.outgoing_connections.send_counts();
+                if counts_tx.send(counts).is_err() {
+                    warn!("Unable to send message counts, the receiver is dropped.");
+                }
+                to_box(future::ok(()))
+            }
+            NetworkRequest::QueryTraffic(traffic_tx) => {
+                let traffic = self.outgoing_connections.traffic();
+                if traffic_tx.send(traffic).is_err() {
+                    warn!("Unable to send traffic counters, the receiver is dropped.");
+                }
+                to_box(future::ok(()))
+            }
+            NetworkRequest::QueryLastReceived(last_received_tx) => {
+                let last_received = self.outgoing_connections.last_received();
+                if last_received_tx.send(last_received).is_err() {
+                    warn!("Unable to send last receive times, the receiver is dropped.");
+                }
+                to_box(future::ok(()))
+            }
+            NetworkRequest::QueryQueueDepths(depths_tx) => {
+                let depths = self.outgoing_connections.queue_depths();
+                if depths_tx.send(depths).is_err() {
+                    warn!("Unable to send queue depths, the receiver is dropped.");
+                }
+                to_box(future::ok(()))
+            }
+            NetworkRequest::Reconfigure(network_config) => {
+                self.reconfigure(network_config);
+                to_box(future::ok(()))
+            }
+            NetworkRequest::GracefulShutdown { timeout } => self.graceful_shutdown(timeout),
+            // Immediately stop the event loop.
+            NetworkRequest::Shutdown => to_box(cancel(&self.cancel_sender).into_future()),
+        }
+    }
+
+    fn send_message(
+        &mut self,
+        peer: SocketAddr,
+        msg: RawMessage,
+        priority: Priority,
+        policy: OverflowPolicy,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        self.send_message_with_token(peer, msg, None, priority, policy)
+    }
+
+    /// Sends the message, the `MessageSent` event with the `token` is emitted
+    /// once the message is written to the socket.
+    fn send_message_with_token(
+        &mut self,
+        peer: SocketAddr,
+        msg: RawMessage,
+        token: Option<u64>,
+        priority: Priority,
+        policy: OverflowPolicy,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let connections_tracker = match self.connections_tracker {
+            Some(ref tracker) => tracker.clone(),
+            None => {
+                warn!(
+                    "Rejected message to peer={}, the network is shutting down.",
+                    peer
+                );
+                return to_box(future::ok(()));
+            }
+        };
+
+        let connect_message = &self.connect_message;
+        // The connect message should be written before any other message.
+        let priority = if &msg == connect_message.raw() {
+            Priority::High
+        } else {
+            priority
+        };
+
+        if !self.outgoing_connections.contains(peer) {
+            if !self.reconnects_limiter.acquire(peer) {
+                warn!(
+                    "Rejected outgoing connection with peer={}, \
+                     reconnects limit reached.",
+                    peer
+                );
+                return to_box(future::ok(()));
+            }
+            let senders = self.outgoing_connections.clone().connect_to_peer(
+                self.network_config.borrow().clone(),
+                self.max_message_len,
+                peer,
+                connect_message.raw().clone(),
+                self.network_tx.clone(),
+                connections_tracker,
+                &self.handle,
+            );
+            let senders = match senders {
+                Some(senders) => senders,
+                None => return self.send_event(NetworkEvent::UnableConnectToPeer(peer)),
+            };
+            // if we create new connect, we should send connect message
+            if &msg != connect_message.raw() {
+                // The queues of the new connection are empty.
+                let connect = connect_message.raw().clone();
+                if let Err(e) = senders.high.push(connect, OverflowPolicy::DropNew) {
+                    warn!("Unable to send connect message to peer={}: {:?}", peer, e);
+                }
+            }
+        }
+
+        // The event is sent before the next request is handled, as the other
+        // connectivity events.
+        let limit = self.outgoing_connections.notify_outgoing_limit(self.network_tx.clone());
+        let sent = match self.outgoing_connections.try_send(peer, msg, token, priority, policy) {
+            Ok(None) => to_box(future::ok(())),
+            Ok(Some(_)) => {
+                warn!("Dropped the oldest message to peer={}, the queue is full.", peer);
+                self.send_event(NetworkEvent::SendQueueOverflow(peer))
+            }
+            Err(TrySendError::QueueFull) => {
+                warn!("Dropped message to peer={}, the queue is full.", peer);
+                self.send_event(NetworkEvent::SendQueueOverflow(peer))
+            }
+            Err(TrySendError::BudgetExceeded) => {
+                warn!(
+                    "Dropped message to peer={}, the outgoing bytes limit is reached.",
+                    peer
+                );
+                self.send_event(NetworkEvent::SendQueueOverflow(peer))
+            }
+            Err(e) => {
+                warn!("Dropped message to peer={}, {}.", peer, e);
+                to_box(future::ok(()))
+            }
+        };
+        to_box(limit.and_then(move |_| sent))
+    }
+
+    /// Opens the connection with the peer by sending our `Connect` message,
+    /// does nothing if the connection already exists or is pending. The messages
+    /// sent to the peer afterwards are queued into the same connection.
+    fn connect(&mut self, peer: SocketAddr) -> Box<Future<Item = (), Error = io::Error>> {
+        if self.outgoing_connections.contains(peer) {
+            trace!(
+                "Skipped connection with peer={}, it is already connected or pending.",
+                peer
+            );
+            return to_box(future::ok(()));
+        }
+        let connect = self.connect_message.raw().clone();
+        self.send_message(peer, connect, Priority::High, OverflowPolicy::DropNew)
+    }
+
+    /// Sends the first message as usual to establish the connection if needed,
+    /// the rest of the batch is queued into the same connection.
+    fn send_messages(
+        &mut self,
+        peer: SocketAddr,
+        msgs: Vec<RawMessage>,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let mut msgs = msgs.into_iter();
+        let first = match msgs.next() {
+            Some(msg) => msg,
+            None => return to_box(future::ok(())),
+        };
+        let connections = self.outgoing_connections.clone();
+        let network_tx = self.network_tx.clone();
+        let policy = self.network_config.borrow().outgoing_overflow_policy;
+        let fut = self.send_message(peer, first, Priority::Normal, policy).and_then(
+            move |_| {
+                let mut dropped = 0;
+                for msg in msgs {
+                    match connections.try_send(peer, msg, None, Priority::Normal, policy) {
+                        Ok(None) => {}
+                        Ok(Some(_)) |
+                        Err(TrySendError::QueueFull) |
+                        Err(TrySendError::BudgetExceeded) => dropped += 1,
+                        Err(e) => {
+                            warn!("Dropped messages to peer={}, {}.", peer, e);
+                            return to_box(future::ok(()));
+                        }
+                    }
+                }
+                if dropped == 0 {
+                    return to_box(future::ok(()));
+                }
+                warn!(
+                    "Dropped {} messages to peer={}, the queue is full \
+                     or the outgoing bytes limit is reached.",
+                    dropped,
+                    peer
+                );
+                let fut = network_tx
+                    .send(NetworkEvent::SendQueueOverflow(peer))
+                    .map_err(|_| other_error("can't send network event"));
+                to_box(fut)
+            },
+        );
+        to_box(fut)
+    }
+
+    fn try_send_message(&mut self, peer: SocketAddr, msg: RawMessage) -> SendResult {
+        let rejected = |reason: &str| SendResult::Rejected { reason: reason.to_owned() };
+        let connections_tracker = match self.connections_tracker {
+            Some(ref tracker) => tracker.clone(),
+            None => return rejected("the network is shutting down"),
+        };
+
+        if !self.outgoing_connections.contains(peer) {
+            if !self.reconnects_limiter.acquire(peer) {
+                return rejected("reconnects limit reached");
+            }
+            let connected = self.outgoing_connections.clone().connect_to_peer(
+                self.network_config.borrow().clone(),
+                self.max_message_len,
+                peer,
+                self.connect_message.raw().clone(),
+                self.network_tx.clone(),
+                connections_tracker,
+                &self.handle,
+            );
+            if connected.is_none() {
+                return rejected("connections limit reached");
+            }
+            // New connection should start with the connect message.
+            if &msg != self.connect_message.raw() {
+                let connect = self.connect_message.raw().clone();
+                let sent = self.outgoing_connections.try_send(
+                    peer,
+                    connect,
+                    None,
+                    Priority::High,
+                    OverflowPolicy::DropNew,
+                );
+                if let Err(e) = sent {
+                    return rejected(&e.to_string());
+                }
+            }
+        }
+
+        let priority = if &msg == self.connect_message.raw() {
+            Priority::High
+        } else {
+            Priority::Normal
+        };
+        let policy = self.network_config.borrow().outgoing_overflow_policy;
+        match self.outgoing_connections.try_send(peer, msg, None, priority, policy) {
+            Ok(_) => SendResult::Queued,
+            Err(e) => rejected(&e.to_string()),
+        }
+    }
+
+    fn reconfigure(&mut self, network_config: NetworkConfiguration) {
+        {
+            let current = self.network_config.borrow();
+            if current.tls != network_config.tls ||
+                current.outgoing_idle_timeout != network_config.outgoing_idle_timeout ||
+                current.flap_dampening != network_config.flap_dampening ||
+                current.listen_backlog != network_config.listen_backlog ||
+                current.reuse_address != network_config.reuse_address ||
+                current.reuse_port != network_config.reuse_port
+            {
+                warn!(
+                    "Changes of tls, outgoing_idle_timeout, flap_dampening, listen_backlog, \
+                     reuse_address and reuse_port take effect after restart."
+                );
+            }
+        }
+        self.reconnects_limiter.set_max_per_minute(
+            network_config.max_reconnects_per_minute,
+        );
+        self.outgoing_connections.max_buffered_bytes.set(
+            network_config.max_total_outgoing_bytes,
+        );
+        self.outgoing_connections.max_outgoing_connections.set(
+            network_config.max_outgoing_connections,
+        );
+        self.outgoing_connections.resolver.set_ttl(
+            Duration::from_millis(network_config.dns_cache_ttl),
+        );
+        self.transport.reconfigure(&network_config);
+        *self.network_config.borrow_mut() = network_config;
+    }
+
+    /// Removes all outgoing connections from the pool, the events are sent in order.
+    fn disconnect_all(&self, graceful: bool) -> Box<Future<Item = (), Error = io::Error>> {
+        let disconnections: Vec<_> = self.outgoing_connections
+            .peers()
+            .into_iter()
+            .map(|peer| {
+                self.outgoing_connections.disconnect_with_peer(
+                    peer,
+                    graceful,
+                    self.network_tx.clone(),
+                )
+            })
+            .collect();
+        let fut = stream::iter_ok(disconnections).for_each(|fut| fut);
+        to_box(fut)
+    }
+
+    /// Stops accepting new messages, waits until the buffered messages are written
+    /// to the sockets and then stops the event loop.
+    fn graceful_shutdown(
+        &mut self,
+        timeout: Milliseconds,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let connections_tracker_rx = match self.connections_tracker_rx.take() {
+            Some(rx) => rx,
+            None => return to_box(future::err::<(), _>(other_error("shutdown twice"))),
+        };
+        // Closing the outgoing channels makes writers finish after flushing their buffers.
+        self.connections_tracker = None;
+        self.outgoing_connections.clear();
+
+        let timeout = self.timer.delay(Duration::from_millis(timeout), &self.handle);
+        let cancel_sender = Rc::clone(&self.cancel_sender);
+        let fut = connections_tracker_rx
+            .for_each(|_| Ok(()))
+            .map_err(|_| other_error("can't wait for the outgoing connections"))
+            .select(timeout)
+            .then(move |_| cancel(&cancel_sender))
+            .map_err(log_error);
+        self.handle.spawn(fut);
+        to_box(future::ok(()))
+    }
+
+    /// Resolves once the event is accepted by `network_tx`, the requests are not handled
+    /// until then, so the events are not dropped if the channel is full.
+    fn send_event(&self, event: NetworkEvent) -> Box<Future<Item = (), Error = io::Error>> {
+        let fut = self.network_tx
+            .clone()
+            .send(event)
+            .map_err(|_| other_error("can't send network event"));
+        to_box(fut)
+    }
+}
+
+struct RequestHandler(
+    // TODO: Replace with concrete type
+    Box<Future<Item = (), Error = io::Error>>
+);
+
+impl RequestHandler {
+    fn new(
+        mut network_handler: NetworkHandler,
+        receiver: PriorityRequests,
+    ) -> RequestHandler {
+        let pool = network_handler.outgoing_connections.clone();
+        let network_tx = network_handler.network_tx.clone();
+        // The hosts are resolved off the reactor thread, the requests are still handled
+        // in order, but the other connections are not blocked by the lookups.
+        let requests_handler = receiver
+            .map_err(|_| other_error("no network requests"))
+            .and_then(move |request| match request {
+                NetworkRequest::SendMessageToHost(host, msg) => {
+                    let network_tx = network_tx.clone();
+                    let fut = pool.resolve_host(host.clone()).then(move |res| match res {
+                        Ok(peer) => {
+                            let request = NetworkRequest::SendMessage(peer, msg);
+                            Either::A(future::ok(Some(request)))
+                        }
+                        Err(e) => {
+                            warn!("Dropped message to host={}, {}", host, e);
+                            let event = NetworkEvent::UnresolvedHost(host);
+                            Either::B(network_tx.send(event).map_err(into_other).map(|_| None))
+                        }
+                    });
+                    Either::A(fut)
+                }
+                request => Either::B(future::ok(Some(request))),
+            })
+            .filter_map(|request| request)
+            .for_each(move |request| network_handler.handle_request(request));
+        RequestHandler(to_box(requests_handler))
+    }
+}
+
+impl Future for RequestHandler {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+
+/// Maximum number of the accept rate timer ticks per second, the higher rates refill
+/// several tokens per tick.
+const MAX_ACCEPT_RATE_TICKS: u32 = 1_000;
+
+/// Returns the period of the timer ticks refilling the tokens for the given accept rate
+/// and the number of the tokens refilled per tick.
+pub(crate) fn accept_rate_ticks(rate: u32) -> (Duration, u32) {
+    let ticks = cmp::max(cmp::min(rate, MAX_ACCEPT_RATE_TICKS), 1);
+    let refill = rate / ticks + if rate % ticks == 0 { 0 } else { 1 };
+    (Duration::from_secs(1) / ticks, refill)
+}
+
+/// Incoming connections accepted at most `rate` per second. The tokens are refilled by
+/// the timer ticks, the listener isn't polled while there are no tokens left.
+struct ThrottledIncoming {
+    incoming: IncomingConnections,
+    ticks: Ticks,
+    tokens: u32,
+    refill: u32,
+    rate: u32,
+}
+
+impl ThrottledIncoming {
+    fn new(incoming: IncomingConnections, rate: u32, timer: &Timer, handle: &Handle) -> Self {
+        let (period, refill) = accept_rate_ticks(rate);
+        ThrottledIncoming {
+            incoming,
+            ticks: timer.interval(period, handle),
+            tokens: rate,
+            refill,
+            rate,
+        }
+    }
+}
+
+impl Stream for ThrottledIncoming {
+    type Item = (Box<PeerStream>, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while let Async::Ready(tick) = self.ticks.poll()? {
+            if tick.is_none() {
+                return Err(other_error("accept rate timer is finished"));
+            }
+            self.tokens = cmp::min(self.tokens.saturating_add(self.refill), self.rate);
+        }
+        if self.tokens == 0 {
+            return Ok(Async::NotReady);
+        }
+        let conn = self.incoming.poll()?;
+        if let Async::Ready(Some(_)) = conn {
+            self.tokens -= 1;
+        }
+        Ok(conn)
+    }
+}
+
+/// Collects the frames which are ready without waiting into the batches of at most
+/// `limit` frames. The error is returned after the frames read before it.
+struct Batched<S: Stream> {
+    stream: S,
+    limit: usize,
+    error: Option<S::Error>,
+    finished: bool,
+}
+
+impl<S: Stream> Batched<S> {
+    fn new(stream: S, limit: usize) -> Self {
+        Batched {
+            stream,
+            limit: cmp::max(limit, 1),
+            error: None,
+            finished: false,
+        }
+    }
+}
+
+impl<S: Stream> Stream for Batched<S> {
+    type Item = Vec<S::Item>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.finished {
+            return Ok(Async::Ready(None));
+        }
+        let mut batch = Vec::new();
+        while batch.len() < self.limit {
+            match self.stream.poll() {
+                Ok(Async::Ready(Some(item))) => batch.push(item),
+                Ok(Async::Ready(None)) => {
+                    self.finished = true;
+                    break;
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    if batch.is_empty() {
+                        return Err(e);
+                    }
+                    self.error = Some(e);
+                    break;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            Ok(Async::Ready(Some(batch)))
+        } else if self.finished {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Passes the network events through, holding the disconnections for `window`,
+/// see `NetworkConfiguration::flap_dampening`. The held events are keyed by the direction
+/// of the connection and the peer address.
+struct FlapDamper {
+    events_rx: mpsc::Receiver<NetworkEvent>,
+    network_tx: mpsc::Sender<NetworkEvent>,
+    window: Duration,
+    timer: Rc<Timer>,
+    handle: Handle,
+    held: Vec<((bool, SocketAddr), NetworkEvent, Delay)>,
+    /// Events waiting for the free space in `network_tx`.
+    ready: VecDeque<NetworkEvent>,
+    finished: bool,
+}
+
+impl FlapDamper {
+    fn new(
+        events_rx: mpsc::Receiver<NetworkEvent>,
+        network_tx: mpsc::Sender<NetworkEvent>,
+        window: Duration,
+        timer: &Rc<Timer>,
+        handle: &Handle,
+    ) -> FlapDamper {
+        FlapDamper {
+            events_rx,
+            network_tx,
+            window,
+            timer: Rc::clone(timer),
+            handle: handle.clone(),
+            held: Vec::new(),
+            ready: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    fn process(&mut self, event: NetworkEvent) {
+        let (incoming, peer, is_disconnect) = match event {
+            NetworkEvent::PeerDisconnected { addr, .. } => (false, addr, true),
+            NetworkEvent::IncomingPeerDisconnected { addr, .. } => (true, addr, true),
+            NetworkEvent::PeerConnectedOutgoing { addr, .. } => (false, addr, false),
+            // The reconnection of the incoming peer is followed by `PeerConnected`.
+            NetworkEvent::PeerReconnected(addr) => {
+                if !self.held.iter().any(|held| held.0 == (true, addr)) {
+                    self.ready.push_back(event);
+                }
+                return;
+            }
+            NetworkEvent::PeerConnected(_, ref connect) => (true, connect.addr(), false),
+            event => {
+                self.ready.push_back(event);
+                return;
+            }
+        };
+        let key = (incoming, peer);
+        if is_disconnect {
+            let delay = self.timer.delay(self.window, &self.handle);
+            self.held.push((key, event, delay));
+        } else if let Some(pos) = self.held.iter().position(|held| held.0 == key) {
+            trace!("Suppressed reconnection of flapping peer={}", peer);
+            self.held.remove(pos);
+        } else {
+            self.ready.push_back(event);
+        }
+    }
+
+    /// Releases the disconnections held for the whole window, or all of them
+    /// once there are no more events.
+    fn release_expired(&mut self) {
+        let mut i = 0;
+        while i < self.held.len() {
+            let expired = self.finished ||
+                match self.held[i].2.poll() {
+                    Ok(Async::NotReady) => false,
+                    // The timer error releases the event as well.
+                    Ok(Async::Ready(())) | Err(_) => true,
+                };
+            if expired {
+                let (_, event, _) = self.held.remove(i);
+                self.ready.push_back(event);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl Future for FlapDamper {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            self.release_expired();
+            while let Some(event) = self.ready.pop_front() {
+                let sent = self.network_tx.start_send(event).map_err(drop)?;
+                if let AsyncSink::NotReady(event) = sent {
+                    self.ready.push_front(event);
+                    return Ok(Async::NotReady);
+                }
+            }
+            if self.finished {
+                return Ok(Async::Ready(()));
+            }
+            // The next event is taken once the previous ones are sent.
+            match self.events_rx.poll()? {
+                Async::Ready(Some(event)) => self.process(event),
+                Async::Ready(None) => self.finished = true,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+struct Listener(Box<Future<Item = (), Error = io::Error>>);
+
+impl Listener {
+    /// Binds listeners to all the given addresses and merges their incoming connections,
+    /// also returns the actual addresses of the listeners.
+    fn bind_all(
+        listen_addresses: &[SocketAddr],
+        transport: &Transport,
+        handle: &Handle,
+    ) -> Result<(IncomingConnections, Vec<SocketAddr>), NetworkError> {
+        if listen_addresses.is_empty() {
+            return Err(NetworkError::NoListenAddresses);
+        }
+        let mut incoming: IncomingConnections = Box::new(stream::empty());
+        let mut local_addresses = Vec::with_capacity(listen_addresses.len());
+        for address in listen_addresses {
+            let (listener, local_address) = transport
+                .listen(*address, handle)
+                .map_err(|source| NetworkError::BindFailed { addr: *address, source })?;
+            local_addresses.push(local_address);
+            incoming = Box::new(incoming.select(listener));
+        }
+        Ok((incoming, local_addresses))
+    }
+
+    fn bind(
+        network_handler: &NetworkHandler,
+        listen_addresses: &[SocketAddr],
+        protocol_version: u8,
+        connection_filter: Option<Box<ConnectionFilter>>,
+        message_verifier: Option<Box<MessageVerifier>>,
+    ) -> Result<(Listener, Vec<SocketAddr>), NetworkError> {
+        let connection_filter: Option<Rc<ConnectionFilter>> = connection_filter.map(Rc::from);
+        let message_verifier: Option<Rc<MessageVerifier>> = message_verifier.map(Rc::from);
+        let network_config = Rc::clone(&network_handler.network_config);
+        let max_message_len = network_handler.max_message_len;
+        let our_public_key = *network_handler.connect_message.pub_key();
+        let handle = network_handler.handle.clone();
+        let incoming_connections_counter = Rc::clone(&network_handler.incoming_connections_counter);
+        // Number of the incoming connections which have not completed the handshake yet.
+        let handshaking_counter: Rc<Cell<usize>> = Rc::default();
+        let tls = network_handler.tls.clone();
+        let timer = Rc::clone(&network_handler.timer);
+        let pending_connections =
+            Rc::clone(&network_handler.outgoing_connections.pending_connections);
+        let connections = network_handler.outgoing_connections.clone();
+        // Incoming connections handler
+        let (incoming, local_addresses) =
+            Self::bind_all(listen_addresses, &*network_handler.transport, &handle)?;
+        let accept_rate = network_config.borrow().incoming_accept_rate;
+        let incoming: IncomingConnections = match accept_rate {
+            Some(rate) if rate > 0 => {
+                Box::new(ThrottledIncoming::new(incoming, rate, &*timer, &handle))
+            }
+            _ => incoming,
+        };
+        let network_tx = network_handler.network_tx.clone();
+        let totals = Rc::clone(&connections.totals);
+        let proxy_incoming = Rc::clone(&incoming_connections_counter);
+        let proxy_handshaking = Rc::clone(&handshaking_counter);
+        let proxy_pending = Rc::clone(&pending_connections);
+        let proxy_config = Rc::clone(&network_config);
+        let proxy_timer = Rc::clone(&timer);
+        let proxy_handle = handle.clone();
+        let accept = move |sock: Box<PeerStream>,
+                           addr: SocketAddr,
+                           accepted: Instant|
+              -> Box<Future<Item = (), Error = io::Error>> {
+            let network_config = network_config.borrow().clone();
+            if !network_config.allows_ip(&addr.ip()) {
+                warn!("Rejected incoming connection with peer={}, the address is denied.", addr);
+                return to_box(future::ok(()));
+            }
+            // Incoming connections limiter
+            let incoming_connections_limit = network_config.max_incoming_connections;
+            let handshaking_limit = network_config.max_handshaking_incoming;
+            let verify_connect_address = network_config.verify_connect_address;
+            // Check incoming connections count
+            let connections_count = incoming_connections_counter.get();
+            let established_count = match handshaking_limit {
+                Some(_) => connections_count - handshaking_counter.get(),
+                None => connections_count,
+            };
+            if established_count >= incoming_connections_limit {
+                warn!(
+                    "Rejected incoming connection with peer={}, \
+                     connections limit reached.",
+                    addr
+                );
+                return to_box(future::ok(()));
+            }
+            let handshake_guard = match PendingGuard::acquire(
+                &handshaking_counter,
+                handshaking_limit.unwrap_or(usize::max_value()),
+            ) {
+                Some(guard) => guard,
+                None => {
+                    warn!(
+                        "Rejected incoming connection with peer={}, \
+                         handshaking connections limit reached.",
+                        addr
+                    );
+                    return to_box(future::ok(()));
+                }
+            };
+            let pending_limit = network_config.max_pending_connections;
+            let pending_guard = match PendingGuard::acquire(&pending_connections, pending_limit) {
+                Some(guard) => guard,
+                None => {
+                    warn!(
+                        "Rejected incoming connection with peer={}, \
+                         pending connections limit reached.",
+                        addr
+                    );
+                    return to_box(future::ok(()));
+                }
+            };
+            let handshake_timeout = network_config.incoming_handshake_timeout;
+            let timeout = timer.delay(Duration::from_millis(handshake_timeout), &handle);
+            trace!("Accepted incoming connection with peer={}", addr);
+            incoming_connections_counter.set(connections_count + 1);
+            let counter = Rc::clone(&incoming_connections_counter);
+            let established_counter = Rc::clone(&incoming_connections_counter);
+            let handshaking = Rc::clone(&handshaking_counter);
+            let connection_filter = connection_filter.clone();
+            let message_verifier = if network_config.verify_messages {
+                message_verifier.clone()
+            } else {
+                None
+            };
+            let connections = connections.clone();
+            let compression = network_config.compression;
+            // The activity counts the traffic from the start, it is registered
+            // once the handshake is completed.
+            let activity = Rc::new(ConnectionActivity::new(Rc::clone(&connections.totals)));
+            let codec = CountingCodec::new(
+                connections.codecs.new_codec(max_message_len, compression),
+                Rc::clone(&activity),
+            );
+            let errors_tx = network_tx.clone();
+            let pre_connect_tx = network_tx.clone();
+            let network_tx = network_tx.clone();
+            let pre_connect_messages = Rc::new(network_config.pre_connect_messages);
+            let max_connection_lifetime = network_config.max_connection_lifetime;
+            let rate_policy = network_config.incoming_rate_policy;
+            let batch_size = network_config.incoming_batch_size;
+            let mut first_message = network_config.report_first_message;
+            let mut rate_limiter = match network_config.incoming_message_rate {
+                Some(rate) if rate > 0 => Some(MessageRateLimiter::new(rate)),
+                _ => None,
+            };
+            let conn_timer = Rc::clone(&timer);
+            let conn_handle = handle.clone();
+            // The timeout covers both TLS handshake and receiving the `Connect` message.
+            let connection_handler = tls::accept(tls.as_ref(), sock)
+                .and_then(move |sock| {
+                    let stream = sock.framed(codec);
+                    let (sink, stream) = stream.split();
+                    // Reports the allowed messages until the first other one is received.
+                    let first = future::loop_fn(stream, move |stream| {
+                        let pre_connect_tx = pre_connect_tx.clone();
+                        let allowed = Rc::clone(&pre_connect_messages);
+                        stream.into_future().map_err(|e| e.0).and_then(
+                            move |(raw, stream)| match raw {
+                                Some(raw) => {
+                                    let kind = (raw.service_id(), raw.message_type());
+                                    if !allowed.contains(&kind) {
+                                        return Either::A(
+                                            future::ok(Loop::Break((Some(raw), stream))),
+                                        );
+                                    }
+                                    trace!("Received pre-connect message from peer={}", addr);
+                                    let event = NetworkEvent::PreConnectMessage(addr, raw);
+                                    let fut = pre_connect_tx
+                                        .send(event)
+                                        .map_err(into_other)
+                                        .map(move |_| Loop::Continue(stream));
+                                    Either::B(fut)
+                                }
+                                None => Either::A(future::ok(Loop::Break((None, stream)))),
+                            },
+                        )
+                    });
+                    first.map(move |(raw, stream)| (raw, sink, stream))
                 })
-                .map_err(log_error);
+                .select2(timeout)
+                .then(move |res| match res {
+                    Ok(Either::A((first, _))) => Ok(first),
+                    Ok(Either::B(_)) => Err(ConnectionError::HandshakeTimeout(other_error(
+                        format!(
+                            "Handshake timeout with peer={}, no Connect received in {} ms",
+                            addr,
+                            handshake_timeout
+                        ),
+                    ))),
+                    Err(Either::A((e, _))) |
+                    Err(Either::B((e, _))) => Err(ConnectionError::from(e)),
+                })
+                .and_then(move |(raw, sink, stream)| {
+                    let raw = raw.ok_or_else(|| {
+                        ConnectionError::Closed(other_error("Incoming socket closed"))
+                    })?;
+                    if raw.version() != protocol_version {
+                        return Err(ConnectionError::BadFirstMessage(invalid_data(format!(
+                            "Protocol version mismatch, expected={}, received={}",
+                            protocol_version,
+                            raw.version()
+                        ))));
+                    }
+                    let error = match Any::from_raw(raw) {
+                        Ok(Any::Connect(msg)) => return Ok((msg, sink, stream)),
+                        Ok(other) => format!("First message is not Connect, got={:?}", other),
+                        Err(e) => format!("Malformed message: {}", e),
+                    };
+                    Err(ConnectionError::BadFirstMessage(invalid_data(error)))
+                })
+                .then(move |res| {
+                    drop(pending_guard);
+                    drop(handshake_guard);
+                    res
+                })
+                .and_then(move |(connect, sink, stream)| {
+                    trace!("Received handshake message={:?}", connect);
+                    // Other connections may have completed the handshake in the meantime.
+                    let established_count = established_counter.get() - handshaking.get();
+                    if handshaking_limit.is_some() &&
+                        established_count > incoming_connections_limit
+                    {
+                        warn!(
+                            "Rejected incoming connection with peer={}, \
+                             connections limit reached.",
+                            addr
+                        );
+                        return to_box(future::ok(()));
+                    }
+                    if *connect.pub_key() == our_public_key {
+                        warn!("Rejected incoming connection with peer={}, it is our node.", addr);
+                        return to_box(future::ok(()));
+                    }
+                    if verify_connect_address && connect.addr().ip() != addr.ip() {
+                        warn!(
+                            "Rejected incoming connection with peer={}, \
+                             Connect message contains different address={}.",
+                            addr,
+                            connect.addr()
+                        );
+                        return to_box(future::ok(()));
+                    }
+                    if let Some(ref filter) = connection_filter {
+                        if !filter.allow(addr, &connect) {
+                            warn!(
+                                "Rejected incoming connection with peer={}, \
+                                 denied by the connection filter.",
+                                addr
+                            );
+                            return to_box(future::ok(()));
+                        }
+                    }
+                    let peer = connect.addr();
+                    connections.metrics.record_connection_opened(peer);
+                    connections.peer_addresses.borrow_mut().insert(*connect.pub_key(), peer);
+                    let reconnect =
+                        connections.insert_incoming(peer, *connect.pub_key(), &activity);
+                    activity.message_read();
+                    let received = Rc::clone(&activity);
+                    let event = NetworkEvent::PeerConnected(addr, connect);
+                    // Lets the peer compress the messages if the compression is enabled.
+                    let ack = match compression {
+                        Some(compression) => {
+                            Either::A(sink.send(compression_ack(compression)).map(drop))
+                        }
+                        None => Either::B(future::ok(())),
+                    };
+                    let events_tx = network_tx.clone();
+                    let stream = ack
+                        .and_then(move |_| {
+                            if reconnect {
+                                let event = NetworkEvent::PeerReconnected(peer);
+                                Either::A(events_tx.send(event).map_err(into_other))
+                            } else {
+                                Either::B(future::ok(events_tx))
+                            }
+                        })
+                        .and_then(move |events_tx| events_tx.send(event).map_err(into_other))
+                        .and_then(move |_| Ok(stream))
+                        .flatten_stream();
+
+                    let disconnected_tx = network_tx.clone();
+                    let lifetime = match max_connection_lifetime {
+                        Some(lifetime) => {
+                            conn_timer.delay(Duration::from_millis(lifetime), &conn_handle)
+                        }
+                        None => to_box(future::empty::<(), io::Error>()),
+                    };
+                    // The next messages are not read until the previous ones are accepted
+                    // by the bounded channel, so the slow handler pauses the reading.
+                    let fut = Batched::new(stream, batch_size.unwrap_or(1))
+                        .fold(network_tx, move |network_tx, mut batch| {
+                            let mut wait = None;
+                            for raw in &batch {
+                                received.message_read();
+                                if let Some(ref verifier) = message_verifier {
+                                    if let Err(reason) = verifier.verify(raw) {
+                                        let e =
+                                            format!("Message verification failed: {}", reason);
+                                        return Either::A(future::err(invalid_data(e)));
+                                    }
+                                }
+                                if let Some(ref mut limiter) = rate_limiter {
+                                    match rate_policy {
+                                        // The wait of the last message is the longest one.
+                                        RateLimitPolicy::Throttle => {
+                                            wait = limiter.acquire().or(wait);
+                                        }
+                                        RateLimitPolicy::Disconnect => {
+                                            if !limiter.try_acquire() {
+                                                let e = invalid_data("rate limit exceeded");
+                                                return Either::A(future::err(e));
+                                            }
+                                        }
+                                    }
+                                }
+                                received.message_received();
+                            }
+                            let first = if first_message {
+                                first_message = false;
+                                Some(NetworkEvent::FirstMessage(addr, batch.remove(0)))
+                            } else {
+                                None
+                            };
+                            let event = match batch_size {
+                                _ if batch.is_empty() => None,
+                                Some(_) => Some(NetworkEvent::MessagesReceived(addr, batch)),
+                                None => {
+                                    Some(NetworkEvent::MessageReceived(addr, batch.remove(0)))
+                                }
+                            };
+                            let events = first.into_iter().chain(event);
+                            let send = stream::iter_ok::<_, io::Error>(events).fold(
+                                network_tx,
+                                |network_tx, event| network_tx.send(event).map_err(into_other),
+                            );
+                            // The next message isn't read until the delay is elapsed.
+                            match wait {
+                                Some(wait) => {
+                                    let delay = conn_timer.delay(wait, &conn_handle);
+                                    Either::B(Either::A(delay.and_then(move |_| send)))
+                                }
+                                None => Either::B(Either::B(send)),
+                            }
+                        })
+                        .map(drop)
+                        .select2(lifetime)
+                        .then(move |res| match res {
+                            Ok(Either::A(_)) => Ok(()),
+                            Ok(Either::B(_)) => {
+                                trace!(
+                                    "Closed incoming connection with peer={}, \
+                                     lifetime is expired",
+                                    addr
+                                );
+                                Ok(())
+                            }
+                            Err(Either::A((e, _))) |
+                            Err(Either::B((e, _))) => Err(e),
+                        })
+                        .then(move |res| {
+                            connections.remove_incoming(&peer, &activity);
+                            connections.metrics.record_connection_closed(peer);
+                            let event = NetworkEvent::IncomingPeerDisconnected {
+                                addr: peer,
+                                duration: accepted.elapsed(),
+                            };
+                            disconnected_tx.send(event).map_err(into_other).and_then(
+                                move |_| res,
+                            )
+                        });
+                    to_box(fut.map_err(ConnectionError::from))
+                })
+                .or_else(move |e| {
+                    // The peer is notified only about the errors caused by the peer.
+                    if e.is_retryable() {
+                        return to_box(future::err::<(), _>(e));
+                    }
+                    let event = NetworkEvent::PeerErrored(addr, e.to_string());
+                    let fut = errors_tx
+                        .send(event)
+                        .map_err(|err| ConnectionError::Closed(into_other(err)))
+                        .and_then(move |_| Err::<(), _>(e));
+                    to_box(fut)
+                })
+                .then(move |res| {
+                    counter.set(counter.get() - 1);
+                    res
+                })
+                .map(drop)
+                .map_err(move |e| {
+                    warn!("Closed incoming connection with peer={}, {}", addr, e)
+                });
             handle.spawn(to_box(connection_handler));
             to_box(future::ok(()))
+        };
+        let accept = Rc::new(accept);
+        let server = incoming.for_each(move |(sock, addr)| {
+            let accepted = Instant::now();
+            totals.borrow_mut().connections_accepted += 1;
+            let network_config = proxy_config.borrow().clone();
+            if !network_config.proxy_protocol {
+                return accept(sock, addr, accepted);
+            }
+            // The sockets waiting for the header are counted as the incoming connections
+            // which have not completed the handshake, so they can't exhaust the descriptors.
+            // The counters are released before the connection is accepted with the address
+            // of the client.
+            let handshaking_limit = network_config.max_handshaking_incoming;
+            let guards = (
+                PendingGuard::acquire(&proxy_incoming, network_config.max_incoming_connections),
+                PendingGuard::acquire(
+                    &proxy_handshaking,
+                    handshaking_limit.unwrap_or(usize::max_value()),
+                ),
+                PendingGuard::acquire(&proxy_pending, network_config.max_pending_connections),
+            );
+            let guards = match guards {
+                (Some(incoming), Some(handshaking), Some(pending)) => {
+                    (incoming, handshaking, pending)
+                }
+                _ => {
+                    warn!(
+                        "Rejected incoming connection with peer={}, \
+                         connections limit reached.",
+                        addr
+                    );
+                    return to_box(future::ok(()));
+                }
+            };
+            let handshake_timeout = network_config.incoming_handshake_timeout;
+            // The address of the client is known once the header is received, so the checks
+            // of the connection are postponed until then.
+            let timeout_duration = Duration::from_millis(handshake_timeout);
+            let timeout = proxy_timer.delay(timeout_duration, &proxy_handle);
+            let accept = Rc::clone(&accept);
+            let fut = proxy_protocol::read_header(sock)
+                .select2(timeout)
+                .then(move |res| match res {
+                    Ok(Either::A((header, _))) => Ok(header),
+                    Ok(Either::B(_)) => Err(other_error(format!(
+                        "no PROXY protocol header received in {} ms",
+                        handshake_timeout
+                    ))),
+                    Err(Either::A((e, _))) |
+                    Err(Either::B((e, _))) => Err(e),
+                })
+                .and_then(move |(sock, client)| {
+                    drop(guards);
+                    accept(sock, client.unwrap_or(addr), accepted)
+                })
+                .map_err(move |e| {
+                    warn!("Rejected incoming connection with peer={}, {}", addr, e)
+                });
+            proxy_handle.spawn(fut);
+            to_box(future::ok(()))
         });
 
-        Ok(Listener(to_box(server)))
+        Ok((Listener(to_box(server)), local_addresses))
     }
 }
 
@@ -416,9 +3758,14 @@ impl Future for Listener {
     }
 }
 
-fn conn_fut<F>(fut: F) -> Box<Future<Item = mpsc::Sender<RawMessage>, Error = io::Error>>
-where
-    F: Future<Item = mpsc::Sender<RawMessage>, Error = io::Error> + 'static,
-{
-    Box::new(fut)
+fn cancel(cancel_sender: &Rc<RefCell<Option<unsync::oneshot::Sender<()>>>>) -> io::Result<()> {
+    cancel_sender
+        .borrow_mut()
+        .take()
+        .ok_or_else(|| other_error("shutdown twice"))
+        .and_then(|sender| {
+            sender.send(()).map_err(
+                |_| other_error("can't send shutdown signal"),
+            )
+        })
 }
@@ -0,0 +1,153 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Incoming connections through the load balancer which sends the PROXY protocol v2 header
+//! with the address of the client.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::{future, Future};
+use tokio_io::io::read_exact;
+
+use super::error::invalid_data;
+use super::transport::PeerStream;
+
+const SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+/// Length of the fixed part of the header: the signature, the version and command,
+/// the address family and the length of the addresses.
+pub const HEADER_LENGTH: usize = 16;
+const VERSION: u8 = 2;
+const COMMAND_LOCAL: u8 = 0;
+const COMMAND_PROXY: u8 = 1;
+const FAMILY_INET: u8 = 1;
+const FAMILY_INET6: u8 = 2;
+/// Length of the source and destination addresses together with the ports.
+const INET_ADDRESSES_LENGTH: usize = 12;
+const INET6_ADDRESSES_LENGTH: usize = 36;
+
+type HeaderFuture = Box<Future<Item = (Box<PeerStream>, Option<SocketAddr>), Error = io::Error>>;
+
+/// Reads the PROXY protocol v2 header from the accepted socket, returns the address
+/// of the client if the balancer proxies the connection. The address is not sent
+/// for the `LOCAL` connections, e.g. health checks, and for the unsupported address families.
+pub(crate) fn read_header(sock: Box<PeerStream>) -> HeaderFuture {
+    let fut = read_exact(sock, [0; HEADER_LENGTH]).and_then(|(sock, header)| -> HeaderFuture {
+        let (command, family, len) = match parse_header(&header) {
+            Ok(parsed) => parsed,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let fut = read_exact(sock, vec![0; len]).and_then(move |(sock, addresses)| {
+            if command == COMMAND_LOCAL {
+                return Ok((sock, None));
+            }
+            let addr = parse_source_address(family, &addresses)?;
+            Ok((sock, addr))
+        });
+        Box::new(fut)
+    });
+    Box::new(fut)
+}
+
+/// Returns the command, the address family and the length of the addresses.
+fn parse_header(header: &[u8; HEADER_LENGTH]) -> io::Result<(u8, u8, usize)> {
+    if header[..SIGNATURE.len()] != SIGNATURE {
+        return Err(invalid_data("Malformed PROXY protocol header signature"));
+    }
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != VERSION {
+        return Err(invalid_data(
+            format!("Unsupported PROXY protocol version={}", version),
+        ));
+    }
+    if command != COMMAND_LOCAL && command != COMMAND_PROXY {
+        return Err(invalid_data(
+            format!("Unknown PROXY protocol command={}", command),
+        ));
+    }
+    let family = header[13] >> 4;
+    let len = BigEndian::read_u16(&header[14..HEADER_LENGTH]) as usize;
+    Ok((command, family, len))
+}
+
+/// Returns the source address, the rest of the addresses block, e.g. TLVs, is ignored.
+fn parse_source_address(family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let min_len = match family {
+        FAMILY_INET => INET_ADDRESSES_LENGTH,
+        FAMILY_INET6 => INET6_ADDRESSES_LENGTH,
+        _ => return Ok(None),
+    };
+    if addresses.len() < min_len {
+        return Err(invalid_data(format!(
+            "PROXY protocol addresses are too short: {} bytes, expected {}",
+            addresses.len(),
+            min_len
+        )));
+    }
+    let addr = if family == FAMILY_INET {
+        let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+        let port = BigEndian::read_u16(&addresses[8..10]);
+        SocketAddr::V4(SocketAddrV4::new(ip, port))
+    } else {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&addresses[..16]);
+        let port = BigEndian::read_u16(&addresses[32..34]);
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+    };
+    Ok(Some(addr))
+}
+
+/// Returns the header of the proxied TCP connection from `source` to `destination`.
+/// If the addresses belong to different families, the IPv4 one is sent as the IPv4-mapped
+/// IPv6 address.
+pub fn header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source, destination) = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => (ipv6_mapped(source), destination),
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => (source, ipv6_mapped(destination)),
+        _ => (source, destination),
+    };
+    let mut data = SIGNATURE.to_vec();
+    data.push(VERSION << 4 | COMMAND_PROXY);
+    let (family, len) = match source {
+        SocketAddr::V4(_) => (FAMILY_INET, INET_ADDRESSES_LENGTH),
+        SocketAddr::V6(_) => (FAMILY_INET6, INET6_ADDRESSES_LENGTH),
+    };
+    // The transport protocol is TCP.
+    data.push(family << 4 | 1);
+    data.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    for addr in &[source, destination] {
+        match *addr {
+            SocketAddr::V4(addr) => data.extend_from_slice(&addr.ip().octets()),
+            SocketAddr::V6(addr) => data.extend_from_slice(&addr.ip().octets()),
+        }
+    }
+    for addr in &[source, destination] {
+        data.extend_from_slice(&[(addr.port() >> 8) as u8, addr.port() as u8]);
+    }
+    data
+}
+
+fn ipv6_mapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let ip = addr.ip().to_ipv6_mapped();
+            SocketAddr::V6(SocketAddrV6::new(ip, addr.port(), 0, 0))
+        }
+        SocketAddr::V6(_) => addr,
+    }
+}
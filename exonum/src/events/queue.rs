@@ -0,0 +1,223 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded queue of the outgoing messages of the single peer connection.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use futures::{Async, Poll, Stream};
+use futures::task::{self, Task};
+
+use messages::RawMessage;
+use super::network::OverflowPolicy;
+
+/// Creates the queue which holds at most `capacity` messages.
+pub(crate) fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    channel_with_counter(capacity, Rc::default())
+}
+
+/// Creates the queue which adds the size of the buffered messages to the shared counter.
+pub(crate) fn channel_with_counter<T>(
+    capacity: usize,
+    buffered_bytes: Rc<Cell<usize>>,
+) -> (Sender<T>, Receiver<T>) {
+    let inner = Rc::new(RefCell::new(Inner {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        buffered_bytes,
+        queued_bytes: 0,
+        in_flight_bytes: 0,
+        senders: 1,
+        closed: false,
+        receiver_alive: true,
+        task: None,
+    }));
+    (Sender(Rc::clone(&inner)), Receiver(inner))
+}
+
+/// Reason why the message can't be added to the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PushError {
+    Full,
+    Closed,
+}
+
+/// Queued message together with the token which is reported once the message is written.
+pub(crate) type Item<T = RawMessage> = (T, Option<u64>);
+
+struct Inner<T> {
+    buffer: VecDeque<Item<T>>,
+    capacity: usize,
+    /// Total size of the messages buffered in all queues sharing the counter.
+    buffered_bytes: Rc<Cell<usize>>,
+    /// Size of the messages buffered in this queue, including the one in flight.
+    queued_bytes: usize,
+    /// Size of the message taken by the receiver last. It is counted until the receiver
+    /// is polled again, i.e. until the writer has flushed the message to the socket.
+    in_flight_bytes: usize,
+    senders: usize,
+    /// Set by `Sender::close`, the receiver is finished once the buffer is empty.
+    closed: bool,
+    receiver_alive: bool,
+    /// Receiver task waiting for the messages.
+    task: Option<Task>,
+}
+
+impl<T> Inner<T> {
+    fn add_bytes(&mut self, len: usize) {
+        self.queued_bytes += len;
+        self.buffered_bytes.set(self.buffered_bytes.get() + len);
+    }
+
+    fn sub_bytes(&mut self, len: usize) {
+        self.queued_bytes -= len;
+        self.buffered_bytes.set(self.buffered_bytes.get() - len);
+    }
+
+    fn notify(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+/// Adds the messages to the queue, the receiver is finished once all senders are dropped.
+pub(crate) struct Sender<T = RawMessage>(Rc<RefCell<Inner<T>>>);
+
+impl<T: AsRef<[u8]>> Sender<T> {
+    /// Adds the message to the back of the queue. If the queue is full, the message is either
+    /// rejected or replaces the oldest one, which is returned, depending on the `policy`.
+    pub(crate) fn push(&self, msg: T, policy: OverflowPolicy) -> Result<Option<T>, PushError> {
+        self.push_with_token(msg, None, policy)
+    }
+
+    /// Same as `push`, but the message is received together with the `token`.
+    /// The token of the evicted message is dropped.
+    pub(crate) fn push_with_token(
+        &self,
+        msg: T,
+        token: Option<u64>,
+        policy: OverflowPolicy,
+    ) -> Result<Option<T>, PushError> {
+        let mut inner = self.0.borrow_mut();
+        if inner.closed || !inner.receiver_alive {
+            return Err(PushError::Closed);
+        }
+        let mut evicted = None;
+        if inner.buffer.len() >= inner.capacity {
+            match policy {
+                OverflowPolicy::DropOld if inner.capacity > 0 => {
+                    evicted = inner.buffer.pop_front().map(|(msg, _)| msg);
+                    if let Some(ref evicted) = evicted {
+                        inner.sub_bytes(evicted.as_ref().len());
+                    }
+                }
+                _ => return Err(PushError::Full),
+            }
+        }
+        inner.add_bytes(msg.as_ref().len());
+        inner.buffer.push_back((msg, token));
+        inner.notify();
+        Ok(evicted)
+    }
+}
+
+impl<T> Sender<T> {
+    /// Returns the number of the messages which are queued, but not taken by the receiver yet.
+    pub(crate) fn len(&self) -> usize {
+        self.0.borrow().buffer.len()
+    }
+
+    /// Rejects the new messages for all senders, the queued ones are still received.
+    pub(crate) fn close(&self) {
+        let mut inner = self.0.borrow_mut();
+        inner.closed = true;
+        inner.notify();
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.0.borrow_mut().senders += 1;
+        Sender(Rc::clone(&self.0))
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.0.borrow_mut();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            inner.notify();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.0.borrow();
+        f.debug_struct("Sender")
+            .field("len", &inner.buffer.len())
+            .field("capacity", &inner.capacity)
+            .finish()
+    }
+}
+
+/// Yields the queued messages with their tokens in order, the buffered messages
+/// are dropped with the receiver. The yielded message is counted in the shared counter
+/// until the receiver is polled for the next one or dropped.
+pub(crate) struct Receiver<T = RawMessage>(Rc<RefCell<Inner<T>>>);
+
+impl<T: AsRef<[u8]>> Stream for Receiver<T> {
+    type Item = Item<T>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Item<T>>, ()> {
+        let mut inner = self.0.borrow_mut();
+        // The previous message is written once the next one is requested.
+        let in_flight = mem::replace(&mut inner.in_flight_bytes, 0);
+        inner.sub_bytes(in_flight);
+        if let Some(item) = inner.buffer.pop_front() {
+            inner.in_flight_bytes = item.0.as_ref().len();
+            return Ok(Async::Ready(Some(item)));
+        }
+        if inner.senders == 0 || inner.closed {
+            return Ok(Async::Ready(None));
+        }
+        inner.task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.0.borrow_mut();
+        inner.receiver_alive = false;
+        let len = inner.queued_bytes;
+        inner.sub_bytes(len);
+        inner.buffer.clear();
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("len", &self.0.borrow().buffer.len())
+            .finish()
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Outgoing connections through the SOCKS5 proxy (RFC 1928).
+
+use std::io;
+use std::net::SocketAddr;
+
+use futures::{future, Future};
+use tokio_core::net::TcpStream;
+use tokio_io::io::{read_exact, write_all};
+
+use super::error::{invalid_data, other_error};
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0;
+const AUTH_PASSWORD: u8 = 2;
+const AUTH_PASSWORD_VERSION: u8 = 1;
+const COMMAND_CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+type SocketFuture = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+/// SOCKS5 proxy used for the outgoing connections.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    /// Address of the proxy.
+    pub address: SocketAddr,
+    /// Credentials for the username/password authentication (RFC 1929), if it is not set
+    /// the proxy should not require authentication.
+    pub auth: Option<ProxyAuth>,
+}
+
+/// Credentials of the SOCKS5 proxy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Performs the SOCKS5 handshake over the socket connected to the proxy,
+/// the returned socket is connected to the `target`.
+pub(crate) fn connect(
+    sock: TcpStream,
+    target: SocketAddr,
+    auth: Option<ProxyAuth>,
+) -> SocketFuture {
+    let method = if auth.is_some() {
+        AUTH_PASSWORD
+    } else {
+        AUTH_NONE
+    };
+    let fut = write_all(sock, [SOCKS_VERSION, 1, method])
+        .and_then(|(sock, _)| read_exact(sock, [0; 2]))
+        .and_then(move |(sock, reply)| -> SocketFuture {
+            if reply[0] != SOCKS_VERSION {
+                return Box::new(future::err(invalid_data("Proxy is not SOCKS5")));
+            }
+            match (reply[1], auth) {
+                (AUTH_NONE, _) => Box::new(future::ok(sock)),
+                (AUTH_PASSWORD, Some(auth)) => authenticate(sock, &auth),
+                _ => Box::new(future::err(
+                    other_error("Proxy rejected the authentication method"),
+                )),
+            }
+        })
+        .and_then(move |sock| write_all(sock, connect_request(target)))
+        .and_then(|(sock, _)| read_exact(sock, [0; 4]))
+        .and_then(|(sock, header)| -> SocketFuture {
+            if header[1] != 0 {
+                let e = other_error(format!("Proxy is unable to connect, reply={}", header[1]));
+                return Box::new(future::err(e));
+            }
+            // Skip the address bound by the proxy.
+            match header[3] {
+                ADDRESS_IPV4 => skip(sock, 4 + 2),
+                ADDRESS_IPV6 => skip(sock, 16 + 2),
+                ADDRESS_DOMAIN => {
+                    let fut = read_exact(sock, [0; 1]).and_then(|(sock, len)| {
+                        skip(sock, usize::from(len[0]) + 2)
+                    });
+                    Box::new(fut)
+                }
+                other => Box::new(future::err(invalid_data(
+                    format!("Unknown proxy address type={}", other),
+                ))),
+            }
+        });
+    Box::new(fut)
+}
+
+fn authenticate(sock: TcpStream, auth: &ProxyAuth) -> SocketFuture {
+    let username = auth.username.as_bytes();
+    let password = auth.password.as_bytes();
+    if username.len() > 255 || password.len() > 255 {
+        return Box::new(future::err(
+            other_error("Proxy username and password should not exceed 255 bytes"),
+        ));
+    }
+    let mut request = vec![AUTH_PASSWORD_VERSION, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+
+    let fut = write_all(sock, request)
+        .and_then(|(sock, _)| read_exact(sock, [0; 2]))
+        .and_then(|(sock, reply)| if reply[1] == 0 {
+            Ok(sock)
+        } else {
+            Err(other_error("Proxy authentication failed"))
+        });
+    Box::new(fut)
+}
+
+fn connect_request(target: SocketAddr) -> Vec<u8> {
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(ADDRESS_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(ADDRESS_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.push((target.port() >> 8) as u8);
+    request.push(target.port() as u8);
+    request
+}
+
+fn skip(sock: TcpStream, len: usize) -> SocketFuture {
+    Box::new(read_exact(sock, vec![0; len]).map(|(sock, _)| sock))
+}
@@ -12,23 +12,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::SocketAddr;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, TcpStream};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
-use std::time::{self, Duration};
+use std::time::{self, Duration, Instant};
 
-use futures::{Future, Sink, Stream};
+use futures::{future, stream, Async, Future, Sink, Stream};
 use futures::stream::Wait;
-use futures::sync::mpsc;
-use tokio_core::reactor::Core;
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::{Core, Handle, Timeout};
 use tokio_timer::{TimeoutStream, Timer};
 
 use crypto::{gen_keypair, PublicKey, Signature};
 use messages::{Connect, Message, MessageWriter, RawMessage};
-use events::{NetworkEvent, NetworkRequest};
-use events::network::{NetworkConfiguration, NetworkPart};
-use events::error::log_error;
+use events::{CodecFactory, Compression, ConnectFailure, ConnectionFilter, ConnectionStats, Event,
+             EventHandler, HandlerPart, IpNet, MemoryTransport, MessageVerifier,
+             MessagesCodecFactory, MockTimer, NetworkError, NetworkEvent, NetworkMetrics,
+             NetworkRequest, NetworkRunSummary, NoopMetrics, OverflowPolicy, PeerCodec, Priority,
+             ProxyAuth, ProxyConfig, RateLimitPolicy, SendResult, SinkCounter, TcpKeepAliveConfig,
+             TlsConfig};
+#[cfg(unix)]
+use events::{Endpoint, UnixTransport};
+#[cfg(unix)]
+use events::transport::UnixPeers;
+use events::queue::{self, PushError};
+use events::proxy_protocol;
+use events::transport::{IncomingConnections, PeerStreamFuture, Transport};
+use events::network::{accept_rate_ticks, ConnectionsPool, NetworkConfiguration, NetworkPart,
+                      PriorityReceiver, PriorityRequests, ReconnectsLimiter, RetryJitter,
+                      RetryStrategy, SeenPeers, SlotPermit, SlotsLimiter, TrySendError};
+use events::error::{invalid_data, log_error, other_error, ConnectionError};
 use node::{EventsPoolCapacity, NodeChannel};
 use blockchain::ConsensusConfig;
+use helpers::Milliseconds;
 
 #[derive(Debug)]
 pub struct TestHandler {
@@ -54,7 +77,21 @@ impl TestHandler {
         }
     }
 
+    /// Waits for the next event, skipping the `PeerConnectedOutgoing`, `ConnectRetry`,
+    /// `PeerReconnected` and `IncomingPeerDisconnected` notifications.
     pub fn wait_for_event(&mut self) -> Result<NetworkEvent, ()> {
+        loop {
+            match self.next_event()? {
+                NetworkEvent::PeerConnectedOutgoing { .. } |
+                NetworkEvent::ConnectRetry { .. } |
+                NetworkEvent::PeerReconnected(..) |
+                NetworkEvent::IncomingPeerDisconnected { .. } => {}
+                event => return Ok(event),
+            }
+        }
+    }
+
+    pub fn next_event(&mut self) -> Result<NetworkEvent, ()> {
         let event = self.network_events_rx.next().unwrap()?;
         Ok(event)
     }
@@ -68,6 +105,14 @@ impl TestHandler {
             .unwrap();
     }
 
+    pub fn connect_only(&self, addr: SocketAddr) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::Connect(addr))
+            .wait()
+            .unwrap();
+    }
+
     pub fn disconnect_with(&self, addr: SocketAddr) {
         self.network_requests_tx
             .clone()
@@ -76,6 +121,64 @@ impl TestHandler {
             .unwrap();
     }
 
+    pub fn disconnect_with_graceful(&self, addr: SocketAddr) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::DisconnectWithPeerGraceful(addr))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn send_to_key(&self, key: PublicKey, msg: RawMessage) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SendMessageToKey(key, msg))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn send_to_host(&self, host: &str, msg: RawMessage) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SendMessageToHost(host.to_owned(), msg))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn register_sink(&self, addr: SocketAddr) -> SinkCounter {
+        let counter = SinkCounter::new();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::RegisterSinkPeer(addr, counter.clone()))
+            .wait()
+            .unwrap();
+        counter
+    }
+
+    pub fn set_persistent_peers(&self, peers: Vec<SocketAddr>) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SetPersistentPeers(peers))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn disconnect_all(&self) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::DisconnectAll { graceful: false })
+            .wait()
+            .unwrap();
+    }
+
+    pub fn stop_sending(&self, addr: SocketAddr) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::StopSending(addr))
+            .wait()
+            .unwrap();
+    }
+
     pub fn send_to(&self, addr: SocketAddr, raw: RawMessage) {
         self.network_requests_tx
             .clone()
@@ -84,6 +187,110 @@ impl TestHandler {
             .unwrap();
     }
 
+    pub fn send_and_disconnect(&self, addr: SocketAddr, raw: RawMessage) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SendMessageAndDisconnect(addr, raw))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn send_to_tracked(&self, addr: SocketAddr, raw: RawMessage, token: u64) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SendMessageTracked(addr, raw, token))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn send_to_priority(&self, addr: SocketAddr, raw: RawMessage, priority: Priority) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SendMessagePriority(addr, raw, priority))
+            .wait()
+            .unwrap();
+    }
+
+    pub fn send_to_ack(&self, addr: SocketAddr, raw: RawMessage) -> SendResult {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::SendMessageAck(addr, raw, result_tx))
+            .wait()
+            .unwrap();
+        result_rx.wait().unwrap()
+    }
+
+    pub fn query_connections(&self) -> ConnectionStats {
+        let (stats_tx, stats_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::QueryConnections(stats_tx))
+            .wait()
+            .unwrap();
+        stats_rx.wait().unwrap()
+    }
+
+    pub fn is_connected(&self, addr: SocketAddr) -> bool {
+        let (connected_tx, connected_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::IsConnected(addr, connected_tx))
+            .wait()
+            .unwrap();
+        connected_rx.wait().unwrap()
+    }
+
+    pub fn query_send_counts(&self) -> HashMap<SocketAddr, u64> {
+        let (counts_tx, counts_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::QuerySendCounts(counts_tx))
+            .wait()
+            .unwrap();
+        counts_rx.wait().unwrap()
+    }
+
+    pub fn reconfigure(&self, network_config: NetworkConfiguration) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::Reconfigure(network_config))
+            .wait()
+            .unwrap();
+        // Requests are handled in order, so the configuration is applied after the response.
+        self.query_connections();
+    }
+
+    pub fn query_traffic(&self) -> HashMap<SocketAddr, (u64, u64)> {
+        let (traffic_tx, traffic_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::QueryTraffic(traffic_tx))
+            .wait()
+            .unwrap();
+        traffic_rx.wait().unwrap()
+    }
+
+    pub fn query_last_received(&self) -> HashMap<SocketAddr, Duration> {
+        let (last_received_tx, last_received_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::QueryLastReceived(last_received_tx))
+            .wait()
+            .unwrap();
+        last_received_rx.wait().unwrap()
+    }
+
+    pub fn query_queue_depths(&self) -> HashMap<SocketAddr, usize> {
+        let (depths_tx, depths_rx) = oneshot::channel();
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::QueryQueueDepths(depths_tx))
+            .wait()
+            .unwrap();
+        depths_rx.wait().unwrap()
+    }
+
     pub fn wait_for_connect(&mut self) -> Connect {
         match self.wait_for_event() {
             Ok(NetworkEvent::PeerConnected(_addr, connect)) => connect,
@@ -92,9 +299,20 @@ impl TestHandler {
         }
     }
 
+    pub fn wait_for_outgoing_connect(&mut self) -> (SocketAddr, Duration) {
+        match self.next_event() {
+            Ok(NetworkEvent::PeerConnectedOutgoing {
+                   addr,
+                   connect_duration,
+               }) => (addr, connect_duration),
+            Ok(other) => panic!("Unexpected outgoing connect received, {:?}", other),
+            Err(e) => panic!("An error during wait for outgoing connect occurred, {:?}", e),
+        }
+    }
+
     pub fn wait_for_disconnect(&mut self) -> SocketAddr {
         match self.wait_for_event() {
-            Ok(NetworkEvent::PeerDisconnected(addr)) => addr,
+            Ok(NetworkEvent::PeerDisconnected { addr, .. }) => addr,
             Ok(other) => panic!("Unexpected disconnect received, {:?}", other),
             Err(e) => panic!("An error during wait for disconnect occurred, {:?}", e),
         }
@@ -116,16 +334,92 @@ impl TestHandler {
             .unwrap();
         self.handle.take().expect("shutdown twice").join().unwrap();
     }
+
+    pub fn graceful_shutdown(&mut self, timeout: Milliseconds) {
+        self.network_requests_tx
+            .clone()
+            .send(NetworkRequest::GracefulShutdown { timeout })
+            .wait()
+            .unwrap();
+        self.handle.take().expect("shutdown twice").join().unwrap();
+    }
 }
 
 impl Drop for TestHandler {
     fn drop(&mut self) {
-        if !::std::thread::panicking() {
+        if !::std::thread::panicking() && self.handle.is_some() {
             self.shutdown();
         }
     }
 }
 
+/// Records all network events emitted by the node, so the tests can check their sequence
+/// without running the `HandlerPart`.
+#[derive(Debug, Clone)]
+pub struct TestEventSink {
+    events: Arc<Mutex<Vec<NetworkEvent>>>,
+}
+
+impl TestEventSink {
+    /// Starts recording the events in the background thread, which finishes once
+    /// all senders are dropped.
+    pub fn new(network_events_rx: mpsc::Receiver<NetworkEvent>) -> TestEventSink {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        thread::spawn(move || {
+            for event in network_events_rx.wait() {
+                match event {
+                    Ok(event) => recorded.lock().unwrap().push(event),
+                    Err(_) => break,
+                }
+            }
+        });
+        TestEventSink { events }
+    }
+
+    /// Returns the events recorded so far.
+    pub fn events(&self) -> MutexGuard<Vec<NetworkEvent>> {
+        self.events.lock().unwrap()
+    }
+
+    /// Waits until at least `len` events are recorded, panics after 30 seconds.
+    pub fn wait_for_len(&self, len: usize) {
+        for _ in 0..3_000 {
+            if self.events().len() >= len {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Expected {} events, recorded {:?}", len, *self.events());
+    }
+
+    /// Waits for the events and checks that their variants match the sequence,
+    /// e.g. `[("PeerConnected", 1), ("MessageReceived", 3)]`. The later events are ignored.
+    pub fn expect_sequence(&self, expected: &[(&str, usize)]) {
+        let expected: Vec<&str> = expected
+            .iter()
+            .flat_map(|&(name, count)| iter::repeat(name).take(count))
+            .collect();
+        self.wait_for_len(expected.len());
+        let recorded: Vec<String> = self.events()
+            .iter()
+            .take(expected.len())
+            .map(event_name)
+            .collect();
+        assert_eq!(recorded, expected);
+    }
+}
+
+/// Returns the name of the event variant.
+fn event_name(event: &NetworkEvent) -> String {
+    let debug = format!("{:?}", event);
+    debug
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
 #[derive(Debug)]
 pub struct TestEvents {
     pub listen_address: SocketAddr,
@@ -144,15 +438,21 @@ impl TestEvents {
 
     pub fn spawn(self) -> TestHandler {
         let (mut handler_part, network_part) = self.into_reactor();
-        let handle = thread::spawn(move || {
-            let mut core = Core::new().unwrap();
-            let fut = network_part.run(&core.handle());
-            core.run(fut).map_err(log_error).unwrap();
-        });
-        handler_part.handle = Some(handle);
+        handler_part.handle = Some(spawn_network_part(network_part));
         handler_part
     }
 
+    /// Same as `spawn`, but the events are recorded by the returned sink instead
+    /// of the handler.
+    pub fn spawn_with_sink(self) -> (TestHandler, TestEventSink) {
+        let capacity = self.events_config.network_events_capacity;
+        let (mut handler_part, mut network_part) = self.into_reactor();
+        let (network_tx, network_rx) = mpsc::channel(capacity);
+        network_part.network_tx = network_tx;
+        handler_part.handle = Some(spawn_network_part(network_part));
+        (handler_part, TestEventSink::new(network_rx))
+    }
+
     fn into_reactor(self) -> (TestHandler, NetworkPart) {
         let channel = NodeChannel::new(&self.events_config);
         let network_config = self.network_config;
@@ -161,11 +461,24 @@ impl TestEvents {
 
         let network_part = NetworkPart {
             our_connect_message: connect_message(self.listen_address),
-            listen_address: self.listen_address,
+            protocol_version: ::messages::PROTOCOL_MAJOR_VERSION,
+            listen_addresses: vec![self.listen_address],
             network_config,
             max_message_len: ConsensusConfig::DEFAULT_MESSAGE_MAX_LEN,
             network_requests: channel.network_requests,
+            control_requests: None,
             network_tx: network_tx.clone(),
+            local_addresses_tx: None,
+            ready_tx: None,
+            connection_filter: None,
+            message_verifier: None,
+            shutdown_rx: None,
+            transport: None,
+            timer: None,
+            metrics: None,
+            codec_factory: None,
+            subscribers: Vec::new(),
+            running: None,
         };
 
         let handler_part = TestHandler::new(self.listen_address, network_requests_tx, network_rx);
@@ -173,9 +486,36 @@ impl TestEvents {
     }
 }
 
+fn spawn_network_part(network_part: NetworkPart) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        network_part.run().map_err(log_error).unwrap();
+    })
+}
+
 pub fn connect_message(addr: SocketAddr) -> Connect {
     let time = time::UNIX_EPOCH;
-    Connect::new_with_signature(&PublicKey::zero(), addr, time, &Signature::zero())
+    // Every test node should have its own public key.
+    let mut key = [0; 32];
+    key[0] = (addr.port() >> 8) as u8;
+    key[1] = addr.port() as u8;
+    Connect::new_with_signature(&PublicKey::new(key), addr, time, &Signature::zero())
+}
+
+/// Opens a plain TCP connection, waiting until the node starts listening.
+pub fn connect_raw(addr: SocketAddr) -> TcpStream {
+    let mut attempts = 50;
+    loop {
+        match TcpStream::connect(&addr) {
+            Ok(sock) => return sock,
+            Err(e) => {
+                attempts -= 1;
+                if attempts == 0 {
+                    panic!("Unable to connect to {}: {}", addr, e);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
 }
 
 pub fn raw_message(id: u16, len: usize) -> RawMessage {
@@ -288,7 +628,10 @@ fn test_network_max_message_len() {
     assert_eq!(e2.wait_for_message(), acceptable_message);
 
     e2.send_to(first, too_big_message.clone());
-    assert!(e1.wait_for_event().is_err());
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerErrored(..)) => {}
+        other => panic!("Unexpected event: {:?}", other),
+    }
 }
 
 #[test]
@@ -367,3 +710,3036 @@ fn test_send_first_not_connect() {
     assert_eq!(node.wait_for_connect(), connect_message(other));
     assert_eq!(node.wait_for_message(), message);
 }
+
+#[test]
+fn test_exponential_retry_strategy() {
+    let strategy = RetryStrategy::Exponential {
+        base: 2,
+        max_interval: 1_000,
+    };
+    let delays: Vec<_> = strategy.delays(100, 5, RetryJitter::Full).collect();
+    assert_eq!(delays.len(), 5);
+    for (n, delay) in delays.into_iter().enumerate() {
+        let upper_bound = ::std::cmp::min(100 << (n + 1), 1_000);
+        assert!(delay <= Duration::from_millis(upper_bound));
+    }
+}
+
+#[test]
+fn test_retry_jitter() {
+    let timeout = Duration::from_millis(100);
+
+    let delays: Vec<_> = RetryStrategy::Fixed.delays(100, 5, RetryJitter::None).collect();
+    assert_eq!(delays, vec![timeout; 5]);
+
+    for delay in RetryStrategy::Fixed.delays(100, 5, RetryJitter::Equal) {
+        assert!(delay >= timeout / 2 && delay <= timeout);
+    }
+    for delay in RetryStrategy::Fixed.delays(100, 5, RetryJitter::Full) {
+        assert!(delay <= timeout);
+    }
+}
+
+#[test]
+fn test_network_connect_failed() {
+    let main = "127.0.0.1:19700".parse().unwrap();
+    let unreachable = "127.0.0.1:19701".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(main);
+    events.network_config.tcp_connect_retry_timeout = 10;
+    events.network_config.tcp_connect_max_retries = 2;
+    let mut node = events.spawn();
+
+    node.connect_with(unreachable);
+    match node.wait_for_event() {
+        Ok(NetworkEvent::ConnectFailed(addr, _)) => assert_eq!(addr, unreachable),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_reconnects_limiter() {
+    let first = "127.0.0.1:19800".parse().unwrap();
+    let second = "127.0.0.1:19801".parse().unwrap();
+
+    let mut limiter = ReconnectsLimiter::new(2);
+    assert!(limiter.acquire(first));
+    assert!(limiter.acquire(first));
+    assert!(!limiter.acquire(first));
+    assert!(limiter.acquire(second));
+
+    let mut unlimited = ReconnectsLimiter::new(0);
+    for _ in 0..100 {
+        assert!(unlimited.acquire(first));
+    }
+    assert_eq!(unlimited.peers_count(), 0);
+}
+
+#[test]
+fn test_reconnects_limiter_removes_full_buckets() {
+    let first = "127.0.0.1:19800".parse().unwrap();
+    let second = "127.0.0.1:19801".parse().unwrap();
+
+    let mut limiter = ReconnectsLimiter::new(2);
+    let now = Instant::now();
+    assert!(limiter.acquire_at(first, now));
+    assert!(limiter.acquire_at(second, now));
+    assert_eq!(limiter.peers_count(), 2);
+
+    // The buckets are refilled after a minute, so they are removed by the next sweep.
+    let later = now + Duration::from_secs(61);
+    assert!(limiter.acquire_at(first, later));
+    assert_eq!(limiter.peers_count(), 1);
+    assert!(limiter.acquire_at(first, later));
+    assert!(!limiter.acquire_at(first, later));
+}
+
+#[test]
+fn test_network_query_connections() {
+    let first = "127.0.0.1:19900".parse().unwrap();
+    let second = "127.0.0.1:19901".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+
+    let stats = e1.query_connections();
+    assert_eq!(stats.outgoing_count, 1);
+    assert_eq!(stats.incoming_count, 0);
+    assert_eq!(stats.outgoing_peers, vec![second]);
+
+    let stats = e2.query_connections();
+    assert_eq!(stats.outgoing_count, 0);
+    assert_eq!(stats.incoming_count, 1);
+    assert!(stats.outgoing_peers.is_empty());
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    assert_eq!(e1.query_connections().outgoing_count, 0);
+}
+
+#[test]
+fn test_network_incoming_connections_limit() {
+    let addr = "127.0.0.1:19910".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(addr);
+    events.network_config.max_incoming_connections = 2;
+    let _node = events.spawn();
+
+    let mut sockets = vec![connect_raw(addr)];
+    sockets.extend((0..2).map(|_| TcpStream::connect(&addr).unwrap()));
+
+    // The last connection exceeds the limit and should be closed by the node.
+    let mut buf = [0; 1];
+    let rejected = &mut sockets[2];
+    rejected
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(rejected.read(&mut buf).unwrap(), 0);
+
+    for accepted in &mut sockets[..2] {
+        accepted
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(accepted.read(&mut buf).is_err());
+    }
+}
+
+#[test]
+fn test_network_graceful_shutdown() {
+    let first = "127.0.0.1:19920".parse().unwrap();
+    let second = "127.0.0.1:19921".parse().unwrap();
+
+    let messages: Vec<_> = (0..5).map(|i| raw_message(i, 100_000)).collect();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+
+    for msg in &messages {
+        e1.send_to(second, msg.clone());
+    }
+    e1.graceful_shutdown(10_000);
+
+    for msg in messages {
+        assert_eq!(e2.wait_for_message(), msg);
+    }
+}
+
+#[test]
+fn test_network_incoming_handshake_timeout() {
+    let addr = "127.0.0.1:19930".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(addr);
+    events.network_config.incoming_handshake_timeout = 200;
+    let mut node = events.spawn();
+
+    // Open the connection, but never send the `Connect` message.
+    let mut sock = connect_raw(addr);
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(sock.read(&mut buf).unwrap(), 0);
+    assert_eq!(node.query_connections().incoming_count, 0);
+}
+
+#[test]
+fn test_network_multiple_listen_addresses() {
+    let first = "127.0.0.1:19940".parse().unwrap();
+    let first_extra = "127.0.0.1:19941".parse().unwrap();
+    let second = "127.0.0.1:19942".parse().unwrap();
+    let third = "127.0.0.1:19943".parse().unwrap();
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    network_part.listen_addresses.push(first_extra);
+    e1.handle = Some(spawn_network_part(network_part));
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    let mut e3 = TestEvents::with_addr(third).spawn();
+
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    e3.connect_with(first_extra);
+    assert_eq!(e1.wait_for_connect(), connect_message(third));
+
+    assert_eq!(e1.query_connections().incoming_count, 2);
+}
+
+#[test]
+fn test_network_reject_self_connection() {
+    let first = "127.0.0.1:19950".parse().unwrap();
+    let second = "127.0.0.1:19951".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    // Pretend to be the node itself.
+    let mut sock = connect_raw(first);
+    sock.write_all(connect_message(first).raw().as_ref()).unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(sock.read(&mut buf).unwrap(), 0);
+
+    // The first `PeerConnected` event should come from the real peer.
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+}
+
+#[test]
+fn test_network_reuse_pending_connection() {
+    let first = "127.0.0.1:19960".parse().unwrap();
+    let second = "127.0.0.1:19961".parse().unwrap();
+
+    let m1 = raw_message(21, 1000);
+    let m2 = raw_message(22, 1000);
+
+    // The second node is not started yet, so the connection stays pending.
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_connect_retry_timeout = 100;
+    events.network_config.tcp_connect_max_retries = 50;
+    let mut e1 = events.spawn();
+    e1.send_to(second, m1.clone());
+    e1.send_to(second, m2.clone());
+    assert_eq!(e1.query_connections().outgoing_count, 1);
+
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), m1);
+    assert_eq!(e2.wait_for_message(), m2);
+    assert_eq!(e2.query_connections().incoming_count, 1);
+}
+
+#[test]
+fn test_network_write_timeout() {
+    let first = "127.0.0.1:19970".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:19971".parse().unwrap();
+
+    // The peer accepts the connection, but never reads from it.
+    let _listener = TcpListener::bind(&second).unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.write_timeout = Some(500);
+    events.network_config.outgoing_message_queue_len = 32;
+    let mut e1 = events.spawn();
+
+    // Enough data to fill the socket buffers.
+    for i in 0..24 {
+        e1.send_to(second, raw_message(i, 1_000_000));
+    }
+    assert_eq!(e1.wait_for_disconnect(), second);
+}
+
+#[test]
+fn test_network_send_counts() {
+    let first = "127.0.0.1:19980".parse().unwrap();
+    let second = "127.0.0.1:19981".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    e1.send_to(second, raw_message(31, 1000));
+    e1.send_to(second, raw_message(32, 1000));
+    e2.wait_for_message();
+    e2.wait_for_message();
+    assert_eq!(e1.query_send_counts().get(&second), Some(&3));
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    assert!(e1.query_send_counts().is_empty());
+
+    // Counter starts from zero for the new connection.
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    assert_eq!(e1.query_send_counts().get(&second), Some(&1));
+}
+
+#[test]
+fn test_network_tls() {
+    let first = "127.0.0.1:19990".parse().unwrap();
+    let second = "127.0.0.1:19991".parse().unwrap();
+
+    let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/tls");
+    let tls_config = TlsConfig {
+        cert_path: testdata.join("node.crt"),
+        key_path: testdata.join("node.key"),
+        ca_path: Some(testdata.join("node.crt")),
+    };
+    let tls_events = |addr| {
+        let mut events = TestEvents::with_addr(addr);
+        events.network_config.tls = Some(tls_config.clone());
+        events
+    };
+
+    let m1 = raw_message(41, 1000);
+    let m2 = raw_message(42, 1000);
+
+    let mut e1 = tls_events(first).spawn();
+    let mut e2 = tls_events(second).spawn();
+
+    e1.connect_with(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+
+    e1.send_to(second, m1.clone());
+    assert_eq!(e2.wait_for_message(), m1);
+    e2.send_to(first, m2.clone());
+    assert_eq!(e1.wait_for_message(), m2);
+}
+
+#[test]
+fn test_network_local_addresses() {
+    let first = "127.0.0.1:0".parse().unwrap();
+    let second = "127.0.0.1:19992".parse().unwrap();
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    let (local_addresses_tx, local_addresses_rx) = oneshot::channel();
+    network_part.local_addresses_tx = Some(local_addresses_tx);
+    e1.handle = Some(spawn_network_part(network_part));
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let local_addresses = local_addresses_rx.wait().unwrap();
+    assert_eq!(local_addresses.len(), 1);
+    let bound = local_addresses[0];
+    assert_ne!(bound.port(), 0);
+
+    e2.connect_with(bound);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+}
+
+#[test]
+fn test_network_send_message_ack() {
+    let first = "127.0.0.1:19993".parse().unwrap();
+    let second = "127.0.0.1:19994".parse().unwrap();
+    let stalled: SocketAddr = "127.0.0.1:19995".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.outgoing_message_queue_len = 2;
+    let e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let msg = raw_message(51, 1000);
+    assert_eq!(e1.send_to_ack(second, msg.clone()), SendResult::Queued);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), msg);
+
+    // The peer never reads, so its queue is eventually filled.
+    let _listener = TcpListener::bind(&stalled).unwrap();
+    let rejected = (0..100)
+        .map(|i| e1.send_to_ack(stalled, raw_message(i, 1_000_000)))
+        .find(|result| *result != SendResult::Queued);
+    assert_eq!(
+        rejected,
+        Some(SendResult::Rejected { reason: "the queue of the peer is full".to_owned() })
+    );
+}
+
+#[test]
+fn test_network_outgoing_idle_timeout() {
+    let first = "127.0.0.1:19996".parse().unwrap();
+    let second = "127.0.0.1:19997".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.outgoing_idle_timeout = Some(300);
+    let mut e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+
+    assert_eq!(e1.wait_for_disconnect(), second);
+    assert_eq!(e1.query_connections().outgoing_count, 0);
+}
+
+#[derive(Debug)]
+struct DenyKey(PublicKey);
+
+impl ConnectionFilter for DenyKey {
+    fn allow(&self, _addr: SocketAddr, connect: &Connect) -> bool {
+        *connect.pub_key() != self.0
+    }
+}
+
+#[test]
+fn test_network_connection_filter() {
+    let first = "127.0.0.1:19998".parse().unwrap();
+    let second = "127.0.0.1:19999".parse().unwrap();
+    let third = "127.0.0.1:19800".parse().unwrap();
+
+    let denied_key = *connect_message(second).pub_key();
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    network_part.connection_filter = Some(Box::new(DenyKey(denied_key)));
+    e1.handle = Some(spawn_network_part(network_part));
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    let e3 = TestEvents::with_addr(third).spawn();
+
+    e2.connect_with(first);
+    // The denied connection is closed by the first node.
+    assert_eq!(e2.wait_for_disconnect(), first);
+
+    // The first `PeerConnected` event should come from the allowed peer.
+    e3.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(third));
+}
+
+#[derive(Debug)]
+struct RejectType(u16);
+
+impl MessageVerifier for RejectType {
+    fn verify(&self, raw: &RawMessage) -> Result<(), String> {
+        if raw.message_type() == self.0 {
+            Err(format!("message type {} is rejected", self.0))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_network_message_verifier() {
+    let first = "127.0.0.1:18016".parse().unwrap();
+    let second = "127.0.0.1:18017".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.verify_messages = true;
+    let (mut e1, mut network_part) = events.into_reactor();
+    network_part.message_verifier = Some(Box::new(RejectType(62)));
+    e1.handle = Some(spawn_network_part(network_part));
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    let valid = raw_message(61, 1000);
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    e2.send_to(first, valid.clone());
+    assert_eq!(e1.wait_for_message(), valid);
+
+    // The invalid message is not passed to the node, the connection is closed.
+    e2.send_to(first, raw_message(62, 1000));
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerErrored(_, reason)) => assert!(reason.contains("rejected")),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_peer_errored() {
+    let addr = "127.0.0.1:19801".parse().unwrap();
+    let mut node = TestEvents::with_addr(addr).spawn();
+
+    // The first message must be a valid `Connect`.
+    let mut sock = connect_raw(addr);
+    let peer = sock.local_addr().unwrap();
+    sock.write_all(raw_message(61, 1000).as_ref()).unwrap();
+    match node.wait_for_event() {
+        Ok(NetworkEvent::PeerErrored(errored, _)) => assert_eq!(errored, peer),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+
+    // Header with an invalid message length.
+    let mut sock = connect_raw(addr);
+    let peer = sock.local_addr().unwrap();
+    sock.write_all(&[0; 10]).unwrap();
+    match node.wait_for_event() {
+        Ok(NetworkEvent::PeerErrored(errored, _)) => assert_eq!(errored, peer),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_oversized_length_prefix() {
+    let addr = "127.0.0.1:19802".parse().unwrap();
+    let mut node = TestEvents::with_addr(addr).spawn();
+
+    // Header announcing a 4 GB message.
+    let mut sock = connect_raw(addr);
+    sock.write_all(&[0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]).unwrap();
+    match node.wait_for_event() {
+        Ok(NetworkEvent::PeerErrored(..)) => {}
+        other => panic!("Unexpected event: {:?}", other),
+    }
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(sock.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_network_outgoing_connect_duration() {
+    let first = "127.0.0.1:19803".parse().unwrap();
+    let second = "127.0.0.1:19804".parse().unwrap();
+
+    // The second node is started later, so the first attempts fail.
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_connect_retry_timeout = 100;
+    events.network_config.tcp_connect_max_retries = 50;
+    let mut e1 = events.spawn();
+    e1.connect_with(second);
+    thread::sleep(Duration::from_millis(500));
+
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+
+    let (addr, connect_duration) = e1.wait_for_outgoing_connect();
+    assert_eq!(addr, second);
+    assert!(connect_duration >= Duration::from_millis(500));
+}
+
+#[test]
+fn test_network_socket_buffers() {
+    let first = "127.0.0.1:19805".parse().unwrap();
+    let second = "127.0.0.1:19806".parse().unwrap();
+
+    let buffered_events = |addr| {
+        let mut events = TestEvents::with_addr(addr);
+        events.network_config.socket_send_buffer = Some(256 * 1024);
+        events.network_config.socket_recv_buffer = Some(256 * 1024);
+        events
+    };
+
+    let msg = raw_message(71, 100_000);
+
+    let mut e1 = buffered_events(first).spawn();
+    let mut e2 = buffered_events(second).spawn();
+
+    e1.connect_with(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+}
+
+#[test]
+fn test_priority_receiver() {
+    let (high_tx, high_rx) = queue::channel(4);
+    let (normal_tx, normal_rx) = queue::channel(4);
+
+    let normal: Vec<_> = (0..3).map(|i| raw_message(i, 100)).collect();
+    let high: Vec<_> = (3..5).map(|i| raw_message(i, 100)).collect();
+    for msg in &normal {
+        normal_tx.push(msg.clone(), OverflowPolicy::DropNew).unwrap();
+    }
+    for msg in &high {
+        high_tx.push(msg.clone(), OverflowPolicy::DropNew).unwrap();
+    }
+    drop((normal_tx, high_tx));
+
+    let received = PriorityReceiver::new(high_rx, normal_rx)
+        .map(|(msg, _)| msg)
+        .collect()
+        .wait()
+        .unwrap();
+    let expected: Vec<_> = high.into_iter().chain(normal).collect();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn test_connections_pool_custom_item() {
+    let pool = ConnectionsPool::<Vec<u8>>::new(
+        None,
+        Rc::new(MemoryTransport::new()),
+        Rc::new(MockTimer::new()),
+        Rc::new(NoopMetrics),
+        Rc::new(MessagesCodecFactory),
+        Rc::default(),
+        Some(8),
+        4,
+    );
+    let peer = "127.0.0.1:18007".parse().unwrap();
+    let send = |msg: Vec<u8>, priority| {
+        pool.try_send(peer, msg, None, priority, OverflowPolicy::DropNew)
+    };
+
+    assert_eq!(send(vec![0; 4], Priority::Normal), Err(TrySendError::NoConnection));
+    let (rx, _close_rx) = pool.register(peer, 2);
+    assert!(pool.contains(peer));
+    assert_eq!(pool.len(), 1);
+
+    assert_eq!(send(vec![1; 4], Priority::Normal), Ok(None));
+    assert_eq!(send(vec![2; 4], Priority::High), Ok(None));
+    assert_eq!(send(vec![3; 1], Priority::Normal), Err(TrySendError::BudgetExceeded));
+
+    let (received, rx) = rx.into_future().map_err(|_| ()).wait().unwrap();
+    assert_eq!(received, Some((vec![2; 4], None)));
+    assert_eq!(send(vec![3; 4], Priority::Normal), Ok(None));
+
+    let received: Vec<_> = rx.take(2).map(|(msg, _)| msg).collect().wait().unwrap();
+    assert_eq!(received, vec![vec![1; 4], vec![3; 4]]);
+}
+
+#[test]
+fn test_queue_overflow_policy() {
+    let (tx, rx) = queue::channel(2);
+    let msgs: Vec<_> = (0..4).map(|i| raw_message(i, 100)).collect();
+
+    tx.push(msgs[0].clone(), OverflowPolicy::DropNew).unwrap();
+    tx.push(msgs[1].clone(), OverflowPolicy::DropNew).unwrap();
+    assert_eq!(
+        tx.push(msgs[2].clone(), OverflowPolicy::DropNew),
+        Err(PushError::Full)
+    );
+    assert_eq!(
+        tx.push(msgs[3].clone(), OverflowPolicy::DropOld),
+        Ok(Some(msgs[0].clone()))
+    );
+    drop(tx);
+
+    let received = rx.map(|(msg, _)| msg).collect().wait().unwrap();
+    assert_eq!(received, vec![msgs[1].clone(), msgs[3].clone()]);
+}
+
+#[test]
+fn test_queue_buffered_bytes() {
+    let buffered_bytes = Rc::new(Cell::new(0));
+    let (tx, mut rx) = queue::channel_with_counter(2, Rc::clone(&buffered_bytes));
+    let msgs: Vec<_> = (0..3).map(|i| raw_message(i, 100)).collect();
+    let len = msgs[0].as_ref().len();
+
+    tx.push(msgs[0].clone(), OverflowPolicy::DropNew).unwrap();
+    tx.push(msgs[1].clone(), OverflowPolicy::DropNew).unwrap();
+    assert_eq!(buffered_bytes.get(), 2 * len);
+    // The evicted message is not counted.
+    tx.push(msgs[2].clone(), OverflowPolicy::DropOld).unwrap();
+    assert_eq!(buffered_bytes.get(), 2 * len);
+
+    // The message taken by the writer is counted until the next one is requested.
+    assert_eq!(rx.poll(), Ok(Async::Ready(Some((msgs[1].clone(), None)))));
+    assert_eq!(buffered_bytes.get(), 2 * len);
+    assert_eq!(rx.poll(), Ok(Async::Ready(Some((msgs[2].clone(), None)))));
+    assert_eq!(buffered_bytes.get(), len);
+    drop(rx);
+    assert_eq!(buffered_bytes.get(), 0);
+}
+
+#[test]
+fn test_network_send_message_priority() {
+    let first = "127.0.0.1:19807".parse().unwrap();
+    let second = "127.0.0.1:19808".parse().unwrap();
+
+    let m1 = raw_message(81, 1000);
+    let m2 = raw_message(82, 1000);
+
+    let e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    // The connect message precedes the high priority message in the new connection.
+    e1.send_to_priority(second, m1.clone(), Priority::High);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), m1);
+
+    e1.send_to_priority(second, m2.clone(), Priority::Normal);
+    assert_eq!(e2.wait_for_message(), m2);
+}
+
+#[test]
+fn test_network_send_queue_overflow() {
+    let first = "127.0.0.1:19809".parse().unwrap();
+    let stalled: SocketAddr = "127.0.0.1:19810".parse().unwrap();
+
+    // The peer accepts the connection, but never reads from it.
+    let _listener = TcpListener::bind(&stalled).unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.outgoing_message_queue_len = 2;
+    let mut e1 = events.spawn();
+
+    for i in 0..24 {
+        e1.send_to(stalled, raw_message(i, 1_000_000));
+    }
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::SendQueueOverflow(addr)) => assert_eq!(addr, stalled),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_shutdown_handle() {
+    let first = "127.0.0.1:19811".parse().unwrap();
+    let second = "127.0.0.1:19812".parse().unwrap();
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    let shutdown_handle = network_part.shutdown_handle();
+    let thread = spawn_network_part(network_part);
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+
+    shutdown_handle.shutdown();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_network_shutdown_handle_dropped() {
+    let addr = "127.0.0.1:19813".parse().unwrap();
+
+    let (_e1, mut network_part) = TestEvents::with_addr(addr).into_reactor();
+    let shutdown_handle = network_part.shutdown_handle();
+    let thread = spawn_network_part(network_part);
+
+    drop(shutdown_handle);
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_network_memory_transport() {
+    let addrs: Vec<SocketAddr> = (0..3)
+        .map(|i| format!("10.0.0.{}:2000", i + 1).parse().unwrap())
+        .collect();
+
+    let transport = MemoryTransport::new();
+    let mut nodes: Vec<_> = addrs
+        .iter()
+        .map(|addr| {
+            let (mut node, mut network_part) = TestEvents::with_addr(*addr).into_reactor();
+            network_part.transport = Some(Box::new(transport.clone()));
+            node.handle = Some(spawn_network_part(network_part));
+            node
+        })
+        .collect();
+
+    let msg = raw_message(91, 1000);
+    for i in 1..3 {
+        nodes[i].connect_with(addrs[0]);
+        assert_eq!(nodes[0].wait_for_connect(), connect_message(addrs[i]));
+        nodes[0].connect_with(addrs[i]);
+        assert_eq!(nodes[i].wait_for_connect(), connect_message(addrs[0]));
+
+        nodes[0].send_to(addrs[i], msg.clone());
+        assert_eq!(nodes[i].wait_for_message(), msg);
+        nodes[i].send_to(addrs[0], msg.clone());
+        assert_eq!(nodes[0].wait_for_message(), msg);
+    }
+
+    nodes[0].disconnect_with(addrs[1]);
+    assert_eq!(nodes[0].wait_for_disconnect(), addrs[1]);
+}
+
+#[test]
+fn test_network_ready_signal() {
+    let first = "127.0.0.1:19814".parse().unwrap();
+    let second = "127.0.0.1:19815".parse().unwrap();
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    let (ready_tx, ready_rx) = oneshot::channel();
+    network_part.ready_tx = Some(ready_tx);
+    e1.handle = Some(spawn_network_part(network_part));
+
+    ready_rx.wait().unwrap();
+    // The listener is bound, so the connection is accepted without retries.
+    TcpStream::connect(&first).unwrap();
+
+    let e2 = TestEvents::with_addr(second).spawn();
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+}
+
+#[test]
+fn test_network_ready_signal_bind_failed() {
+    let addr: SocketAddr = "127.0.0.1:19816".parse().unwrap();
+    let _listener = TcpListener::bind(&addr).unwrap();
+
+    let (_node, mut network_part) = TestEvents::with_addr(addr).into_reactor();
+    let (ready_tx, ready_rx) = oneshot::channel();
+    network_part.ready_tx = Some(ready_tx);
+    let thread = thread::spawn(move || network_part.run());
+
+    assert!(ready_rx.wait().is_err());
+    match thread.join().unwrap() {
+        Err(NetworkError::BindFailed { addr: failed, source }) => {
+            assert_eq!(failed, addr);
+            assert_eq!(source.kind(), io::ErrorKind::AddrInUse);
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+/// Minimal SOCKS5 proxy which forwards a single connection authenticated
+/// with the given credentials.
+fn spawn_socks5_proxy(addr: SocketAddr, auth: ProxyAuth) {
+    let listener = TcpListener::bind(&addr).unwrap();
+    thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut greeting = [0; 3];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [5, 1, 2]);
+        client.write_all(&[5, 2]).unwrap();
+
+        let read_string = |client: &mut TcpStream| {
+            let mut len = [0; 1];
+            client.read_exact(&mut len).unwrap();
+            let mut buf = vec![0; usize::from(len[0])];
+            client.read_exact(&mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let mut version = [0; 1];
+        client.read_exact(&mut version).unwrap();
+        let username = read_string(&mut client);
+        let password = read_string(&mut client);
+        assert_eq!((username, password), (auth.username, auth.password));
+        client.write_all(&[1, 0]).unwrap();
+
+        let mut request = [0; 10];
+        client.read_exact(&mut request).unwrap();
+        assert_eq!(request[..4], [5, 1, 0, 1]);
+        let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+        let port = u16::from(request[8]) << 8 | u16::from(request[9]);
+        let mut target = TcpStream::connect((ip, port)).unwrap();
+        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+        let mut client_reader = client.try_clone().unwrap();
+        let mut target_writer = target.try_clone().unwrap();
+        thread::spawn(move || io::copy(&mut client_reader, &mut target_writer));
+        io::copy(&mut target, &mut client).ok();
+    });
+}
+
+#[test]
+fn test_network_socks5_proxy() {
+    let first = "127.0.0.1:19817".parse().unwrap();
+    let second = "127.0.0.1:19818".parse().unwrap();
+    let proxy = "127.0.0.1:19819".parse().unwrap();
+
+    let auth = ProxyAuth {
+        username: "exonum".to_owned(),
+        password: "secret".to_owned(),
+    };
+    spawn_socks5_proxy(proxy, auth.clone());
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.proxy = Some(ProxyConfig {
+        address: proxy,
+        auth: Some(auth),
+    });
+    let e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let msg = raw_message(101, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), msg);
+}
+
+#[test]
+fn test_network_single_disconnect_event() {
+    let first = "127.0.0.1:19820".parse().unwrap();
+    let second = "127.0.0.1:19821".parse().unwrap();
+    let third = "127.0.0.1:19822".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+
+    // Repeated requests and closing of the socket by the peer are reported once.
+    e1.disconnect_with(second);
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    drop(e2);
+
+    // The next event should come from the new peer.
+    let e3 = TestEvents::with_addr(third).spawn();
+    e3.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(third));
+}
+
+#[test]
+fn test_network_no_disconnect_for_pending_connection() {
+    let first = "127.0.0.1:19823".parse().unwrap();
+    let second = "127.0.0.1:19824".parse().unwrap();
+    let third = "127.0.0.1:19825".parse().unwrap();
+
+    // The connection with the second node is never established.
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_connect_retry_timeout = 100;
+    events.network_config.tcp_connect_max_retries = 50;
+    let mut e1 = events.spawn();
+    e1.connect_with(second);
+    e1.disconnect_with(second);
+
+    let e3 = TestEvents::with_addr(third).spawn();
+    e3.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(third));
+}
+
+/// Records the connectivity changes reported by the `EventHandler` hooks.
+#[derive(Debug, Default)]
+struct ConnectivityHandler {
+    changes: Rc<RefCell<Vec<(SocketAddr, bool)>>>,
+    events_count: Rc<RefCell<usize>>,
+}
+
+impl EventHandler for ConnectivityHandler {
+    fn handle_event(&mut self, _event: Event) {
+        *self.events_count.borrow_mut() += 1;
+    }
+
+    fn on_peer_connected(&mut self, addr: SocketAddr, _connect: &Connect) {
+        self.changes.borrow_mut().push((addr, true));
+    }
+
+    fn on_peer_disconnected(&mut self, addr: SocketAddr) {
+        self.changes.borrow_mut().push((addr, false));
+    }
+}
+
+#[test]
+fn test_event_handler_connectivity_hooks() {
+    let addr = "127.0.0.1:19826".parse().unwrap();
+    let incoming = "127.0.0.1:19830".parse().unwrap();
+    // The ephemeral port of the incoming socket.
+    let socket_addr = "127.0.0.1:45678".parse().unwrap();
+
+    let handler = ConnectivityHandler::default();
+    let changes = Rc::clone(&handler.changes);
+    let events_count = Rc::clone(&handler.events_count);
+
+    let (_internal_tx, internal_rx) = mpsc::channel(4);
+    let (network_tx, network_rx) = mpsc::channel(8);
+    let (_api_tx, api_rx) = mpsc::channel(4);
+    let events = vec![
+        NetworkEvent::PeerConnected(addr, connect_message(addr)),
+        NetworkEvent::MessageReceived(addr, raw_message(111, 100)),
+        NetworkEvent::PeerDisconnected {
+            addr,
+            duration: Duration::from_secs(1),
+        },
+        NetworkEvent::PeerConnected(socket_addr, connect_message(incoming)),
+        NetworkEvent::IncomingPeerDisconnected {
+            addr: incoming,
+            duration: Duration::from_secs(1),
+        },
+    ];
+    for event in events {
+        network_tx.clone().send(event).wait().unwrap();
+    }
+    drop(network_tx);
+
+    let (requests_tx, _requests_rx) = mpsc::channel(4);
+    let handler_part = HandlerPart::new(handler, internal_rx, network_rx, api_rx, requests_tx);
+    handler_part.run().wait().unwrap();
+
+    // Both hooks are called with the addresses from the `Connect` messages.
+    assert_eq!(
+        *changes.borrow(),
+        vec![(addr, true), (addr, false), (incoming, true), (incoming, false)]
+    );
+    assert_eq!(*events_count.borrow(), 5);
+}
+
+#[test]
+fn test_network_pending_connections_limit() {
+    let addr = "127.0.0.1:19827".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(addr);
+    events.network_config.max_pending_connections = 1;
+    let mut node = events.spawn();
+
+    // The first connection never sends `Connect` and occupies the only pending slot.
+    let _pending = connect_raw(addr);
+    let mut rejected = TcpStream::connect(&addr).unwrap();
+    rejected
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(rejected.read(&mut buf).unwrap(), 0);
+    assert_eq!(node.query_connections().incoming_count, 1);
+}
+
+#[test]
+fn test_network_graceful_disconnect() {
+    let first = "127.0.0.1:19828".parse().unwrap();
+    let second = "127.0.0.1:19829".parse().unwrap();
+
+    let msg = raw_message(15, 100_000);
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+
+    // The buffered messages are written before the connection is closed.
+    for _ in 0..5 {
+        e1.send_to(second, msg.clone());
+    }
+    e1.disconnect_with_graceful(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    for _ in 0..5 {
+        assert_eq!(e2.wait_for_message(), msg);
+    }
+}
+
+#[test]
+fn test_network_connect_retry_events() {
+    let main = "127.0.0.1:19830".parse().unwrap();
+    let unreachable = "127.0.0.1:19831".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(main);
+    events.network_config.tcp_connect_retry_timeout = 10;
+    events.network_config.tcp_connect_max_retries = 2;
+    let mut node = events.spawn();
+
+    node.connect_with(unreachable);
+    for attempt in 2..4 {
+        match node.next_event() {
+            Ok(NetworkEvent::ConnectRetry { addr, attempt: n }) => {
+                assert_eq!(addr, unreachable);
+                assert_eq!(n, attempt);
+            }
+            other => panic!("Unexpected event received, {:?}", other),
+        }
+    }
+    match node.next_event() {
+        Ok(NetworkEvent::ConnectFailed(addr, _)) => assert_eq!(addr, unreachable),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_listen_backlog() {
+    let first = "127.0.0.1:19832".parse().unwrap();
+    let second = "127.0.0.1:19833".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(second);
+    events.network_config.listen_backlog = Some(16);
+
+    let e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = events.spawn();
+
+    e1.connect_with(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+}
+
+#[test]
+fn test_network_verify_connect_address() {
+    let first = "127.0.0.1:19834".parse().unwrap();
+    let second = "127.0.0.1:19835".parse().unwrap();
+    let spoofed = "10.0.0.1:19836".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.verify_connect_address = true;
+    let mut e1 = events.spawn();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    // The advertised address differs from the address of the socket.
+    let mut sock = connect_raw(first);
+    sock.write_all(connect_message(spoofed).raw().as_ref()).unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(sock.read(&mut buf).unwrap(), 0);
+
+    // The port of the socket is ignored.
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+}
+
+#[test]
+fn test_network_send_messages_batch() {
+    let first = "127.0.0.1:19837".parse().unwrap();
+    let second = "127.0.0.1:19838".parse().unwrap();
+
+    let batch: Vec<_> = (0..10).map(|i| raw_message(80 + i, 1000)).collect();
+
+    let e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    // The batch also establishes the connection.
+    e1.network_requests_tx
+        .clone()
+        .send(NetworkRequest::SendMessages(second, batch.clone()))
+        .wait()
+        .unwrap();
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    for msg in batch {
+        assert_eq!(e2.wait_for_message(), msg);
+    }
+}
+
+#[test]
+fn test_network_mock_timer() {
+    let addr = "127.0.0.1:19839".parse().unwrap();
+
+    let timer = MockTimer::new();
+    let mut events = TestEvents::with_addr(addr);
+    events.network_config.incoming_handshake_timeout = 3_600_000;
+    let (mut node, mut network_part) = events.into_reactor();
+    network_part.timer = Some(Box::new(timer.clone()));
+    node.handle = Some(spawn_network_part(network_part));
+
+    // Advance the virtual time by a minute every 10 ms.
+    let stop = Arc::new(AtomicBool::new(false));
+    let ticker = {
+        let stop = Arc::clone(&stop);
+        let timer = timer.clone();
+        thread::spawn(move || while !stop.load(Ordering::SeqCst) {
+            timer.advance(Duration::from_secs(60));
+            thread::sleep(Duration::from_millis(10));
+        })
+    };
+
+    // The hour-long handshake timeout expires in the virtual time.
+    let mut sock = connect_raw(addr);
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(sock.read(&mut buf).unwrap(), 0);
+    assert!(timer.now() >= Duration::from_secs(3600));
+
+    stop.store(true, Ordering::SeqCst);
+    ticker.join().unwrap();
+}
+
+#[test]
+fn test_network_query_traffic() {
+    let first = "127.0.0.1:19840".parse().unwrap();
+    let second = "127.0.0.1:19841".parse().unwrap();
+
+    let msgs = vec![raw_message(33, 1000), raw_message(34, 2000)];
+    let total_len = msgs.iter().map(|msg| msg.as_ref().len() as u64).sum::<u64>() +
+        connect_message(first).raw().as_ref().len() as u64;
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    for msg in msgs {
+        e1.send_to(second, msg.clone());
+        assert_eq!(e2.wait_for_message(), msg);
+    }
+    assert_eq!(e1.query_traffic().get(&second), Some(&(total_len, 0)));
+    // The incoming connection is identified by the address from `Connect`.
+    assert_eq!(e2.query_traffic().get(&first), Some(&(0, total_len)));
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    assert!(e1.query_traffic().is_empty());
+}
+
+#[test]
+fn test_network_reconfigure() {
+    let first = "127.0.0.1:19842".parse().unwrap();
+    let second = "127.0.0.1:19843".parse().unwrap();
+
+    let events = TestEvents::with_addr(first);
+    let mut network_config = events.network_config.clone();
+    let mut e1 = events.spawn();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    // Incoming connections are rejected once the limit is lowered.
+    network_config.max_incoming_connections = 0;
+    e1.reconfigure(network_config.clone());
+    let mut sock = connect_raw(first);
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(sock.read(&mut buf).unwrap(), 0);
+
+    network_config.max_incoming_connections = 8;
+    e1.reconfigure(network_config);
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+}
+
+#[test]
+fn test_network_protocol_version_mismatch() {
+    let first = "127.0.0.1:19844".parse().unwrap();
+    let second = "127.0.0.1:19845".parse().unwrap();
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    network_part.protocol_version = ::messages::PROTOCOL_MAJOR_VERSION + 1;
+    e1.handle = Some(spawn_network_part(network_part));
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    e2.connect_with(first);
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerErrored(_, reason)) => {
+            assert!(reason.contains("Protocol version mismatch"))
+        }
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_stop_sending() {
+    let first = "127.0.0.1:19846".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:19847".parse().unwrap();
+
+    let listener = TcpListener::bind(&second).unwrap();
+    let mut e1 = TestEvents::with_addr(first).spawn();
+
+    e1.connect_with(second);
+    e1.stop_sending(second);
+    e1.send_to(second, raw_message(35, 1000));
+
+    // Only the queued message is written before the write half is closed.
+    let (mut sock, _) = listener.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut received = Vec::new();
+    sock.read_to_end(&mut received).unwrap();
+    assert_eq!(received, connect_message(first).raw().as_ref().to_vec());
+    assert_eq!(e1.query_connections().outgoing_peers, vec![second]);
+
+    // The connection is closed by the peer.
+    drop(sock);
+    assert_eq!(e1.wait_for_disconnect(), second);
+}
+
+#[test]
+fn test_network_connect_then_send_single_connection() {
+    let first = "127.0.0.1:18008".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:18009".parse().unwrap();
+
+    let listener = TcpListener::bind(&second).unwrap();
+    let e1 = TestEvents::with_addr(first).spawn();
+
+    let msg = raw_message(36, 1000);
+    e1.connect_only(second);
+    e1.send_to(second, msg.clone());
+    e1.connect_only(second);
+
+    // The message is written into the connection opened by `Connect`.
+    let (mut sock, _) = listener.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut expected = connect_message(first).raw().as_ref().to_vec();
+    expected.extend_from_slice(msg.as_ref());
+    let mut received = vec![0; expected.len()];
+    sock.read_exact(&mut received).unwrap();
+    assert_eq!(received, expected);
+
+    listener.set_nonblocking(true).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(listener.accept().is_err());
+    assert_eq!(e1.query_connections().outgoing_peers, vec![second]);
+}
+
+#[test]
+fn test_network_sink_peer() {
+    let first = "127.0.0.1:18010".parse().unwrap();
+    let sink: SocketAddr = "127.0.0.1:18011".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let counter = e1.register_sink(sink);
+    assert!(e1.is_connected(sink));
+
+    for i in 0..3 {
+        e1.send_to(sink, raw_message(37 + i, 1000));
+    }
+    for _ in 0..500 {
+        if counter.count() >= 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(counter.count(), 3);
+
+    // The sink connection is closed as the usual one.
+    e1.disconnect_with(sink);
+    assert_eq!(e1.wait_for_disconnect(), sink);
+    assert!(!e1.is_connected(sink));
+}
+
+#[test]
+fn test_network_connect_deadline() {
+    let first = "127.0.0.1:18012".parse().unwrap();
+    let unreachable = "127.0.0.1:18013".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_connect_retry_timeout = 10_000;
+    events.network_config.tcp_connect_retry_jitter = RetryJitter::None;
+    events.network_config.tcp_connect_max_retries = 100;
+    events.network_config.tcp_connect_deadline = Some(300);
+    let mut e1 = events.spawn();
+
+    let started = time::Instant::now();
+    e1.connect_with(unreachable);
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::ConnectFailed(addr, _)) => assert_eq!(addr, unreachable),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    // The connection fails long before the retries are exhausted.
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(e1.query_connections().outgoing_peers.is_empty());
+}
+
+#[test]
+fn test_network_disconnect_duration() {
+    let first = "127.0.0.1:18014".parse().unwrap();
+    let second = "127.0.0.1:18015".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    thread::sleep(Duration::from_millis(200));
+
+    e1.disconnect_with(second);
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerDisconnected { addr, duration }) => {
+            assert_eq!(addr, second);
+            assert!(duration >= Duration::from_millis(200));
+        }
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    match e2.next_event() {
+        Ok(NetworkEvent::IncomingPeerDisconnected { addr, duration }) => {
+            assert_eq!(addr, first);
+            assert!(duration >= Duration::from_millis(200));
+        }
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+/// Records the metrics in the order they are reported.
+#[derive(Debug, Clone, Default)]
+struct TestMetrics(Arc<Mutex<Vec<(&'static str, SocketAddr, usize)>>>);
+
+impl TestMetrics {
+    fn records(&self) -> Vec<(&'static str, SocketAddr, usize)> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn record(&self, name: &'static str, peer: SocketAddr, bytes: usize) {
+        self.0.lock().unwrap().push((name, peer, bytes));
+    }
+}
+
+impl NetworkMetrics for TestMetrics {
+    fn record_connection_opened(&self, peer: SocketAddr) {
+        self.record("opened", peer, 0);
+    }
+
+    fn record_connection_closed(&self, peer: SocketAddr) {
+        self.record("closed", peer, 0);
+    }
+
+    fn record_message_sent(&self, peer: SocketAddr, bytes: usize) {
+        self.record("sent", peer, bytes);
+    }
+
+    fn record_connect_failure(&self, peer: SocketAddr) {
+        self.record("failure", peer, 0);
+    }
+}
+
+#[test]
+fn test_network_metrics() {
+    let first = "127.0.0.1:19848".parse().unwrap();
+    let second = "127.0.0.1:19849".parse().unwrap();
+    let unreachable = "127.0.0.1:19850".parse().unwrap();
+
+    let metrics = TestMetrics::default();
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_connect_retry_timeout = 10;
+    events.network_config.tcp_connect_max_retries = 0;
+    let (mut e1, mut network_part) = events.into_reactor();
+    network_part.metrics = Some(Box::new(metrics.clone()));
+    e1.handle = Some(spawn_network_part(network_part));
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let msg = raw_message(36, 1000);
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+
+    // The connection is closed by the peer.
+    e2.shutdown();
+    assert_eq!(e1.wait_for_disconnect(), second);
+
+    e1.connect_with(unreachable);
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::ConnectFailed(addr, _)) => assert_eq!(addr, unreachable),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+
+    let connect_len = connect_message(first).raw().as_ref().len();
+    assert_eq!(
+        metrics.records(),
+        vec![
+            ("opened", second, 0),
+            ("sent", second, connect_len),
+            ("sent", second, msg.as_ref().len()),
+            ("closed", second, 0),
+            ("failure", unreachable, 0),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_network_unix_transport() {
+    let first = "127.0.0.1:19851".parse().unwrap();
+    let second = "127.0.0.1:19852".parse().unwrap();
+
+    let path = ::std::env::temp_dir().join("exonum-test-19852.sock");
+    ::std::fs::remove_file(&path).ok();
+    let transport = UnixTransport::new(NetworkConfiguration::default())
+        .with_endpoint(second, Endpoint::Unix(path.clone()));
+    let mut nodes: Vec<_> = [first, second]
+        .iter()
+        .map(|addr| {
+            let (mut node, mut network_part) = TestEvents::with_addr(*addr).into_reactor();
+            network_part.transport = Some(Box::new(transport.clone()));
+            node.handle = Some(spawn_network_part(network_part));
+            node
+        })
+        .collect();
+
+    // The second node is reached through the Unix socket, the first one through TCP.
+    let msg = raw_message(37, 1000);
+    nodes[0].connect_with(second);
+    assert_eq!(nodes[1].wait_for_connect(), connect_message(first));
+    nodes[1].connect_with(first);
+    assert_eq!(nodes[0].wait_for_connect(), connect_message(second));
+
+    nodes[0].send_to(second, msg.clone());
+    assert_eq!(nodes[1].wait_for_message(), msg);
+    nodes[1].send_to(first, msg.clone());
+    assert_eq!(nodes[0].wait_for_message(), msg);
+
+    drop(nodes);
+    ::std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_network_connect_without_message() {
+    let first = "127.0.0.1:19853".parse().unwrap();
+    let second = "127.0.0.1:19854".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_only(second);
+    e1.connect_only(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e1.wait_for_outgoing_connect().0, second);
+
+    // The established connection is reused.
+    let msg = raw_message(38, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+    assert_eq!(e1.query_connections().outgoing_count, 1);
+}
+
+#[test]
+fn test_network_max_total_outgoing_bytes() {
+    let first = "127.0.0.1:19855".parse().unwrap();
+    let stalled: SocketAddr = "127.0.0.1:19856".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.outgoing_message_queue_len = 100;
+    events.network_config.max_total_outgoing_bytes = Some(2_000_000);
+    let e1 = events.spawn();
+
+    // The peer never reads, so the messages are buffered until the limit is reached.
+    let _listener = TcpListener::bind(&stalled).unwrap();
+    let rejected = (0..100)
+        .map(|i| e1.send_to_ack(stalled, raw_message(i, 1_000_000)))
+        .find(|result| *result != SendResult::Queued);
+    assert_eq!(
+        rejected,
+        Some(SendResult::Rejected {
+            reason: "the limit of the buffered outgoing bytes is reached".to_owned(),
+        })
+    );
+}
+
+/// The first event is handled only after the second one is received.
+#[derive(Debug)]
+struct ConcurrentHandler {
+    waiting: Option<oneshot::Receiver<()>>,
+    release: Option<oneshot::Sender<()>>,
+    received: usize,
+    completed: Rc<RefCell<Vec<usize>>>,
+}
+
+impl EventHandler for ConcurrentHandler {
+    fn handle_event(&mut self, _event: Event) {}
+
+    fn handle_event_async(
+        &mut self,
+        _event: Event,
+    ) -> Box<Future<Item = Vec<NetworkRequest>, Error = ()>> {
+        self.received += 1;
+        let index = self.received;
+        let completed = Rc::clone(&self.completed);
+        let fut: Box<Future<Item = (), Error = ()>> = match self.waiting.take() {
+            Some(waiting) => Box::new(waiting.map_err(drop)),
+            None => {
+                if let Some(release) = self.release.take() {
+                    release.send(()).unwrap();
+                }
+                Box::new(future::ok(()))
+            }
+        };
+        Box::new(fut.map(move |_| {
+            completed.borrow_mut().push(index);
+            Vec::new()
+        }))
+    }
+}
+
+#[test]
+fn test_event_handler_concurrency() {
+    let addr = "127.0.0.1:19857".parse().unwrap();
+
+    let (release, waiting) = oneshot::channel();
+    let handler = ConcurrentHandler {
+        waiting: Some(waiting),
+        release: Some(release),
+        received: 0,
+        completed: Rc::default(),
+    };
+    let completed = Rc::clone(&handler.completed);
+
+    let (_internal_tx, internal_rx) = mpsc::channel(4);
+    let (network_tx, network_rx) = mpsc::channel(4);
+    let (_api_tx, api_rx) = mpsc::channel(4);
+    for id in 0..2 {
+        let event = NetworkEvent::MessageReceived(addr, raw_message(id, 100));
+        network_tx.clone().send(event).wait().unwrap();
+    }
+    drop(network_tx);
+
+    let (requests_tx, _requests_rx) = mpsc::channel(4);
+    let handler_part =
+        HandlerPart::with_concurrency(handler, internal_rx, network_rx, api_rx, requests_tx, 2);
+    handler_part.run().wait().unwrap();
+    assert_eq!(*completed.borrow(), vec![2, 1]);
+}
+
+#[test]
+fn test_network_is_connected() {
+    let first = "127.0.0.1:19858".parse().unwrap();
+    let second = "127.0.0.1:19859".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    assert!(!e1.is_connected(second));
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    assert_eq!(e1.wait_for_outgoing_connect().0, second);
+    assert!(e1.is_connected(second));
+    // Only the outgoing connections are checked.
+    assert!(!e2.is_connected(first));
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    assert!(!e1.is_connected(second));
+}
+
+#[test]
+fn test_network_tcp_keep_alive() {
+    let first = "127.0.0.1:19860".parse().unwrap();
+    let second = "127.0.0.1:19861".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_keep_alive = Some(TcpKeepAliveConfig {
+        idle: 1_000,
+        interval: Some(1_000),
+        retries: Some(3),
+    });
+    let e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let msg = raw_message(39, 1000);
+    e1.connect_with(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+}
+
+#[test]
+fn test_network_stops_when_events_receiver_dropped() {
+    let addr = "127.0.0.1:19862".parse().unwrap();
+
+    let (node, network_part) = TestEvents::with_addr(addr).into_reactor();
+    let handle = spawn_network_part(network_part);
+    // Drops the receiver of the network events.
+    drop(node);
+    handle.join().unwrap();
+}
+
+/// Transport which never establishes the outgoing connections.
+#[derive(Debug)]
+struct StalledTransport(MemoryTransport);
+
+impl Transport for StalledTransport {
+    fn connect(&self, _peer: SocketAddr, _handle: &Handle) -> PeerStreamFuture {
+        Box::new(future::empty())
+    }
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        handle: &Handle,
+    ) -> io::Result<(IncomingConnections, SocketAddr)> {
+        self.0.listen(addr, handle)
+    }
+}
+
+#[test]
+fn test_network_tcp_connect_timeout() {
+    let first = "127.0.0.1:19863".parse().unwrap();
+    let stalled = "127.0.0.1:19864".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_connect_timeout = 100;
+    events.network_config.tcp_connect_retry_timeout = 10;
+    events.network_config.tcp_connect_max_retries = 1;
+    let (mut node, mut network_part) = events.into_reactor();
+    network_part.transport = Some(Box::new(StalledTransport(MemoryTransport::new())));
+    node.handle = Some(spawn_network_part(network_part));
+
+    // Every attempt is stopped by the timeout.
+    node.connect_with(stalled);
+    match node.wait_for_event() {
+        Ok(NetworkEvent::ConnectFailed(addr, _)) => assert_eq!(addr, stalled),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_message_sent_event() {
+    let first = "127.0.0.1:19865".parse().unwrap();
+    let second = "127.0.0.1:19866".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let msgs: Vec<_> = (40..43).map(|id| raw_message(id, 1000)).collect();
+    e1.send_to_tracked(second, msgs[0].clone(), 1);
+    e1.send_to(second, msgs[1].clone());
+    e1.send_to_tracked(second, msgs[2].clone(), 2);
+    e2.wait_for_connect();
+    for msg in &msgs {
+        assert_eq!(e2.wait_for_message(), *msg);
+    }
+
+    // The untracked message is not reported.
+    for expected in 1..3 {
+        match e1.wait_for_event() {
+            Ok(NetworkEvent::MessageSent { addr, token }) => {
+                assert_eq!(addr, second);
+                assert_eq!(token, expected);
+            }
+            other => panic!("Unexpected event received, {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_network_configuration_builder() {
+    let config = NetworkConfiguration::builder()
+        .max_incoming_connections(16)
+        .tcp_nodelay(false)
+        .write_timeout(Some(1_000))
+        .outgoing_overflow_policy(OverflowPolicy::DropOld)
+        .build();
+
+    let expected = NetworkConfiguration {
+        max_incoming_connections: 16,
+        tcp_nodelay: false,
+        write_timeout: Some(1_000),
+        outgoing_overflow_policy: OverflowPolicy::DropOld,
+        ..NetworkConfiguration::default()
+    };
+    assert_eq!(format!("{:?}", config), format!("{:?}", expected));
+}
+
+#[test]
+fn test_network_outgoing_limit_events() {
+    let first = "127.0.0.1:19867".parse().unwrap();
+    let second = "127.0.0.1:19868".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first);
+    e1.network_config.max_outgoing_connections = 1;
+    let mut e1 = e1.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::OutgoingLimitReached) => {}
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    e2.wait_for_connect();
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::OutgoingLimitRelieved) => {}
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+/// Creates the default codecs and counts them.
+#[derive(Debug, Clone, Default)]
+struct CountingCodecFactory(Arc<AtomicUsize>);
+
+impl CodecFactory for CountingCodecFactory {
+    fn new_codec(&self, max_message_len: u32, compression: Option<Compression>) -> Box<PeerCodec> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        MessagesCodecFactory.new_codec(max_message_len, compression)
+    }
+}
+
+#[test]
+fn test_network_codec_factory() {
+    let first = "127.0.0.1:19869".parse().unwrap();
+    let second = "127.0.0.1:19870".parse().unwrap();
+
+    let codecs = CountingCodecFactory::default();
+    let spawn = |addr| {
+        let (mut handler_part, mut network_part) = TestEvents::with_addr(addr).into_reactor();
+        network_part.codec_factory = Some(Box::new(codecs.clone()));
+        handler_part.handle = Some(spawn_network_part(network_part));
+        handler_part
+    };
+    let e1 = spawn(first);
+    let mut e2 = spawn(second);
+
+    let msg = raw_message(44, 1000);
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+
+    // The outgoing connection of the first node and the incoming one of the second.
+    assert_eq!(codecs.0.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_network_compression() {
+    let first = "127.0.0.1:19871".parse().unwrap();
+    let second = "127.0.0.1:19872".parse().unwrap();
+    let third = "127.0.0.1:19873".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first);
+    e1.network_config.compression = Some(Compression::Lz4);
+    let e1 = e1.spawn();
+    let mut e2 = TestEvents::with_addr(second);
+    e2.network_config.compression = Some(Compression::Lz4);
+    let mut e2 = e2.spawn();
+    // The peer without compression receives the uncompressed messages.
+    let mut e3 = TestEvents::with_addr(third).spawn();
+
+    let msgs: Vec<_> = (45..50).map(|id| raw_message(id, 10_000)).collect();
+    e1.connect_with(second);
+    e1.connect_with(third);
+    e2.wait_for_connect();
+    e3.wait_for_connect();
+    for msg in &msgs {
+        e1.send_to(second, msg.clone());
+        e1.send_to(third, msg.clone());
+    }
+    for msg in &msgs {
+        assert_eq!(e2.wait_for_message(), *msg);
+        assert_eq!(e3.wait_for_message(), *msg);
+    }
+}
+
+#[test]
+fn test_network_query_traffic_compressed() {
+    let first = "127.0.0.1:18060".parse().unwrap();
+    let second = "127.0.0.1:18061".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first);
+    e1.network_config.compression = Some(Compression::Lz4);
+    let e1 = e1.spawn();
+    let mut e2 = TestEvents::with_addr(second);
+    e2.network_config.compression = Some(Compression::Lz4);
+    let mut e2 = e2.spawn();
+
+    let msgs: Vec<_> = (50..55).map(|id| raw_message(id, 10_000)).collect();
+    let messages_len = msgs.iter().map(|msg| msg.as_ref().len() as u64).sum::<u64>();
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    // Lets the first node read the compression ack before sending.
+    thread::sleep(Duration::from_millis(100));
+    for msg in &msgs {
+        e1.send_to(second, msg.clone());
+        assert_eq!(e2.wait_for_message(), *msg);
+    }
+
+    // The traffic counts the compressed frames, the same on both sides.
+    let (sent, received) = e1.query_traffic()[&second];
+    assert!(sent < messages_len, "sent {} bytes", sent);
+    assert_eq!(e2.query_traffic()[&first], (received, sent));
+}
+
+#[test]
+fn test_priority_requests() {
+    let (control_tx, control_rx) = mpsc::channel(4);
+    let (requests_tx, requests_rx) = mpsc::channel(4);
+    let addr: SocketAddr = "127.0.0.1:19874".parse().unwrap();
+
+    let requests_tx = requests_tx
+        .send(NetworkRequest::Connect(addr))
+        .wait()
+        .unwrap();
+    let control_tx = control_tx
+        .send(NetworkRequest::DisconnectWithPeer(addr))
+        .wait()
+        .unwrap();
+    drop((requests_tx, control_tx));
+
+    let received = PriorityRequests::new(control_rx, requests_rx)
+        .map(|request| match request {
+            NetworkRequest::DisconnectWithPeer(_) => "control",
+            NetworkRequest::Connect(_) => "regular",
+            _ => "other",
+        })
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(received, vec!["control", "regular"]);
+}
+
+#[test]
+fn test_write_slots_round_robin() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let slots: Rc<RefCell<SlotsLimiter>> = Rc::default();
+    let written = Rc::new(RefCell::new(Vec::new()));
+
+    // Every write takes the slot until the delay is elapsed.
+    let writer = |peer: &'static str, count: usize| {
+        let slots = Rc::clone(&slots);
+        let written = Rc::clone(&written);
+        let handle = handle.clone();
+        stream::iter_ok(0..count).for_each(move |_| {
+            let written = Rc::clone(&written);
+            let handle = handle.clone();
+            SlotPermit::acquire(&slots, Some(1)).and_then(move |permit| {
+                written.borrow_mut().push(peer);
+                Timeout::new(Duration::from_millis(10), &handle)
+                    .unwrap()
+                    .map(move |_| drop(permit))
+            })
+        })
+    };
+    core.run(writer("busy", 4).join(writer("quiet", 2))).unwrap();
+    assert_eq!(
+        *written.borrow(),
+        vec!["busy", "quiet", "busy", "quiet", "busy", "busy"]
+    );
+
+    // The slot passed to the dropped waiter is released.
+    let permit = SlotPermit::acquire(&slots, Some(1)).wait().unwrap();
+    let waiting = SlotPermit::acquire(&slots, Some(1));
+    drop(permit);
+    drop(waiting);
+    assert!(SlotPermit::acquire(&slots, Some(1)).wait().is_ok());
+}
+
+#[test]
+fn test_network_control_channel() {
+    let first = "127.0.0.1:19875".parse().unwrap();
+    let second = "127.0.0.1:19876".parse().unwrap();
+
+    let (e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    let control_tx = network_part.control_channel();
+    let thread = spawn_network_part(network_part);
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+
+    control_tx.send(NetworkRequest::Shutdown).wait().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_network_query_last_received() {
+    let first = "127.0.0.1:19877".parse().unwrap();
+    let second = "127.0.0.1:19878".parse().unwrap();
+
+    let e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+    // Only `Connect` is received.
+    assert!(e2.query_last_received().is_empty());
+
+    let msg = raw_message(51, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_message(), msg);
+    thread::sleep(Duration::from_millis(100));
+    let elapsed = e2.query_last_received()[&first];
+    assert!(elapsed >= Duration::from_millis(100));
+    // The incoming connections of the first node are queried.
+    assert!(e1.query_last_received().is_empty());
+}
+
+#[test]
+fn test_connection_error_categories() {
+    let decode_error = ConnectionError::from(invalid_data("malformed frame"));
+    assert_eq!(decode_error.category(), "decode_error");
+    assert!(!decode_error.is_retryable());
+    assert!(decode_error.to_string().starts_with("category=decode_error"));
+
+    let closed = ConnectionError::from(other_error("connection reset"));
+    assert_eq!(closed.category(), "closed");
+    assert!(closed.is_retryable());
+}
+
+/// Transport which records the addresses of the outgoing connections, which are never
+/// established.
+#[derive(Debug)]
+struct RecordingTransport {
+    memory: MemoryTransport,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl Transport for RecordingTransport {
+    fn connect(&self, peer: SocketAddr, _handle: &Handle) -> PeerStreamFuture {
+        self.peers.lock().unwrap().push(peer);
+        Box::new(future::empty())
+    }
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        handle: &Handle,
+    ) -> io::Result<(IncomingConnections, SocketAddr)> {
+        self.memory.listen(addr, handle)
+    }
+}
+
+#[test]
+fn test_network_ipv6_scope_id() {
+    let first = "127.0.0.1:19879".parse().unwrap();
+    // The same link-local address on different interfaces.
+    let ip: Ipv6Addr = "fe80::1".parse().unwrap();
+    let peers: Vec<_> = (1..3)
+        .map(|scope_id| SocketAddr::V6(SocketAddrV6::new(ip, 19880, 0, scope_id)))
+        .collect();
+
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let (mut node, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    network_part.transport = Some(Box::new(RecordingTransport {
+        memory: MemoryTransport::new(),
+        peers: Arc::clone(&recorded),
+    }));
+    node.handle = Some(spawn_network_part(network_part));
+
+    for peer in &peers {
+        node.connect_only(*peer);
+    }
+    let outgoing = node.query_connections().outgoing_peers;
+    assert_eq!(outgoing.len(), peers.len());
+    for peer in &peers {
+        assert!(outgoing.contains(peer));
+    }
+
+    // The scope ids are passed to the transport.
+    let mut attempts = 100;
+    while recorded.lock().unwrap().len() < peers.len() && attempts > 0 {
+        thread::sleep(Duration::from_millis(10));
+        attempts -= 1;
+    }
+    let recorded = recorded.lock().unwrap();
+    assert_eq!(recorded.len(), peers.len());
+    for peer in &peers {
+        assert!(recorded.contains(peer));
+    }
+}
+
+#[test]
+fn test_network_disconnect_all() {
+    let first = "127.0.0.1:19881".parse().unwrap();
+    let second = "127.0.0.1:19882".parse().unwrap();
+    let third = "127.0.0.1:19883".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    let mut e3 = TestEvents::with_addr(third).spawn();
+
+    e1.connect_with(second);
+    e1.connect_with(third);
+    e2.wait_for_connect();
+    e3.wait_for_connect();
+    let mut connected = vec![e1.wait_for_outgoing_connect().0, e1.wait_for_outgoing_connect().0];
+    connected.sort_by_key(|addr| addr.port());
+    assert_eq!(connected, vec![second, third]);
+
+    e1.disconnect_all();
+    let mut disconnected = vec![e1.wait_for_disconnect(), e1.wait_for_disconnect()];
+    disconnected.sort_by_key(|addr| addr.port());
+    assert_eq!(disconnected, vec![second, third]);
+    assert_eq!(e1.query_connections().outgoing_count, 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_network_reuse_port() {
+    let addr = "127.0.0.1:19884".parse().unwrap();
+
+    let spawn = || {
+        let mut events = TestEvents::with_addr(addr);
+        events.network_config.reuse_port = true;
+        let (mut node, mut network_part) = events.into_reactor();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        network_part.ready_tx = Some(ready_tx);
+        node.handle = Some(spawn_network_part(network_part));
+        ready_rx.wait().expect("Unable to bind the listener");
+        node
+    };
+    // Both listeners are bound to the same address.
+    let _e1 = spawn();
+    let _e2 = spawn();
+}
+
+#[test]
+fn test_network_peer_reconnected() {
+    let first = "127.0.0.1:19885".parse().unwrap();
+    let second = "127.0.0.1:19886".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    // The first connection is not a reconnect.
+    e1.connect_with(second);
+    match e2.next_event() {
+        Ok(NetworkEvent::PeerConnected(_, connect)) => assert_eq!(connect.addr(), first),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    e1.wait_for_outgoing_connect();
+
+    e1.disconnect_with(second);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    match e2.next_event() {
+        Ok(NetworkEvent::IncomingPeerDisconnected { addr, .. }) => assert_eq!(addr, first),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+
+    e1.connect_with(second);
+    match e2.next_event() {
+        Ok(NetworkEvent::PeerReconnected(addr)) => assert_eq!(addr, first),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    match e2.next_event() {
+        Ok(NetworkEvent::PeerConnected(_, connect)) => assert_eq!(connect.addr(), first),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_seen_peers() {
+    let (first, second, third) = (gen_keypair().0, gen_keypair().0, gen_keypair().0);
+
+    let mut seen = SeenPeers::new(2);
+    assert!(seen.insert(first));
+    assert!(seen.insert(second));
+    assert!(!seen.insert(first));
+    // The least recently connected peer is forgotten.
+    assert!(seen.insert(third));
+    assert!(seen.insert(second));
+    assert!(!seen.insert(third));
+}
+
+#[test]
+fn test_network_send_message_to_key() {
+    let first = "127.0.0.1:19887".parse().unwrap();
+    let second = "127.0.0.1:19888".parse().unwrap();
+
+    let msg = raw_message(11, 1000);
+    let first_key = *connect_message(first).pub_key();
+    let unknown_key = *connect_message("127.0.0.1:19889".parse().unwrap()).pub_key();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e2.send_to_key(first_key, msg.clone());
+    match e2.wait_for_event() {
+        Ok(NetworkEvent::UnknownPeerKey(key)) => assert_eq!(key, first_key),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+
+    e2.send_to_key(first_key, msg.clone());
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    assert_eq!(e1.wait_for_message(), msg);
+
+    e2.send_to_key(unknown_key, msg);
+    match e2.wait_for_event() {
+        Ok(NetworkEvent::UnknownPeerKey(key)) => assert_eq!(key, unknown_key),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_concurrent_outgoing_dials() {
+    let main = "127.0.0.1:19890".parse().unwrap();
+    let second = "127.0.0.1:19891".parse().unwrap();
+    let unreachable = "127.0.0.1:19892".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(main);
+    events.network_config.tcp_connect_retry_timeout = 10;
+    events.network_config.tcp_connect_max_retries = 2;
+    events.network_config.max_concurrent_outgoing_dials = Some(1);
+    let mut node = events.spawn();
+    let _e2 = TestEvents::with_addr(second).spawn();
+
+    // The dial to the second node waits until all attempts to the unreachable one fail.
+    node.connect_with(unreachable);
+    node.connect_with(second);
+    let mut finished = Vec::new();
+    loop {
+        match node.next_event() {
+            Ok(NetworkEvent::ConnectRetry { .. }) => {}
+            Ok(NetworkEvent::ConnectFailed(addr, _)) => finished.push(addr),
+            Ok(NetworkEvent::PeerConnectedOutgoing { addr, .. }) => {
+                finished.push(addr);
+                break;
+            }
+            other => panic!("Unexpected event received, {:?}", other),
+        }
+    }
+    assert_eq!(finished, vec![unreachable, second]);
+}
+
+#[test]
+fn test_network_query_connections_sorted() {
+    let main = "127.0.0.1:19893".parse().unwrap();
+    let peers: Vec<SocketAddr> = vec![
+        "127.0.0.1:19896".parse().unwrap(),
+        "127.0.0.1:19894".parse().unwrap(),
+        "127.0.0.1:19895".parse().unwrap(),
+    ];
+
+    let node = TestEvents::with_addr(main).spawn();
+    let mut nodes: Vec<_> = peers
+        .iter()
+        .map(|addr| TestEvents::with_addr(*addr).spawn())
+        .collect();
+
+    for (peer, node_peer) in peers.iter().zip(&mut nodes) {
+        node.connect_with(*peer);
+        node_peer.wait_for_connect();
+    }
+
+    let mut expected = peers.clone();
+    expected.sort();
+    assert_eq!(node.query_connections().outgoing_peers, expected);
+}
+
+#[test]
+fn test_network_handshaking_incoming_limit() {
+    let first = "127.0.0.1:19897".parse().unwrap();
+    let second = "127.0.0.1:19898".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.max_incoming_connections = 1;
+    events.network_config.max_handshaking_incoming = Some(2);
+    let mut e1 = events.spawn();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    // The connection which never sends `Connect` doesn't take the slot of the established one.
+    let _handshaking = connect_raw(first);
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+
+    // The limit of the established connections is reached.
+    let mut rejected = TcpStream::connect(&first).unwrap();
+    rejected
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(rejected.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_network_event_sink() {
+    let first = "127.0.0.1:19899".parse().unwrap();
+    let second = "127.0.0.1:18000".parse().unwrap();
+
+    let (_e1, sink) = TestEvents::with_addr(first).spawn_with_sink();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    e2.connect_with(first);
+    for id in 0..3 {
+        e2.send_to(first, raw_message(id, 100));
+    }
+    sink.wait_for_len(4);
+    drop(e2);
+
+    sink.expect_sequence(
+        &[
+            ("PeerConnected", 1),
+            ("MessageReceived", 3),
+            ("IncomingPeerDisconnected", 1),
+        ],
+    );
+    match sink.events()[0] {
+        NetworkEvent::PeerConnected(_, ref connect) => assert_eq!(connect.addr(), second),
+        ref other => panic!("Unexpected event recorded, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_pre_connect_messages() {
+    let main = "127.0.0.1:18001".parse().unwrap();
+    let other = "127.0.0.1:18002".parse().unwrap();
+
+    let probe = raw_message(100, 100);
+    let msg = raw_message(11, 100);
+
+    let mut events = TestEvents::with_addr(main);
+    events.network_config.pre_connect_messages = vec![(0, 100)];
+    let (_node, sink) = events.spawn_with_sink();
+
+    let mut sock = connect_raw(main);
+    sock.write_all(probe.as_ref()).unwrap();
+    sock.write_all(connect_message(other).raw().as_ref()).unwrap();
+    sock.write_all(msg.as_ref()).unwrap();
+
+    sink.expect_sequence(
+        &[
+            ("PreConnectMessage", 1),
+            ("PeerConnected", 1),
+            ("MessageReceived", 1),
+        ],
+    );
+    match sink.events()[0] {
+        NetworkEvent::PreConnectMessage(_, ref raw) => assert_eq!(*raw, probe),
+        ref other => panic!("Unexpected event recorded, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_incoming_backpressure() {
+    let main = "127.0.0.1:18003".parse().unwrap();
+    let other = "127.0.0.1:18004".parse().unwrap();
+
+    let messages: Vec<_> = (0..100).map(|id| raw_message(id, 100)).collect();
+    let message_len = messages[0].as_ref().len() as u64;
+    let connect = connect_message(other);
+
+    let mut events = TestEvents::with_addr(main);
+    events.events_config.network_events_capacity = 2;
+    let mut node = events.spawn();
+
+    let mut sock = connect_raw(main);
+    sock.write_all(connect.raw().as_ref()).unwrap();
+    for msg in &messages {
+        sock.write_all(msg.as_ref()).unwrap();
+    }
+
+    // The events are not handled, so the node stops reading from the socket.
+    thread::sleep(Duration::from_millis(500));
+    let (_, received) = node.query_traffic()[&other];
+    let received_messages = (received - connect.raw().as_ref().len() as u64) / message_len;
+    assert!(received_messages < 10, "received {} messages", received_messages);
+
+    // The reading is resumed once the events are handled.
+    assert_eq!(node.wait_for_connect(), connect);
+    for msg in messages {
+        assert_eq!(node.wait_for_message(), msg);
+    }
+}
+
+#[test]
+fn test_network_events_subscriber() {
+    let first = "127.0.0.1:18005".parse().unwrap();
+    let second = "127.0.0.1:18006".parse().unwrap();
+
+    let msg = raw_message(11, 1000);
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    let mut subscriber = network_part.subscribe().wait();
+    e1.handle = Some(spawn_network_part(network_part));
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    e2.connect_with(first);
+    e2.send_to(first, msg.clone());
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    assert_eq!(e1.wait_for_message(), msg);
+
+    // The subscriber receives the same events.
+    match subscriber.next() {
+        Some(Ok(NetworkEvent::PeerConnected(_, connect))) => {
+            assert_eq!(connect, connect_message(second))
+        }
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    match subscriber.next() {
+        Some(Ok(NetworkEvent::MessageReceived(_, raw))) => assert_eq!(raw, msg),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_incoming_accept_rate() {
+    let first = "127.0.0.1:18018".parse().unwrap();
+    let second = "127.0.0.1:18019".parse().unwrap();
+    let third = "127.0.0.1:18020".parse().unwrap();
+
+    let timer = MockTimer::new();
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.incoming_accept_rate = Some(1);
+    let (mut e1, mut network_part) = events.into_reactor();
+    network_part.timer = Some(Box::new(timer.clone()));
+    e1.handle = Some(spawn_network_part(network_part));
+    let e2 = TestEvents::with_addr(second).spawn();
+    let e3 = TestEvents::with_addr(third).spawn();
+
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+
+    // The second connection is accepted only once the virtual second is elapsed.
+    let advanced = Arc::new(AtomicBool::new(false));
+    let ticker = {
+        let advanced = Arc::clone(&advanced);
+        let timer = timer.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            advanced.store(true, Ordering::SeqCst);
+            timer.advance(Duration::from_secs(1));
+        })
+    };
+    e3.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(third));
+    assert!(advanced.load(Ordering::SeqCst));
+    ticker.join().unwrap();
+}
+
+#[test]
+fn test_accept_rate_ticks() {
+    assert_eq!(accept_rate_ticks(1), (Duration::from_secs(1), 1));
+    assert_eq!(accept_rate_ticks(4), (Duration::from_millis(250), 1));
+    assert_eq!(accept_rate_ticks(1_000), (Duration::from_millis(1), 1));
+    // The period isn't shorter than a millisecond for the higher rates.
+    assert_eq!(accept_rate_ticks(2_500), (Duration::from_millis(1), 3));
+    assert_eq!(accept_rate_ticks(2_000_000_000), (Duration::from_millis(1), 2_000_000));
+    assert_eq!(
+        accept_rate_ticks(u32::max_value()),
+        (Duration::from_millis(1), 4_294_968)
+    );
+}
+
+#[test]
+fn test_network_persistent_peer_reconnect() {
+    let first = "127.0.0.1:18021".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:18022".parse().unwrap();
+
+    let listener = TcpListener::bind(&second).unwrap();
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    e1.set_persistent_peers(vec![second]);
+
+    let connect = connect_message(first).raw().as_ref().to_vec();
+    let msg = raw_message(38, 1000);
+    e1.send_to(second, msg.clone());
+    let (mut sock, _) = listener.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut expected = connect.clone();
+    expected.extend_from_slice(msg.as_ref());
+    let mut received = vec![0; expected.len()];
+    sock.read_exact(&mut received).unwrap();
+    assert_eq!(received, expected);
+
+    // The header with an invalid message length fails the connection.
+    sock.write_all(&[0; 10]).unwrap();
+    assert_eq!(e1.wait_for_disconnect(), second);
+
+    // The message is queued into the same connection, which is reestablished
+    // starting with `Connect`.
+    let msg = raw_message(39, 1000);
+    e1.send_to(second, msg.clone());
+    let (mut sock, _) = listener.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut expected = connect;
+    expected.extend_from_slice(msg.as_ref());
+    let mut received = vec![0; expected.len()];
+    sock.read_exact(&mut received).unwrap();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn test_network_max_connection_lifetime() {
+    let first = "127.0.0.1:18023".parse().unwrap();
+    let second = "127.0.0.1:18024".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.max_connection_lifetime = Some(500);
+    let mut e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    // The outgoing connection is closed once its lifetime expires.
+    e1.connect_with(second);
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e1.wait_for_disconnect(), second);
+
+    // The next message opens the new connection.
+    let msg = raw_message(40, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), msg);
+
+    // The incoming connection is closed as well.
+    e2.connect_with(first);
+    let mut connected = false;
+    loop {
+        match e1.next_event() {
+            Ok(NetworkEvent::PeerConnected(_, connect)) => {
+                assert_eq!(connect, connect_message(second));
+                connected = true;
+            }
+            Ok(NetworkEvent::IncomingPeerDisconnected { addr, .. }) => {
+                assert_eq!(addr, second);
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => panic!("An error during wait for disconnect occurred, {:?}", e),
+        }
+    }
+    assert!(connected);
+}
+
+#[test]
+fn test_network_run_summary() {
+    let first = "127.0.0.1:18025".parse().unwrap();
+    let second = "127.0.0.1:18026".parse().unwrap();
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    let shutdown_handle = network_part.shutdown_handle();
+    let thread = thread::spawn(move || network_part.run());
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let msg = raw_message(41, 1000);
+    e2.connect_with(first);
+    e2.send_to(first, msg.clone());
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    assert_eq!(e1.wait_for_message(), msg);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), msg);
+
+    shutdown_handle.shutdown();
+    let summary = thread.join().unwrap().unwrap();
+    // Both nodes send `Connect` followed by the message.
+    let bytes = (connect_message(first).raw().len() + msg.len()) as u64;
+    assert_eq!(
+        summary,
+        NetworkRunSummary {
+            connections_accepted: 1,
+            connections_dialed: 1,
+            dial_failures: 0,
+            messages_sent: 2,
+            messages_received: 2,
+            bytes_sent: bytes,
+            bytes_received: bytes,
+        }
+    );
+}
+
+#[test]
+fn test_network_send_message_and_disconnect() {
+    let first = "127.0.0.1:18027".parse().unwrap();
+    let second = "127.0.0.1:18028".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    // The connection is closed only after the message is written.
+    let msg = raw_message(42, 100_000);
+    e1.send_and_disconnect(second, msg.clone());
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), msg);
+    assert_eq!(e1.wait_for_disconnect(), second);
+    assert!(!e1.is_connected(second));
+
+    // The next message opens the new connection.
+    let msg = raw_message(43, 1000);
+    e1.send_to(second, msg.clone());
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    assert_eq!(e2.wait_for_message(), msg);
+}
+
+#[test]
+fn test_network_incoming_message_rate() {
+    let first = "127.0.0.1:18029".parse().unwrap();
+    let second = "127.0.0.1:18030".parse().unwrap();
+    let third = "127.0.0.1:18031".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.incoming_message_rate = Some(10);
+    let mut e1 = events.spawn();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    // The messages beyond the burst are received at the limited rate.
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    let start = Instant::now();
+    for i in 0..15 {
+        e2.send_to(first, raw_message(44 + i, 100));
+    }
+    for i in 0..15 {
+        assert_eq!(e1.wait_for_message(), raw_message(44 + i, 100));
+    }
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    // The connection which exceeds the rate is closed.
+    let mut events = TestEvents::with_addr(third);
+    events.network_config.incoming_message_rate = Some(2);
+    events.network_config.incoming_rate_policy = RateLimitPolicy::Disconnect;
+    let mut e3 = events.spawn();
+    e2.connect_with(third);
+    assert_eq!(e3.wait_for_connect(), connect_message(second));
+    for i in 0..5 {
+        e2.send_to(third, raw_message(59 + i, 100));
+    }
+    assert_eq!(e3.wait_for_message(), raw_message(59, 100));
+    assert_eq!(e3.wait_for_message(), raw_message(60, 100));
+    match e3.wait_for_event() {
+        Ok(NetworkEvent::PeerErrored(_, reason)) => {
+            assert!(reason.contains("rate limit exceeded"))
+        }
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+/// Requests the disconnection from the peer which has sent a message.
+#[derive(Debug)]
+struct DisconnectingHandler;
+
+impl EventHandler for DisconnectingHandler {
+    fn handle_event(&mut self, _event: Event) {}
+
+    fn handle_event_with_requests(&mut self, event: Event) -> Vec<NetworkRequest> {
+        match event {
+            Event::Network(NetworkEvent::MessageReceived(addr, _)) => {
+                vec![NetworkRequest::DisconnectWithPeer(addr)]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn test_event_handler_network_requests() {
+    let addr = "127.0.0.1:18032".parse().unwrap();
+
+    let (_internal_tx, internal_rx) = mpsc::channel(4);
+    let (network_tx, network_rx) = mpsc::channel(4);
+    let (_api_tx, api_rx) = mpsc::channel(4);
+    let (requests_tx, requests_rx) = mpsc::channel(4);
+    let events = vec![
+        NetworkEvent::PeerConnected(addr, connect_message(addr)),
+        NetworkEvent::MessageReceived(addr, raw_message(112, 100)),
+    ];
+    for event in events {
+        network_tx.clone().send(event).wait().unwrap();
+    }
+    drop(network_tx);
+
+    let handler_part =
+        HandlerPart::new(DisconnectingHandler, internal_rx, network_rx, api_rx, requests_tx);
+    handler_part.run().wait().unwrap();
+
+    let requests = requests_rx.collect().wait().unwrap();
+    assert_eq!(requests.len(), 1);
+    match requests[0] {
+        NetworkRequest::DisconnectWithPeer(peer) => assert_eq!(peer, addr),
+        ref other => panic!("Unexpected request: {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_running_flag() {
+    let first = "127.0.0.1:18033".parse().unwrap();
+    let second = "127.0.0.1:18034".parse().unwrap();
+
+    let (mut e1, mut network_part) = TestEvents::with_addr(first).into_reactor();
+    let running = network_part.running_flag();
+    let shutdown_handle = network_part.shutdown_handle();
+    assert!(!running.is_running());
+    let thread = thread::spawn(move || network_part.run());
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    assert!(running.is_running());
+
+    shutdown_handle.shutdown();
+    thread.join().unwrap().unwrap();
+    assert!(!running.is_running());
+}
+
+#[test]
+fn test_network_incoming_batch_size() {
+    let first = "127.0.0.1:18035".parse().unwrap();
+    let second = "127.0.0.1:18036".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.incoming_batch_size = Some(4);
+    let mut e1 = events.spawn();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    let sent = (0..10).map(|i| raw_message(64 + i, 100)).collect::<Vec<_>>();
+    for msg in &sent {
+        e2.send_to(first, msg.clone());
+    }
+    // The messages are received in order, at most `incoming_batch_size` per event.
+    let mut received = Vec::new();
+    while received.len() < sent.len() {
+        match e1.wait_for_event() {
+            Ok(NetworkEvent::MessagesReceived(_, batch)) => {
+                assert!(!batch.is_empty() && batch.len() <= 4);
+                received.extend(batch);
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn test_network_send_message_to_host() {
+    let first = "127.0.0.1:18037".parse().unwrap();
+    let second = "127.0.0.1:18038".parse().unwrap();
+
+    let mut e1 = TestEvents::with_addr(first).spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    let msg = raw_message(74, 1000);
+    e2.send_to_host("127.0.0.1:18037", msg.clone());
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    assert_eq!(e1.wait_for_message(), msg);
+
+    // The message is sent over the existing connection with the host.
+    let msg = raw_message(75, 1000);
+    e2.send_to_host("127.0.0.1:18037", msg.clone());
+    assert_eq!(e1.wait_for_message(), msg);
+    assert_eq!(e2.query_connections().outgoing_peers, vec![first]);
+
+    e2.send_to_host("unknown", msg);
+    match e2.wait_for_event() {
+        Ok(NetworkEvent::UnresolvedHost(host)) => assert_eq!(host, "unknown"),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_report_first_message() {
+    let first = "127.0.0.1:18039".parse().unwrap();
+    let second = "127.0.0.1:18040".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.report_first_message = true;
+    let mut e1 = events.spawn();
+    let e2 = TestEvents::with_addr(second).spawn();
+
+    for i in 0..3 {
+        e2.send_to(first, raw_message(76 + i, 100));
+    }
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::FirstMessage(_, raw)) => assert_eq!(raw, raw_message(76, 100)),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    assert_eq!(e1.wait_for_message(), raw_message(77, 100));
+    assert_eq!(e1.wait_for_message(), raw_message(78, 100));
+}
+
+/// Transport which fails to open the outgoing connections with the given OS error.
+#[cfg(unix)]
+#[derive(Debug)]
+struct FailingTransport {
+    inner: MemoryTransport,
+    error: i32,
+    attempts: Arc<AtomicUsize>,
+}
+
+#[cfg(unix)]
+impl Transport for FailingTransport {
+    fn connect(&self, _peer: SocketAddr, _handle: &Handle) -> PeerStreamFuture {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        Box::new(future::err(io::Error::from_raw_os_error(self.error)))
+    }
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        handle: &Handle,
+    ) -> io::Result<(IncomingConnections, SocketAddr)> {
+        self.inner.listen(addr, handle)
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_network_connect_resource_exhausted() {
+    let first = "127.0.0.1:18041".parse().unwrap();
+    let second = "127.0.0.1:18042".parse().unwrap();
+    let third = "127.0.0.1:18043".parse().unwrap();
+
+    let spawn_failing = |addr: SocketAddr, error: i32| {
+        let mut events = TestEvents::with_addr(addr);
+        events.network_config.tcp_connect_retry_timeout = 10;
+        events.network_config.tcp_connect_max_retries = 3;
+        let (mut node, mut network_part) = events.into_reactor();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        network_part.transport = Some(Box::new(FailingTransport {
+            inner: MemoryTransport::new(),
+            error,
+            attempts: Arc::clone(&attempts),
+        }));
+        node.handle = Some(spawn_network_part(network_part));
+        (node, attempts)
+    };
+
+    // The attempts are not retried once the file descriptors are exhausted.
+    let (mut node, attempts) = spawn_failing(first, ::libc::EMFILE);
+    node.connect_with(third);
+    match node.wait_for_event() {
+        Ok(NetworkEvent::ConnectFailed(addr, reason)) => {
+            assert_eq!(addr, third);
+            assert_eq!(reason, ConnectFailure::ResourceExhausted);
+        }
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    // Other errors are retried.
+    let (mut node, attempts) = spawn_failing(second, ::libc::ECONNREFUSED);
+    node.connect_with(third);
+    match node.wait_for_event() {
+        Ok(NetworkEvent::ConnectFailed(addr, reason)) => {
+            assert_eq!(addr, third);
+            assert_eq!(reason, ConnectFailure::Unreachable);
+        }
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_network_ip_filter() {
+    let first = "127.0.0.1:18044".parse().unwrap();
+    let second = "127.0.0.1:18045".parse().unwrap();
+    let third = "127.0.0.1:18046".parse().unwrap();
+
+    let net: IpNet = "10.1.128.0/17".parse().unwrap();
+    assert!(net.contains(&"10.1.200.3".parse().unwrap()));
+    assert!(!net.contains(&"10.1.100.3".parse().unwrap()));
+    assert!(net.contains(&"::ffff:10.1.128.1".parse().unwrap()));
+    assert_eq!("::1".parse::<IpNet>().unwrap().to_string(), "::1/128");
+    assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+
+    // The denylist takes precedence over the allowlist.
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.allowed_cidrs = vec!["127.0.0.0/8".parse().unwrap()];
+    events.network_config.denied_cidrs = vec!["127.0.0.1/32".parse().unwrap()];
+    let mut network_config = events.network_config.clone();
+    let mut e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    let e3 = TestEvents::with_addr(third).spawn();
+
+    e2.connect_with(first);
+    assert_eq!(e2.wait_for_disconnect(), first);
+
+    // The addresses out of the allowlist are denied.
+    network_config.allowed_cidrs = vec!["10.0.0.0/8".parse().unwrap()];
+    network_config.denied_cidrs = Vec::new();
+    e1.reconfigure(network_config.clone());
+    e2.connect_with(first);
+    assert_eq!(e2.wait_for_disconnect(), first);
+
+    network_config.allowed_cidrs = vec!["127.0.0.0/8".parse().unwrap()];
+    e1.reconfigure(network_config);
+    e3.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(third));
+}
+
+#[test]
+fn test_network_flap_dampening() {
+    let first = "127.0.0.1:18047".parse().unwrap();
+    let second = "127.0.0.1:18048".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.flap_dampening = Some(1000);
+    let mut e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e2.connect_with(first);
+    assert_eq!(e1.wait_for_connect(), connect_message(second));
+
+    // The quick reconnection looks like the continuous connection.
+    e2.disconnect_with(first);
+    assert_eq!(e2.wait_for_disconnect(), first);
+    thread::sleep(Duration::from_millis(100));
+    let msg = raw_message(79, 100);
+    e2.send_to(first, msg.clone());
+    match e1.next_event() {
+        Ok(NetworkEvent::MessageReceived(_, raw)) => assert_eq!(raw, msg),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+
+    // The real disconnection is reported once the window is elapsed.
+    e2.disconnect_with(first);
+    let start = Instant::now();
+    match e1.next_event() {
+        Ok(NetworkEvent::IncomingPeerDisconnected { addr, .. }) => assert_eq!(addr, second),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(900));
+}
+
+#[test]
+fn test_network_query_queue_depths() {
+    let first = "127.0.0.1:18049".parse().unwrap();
+    let second = "127.0.0.1:18050".parse().unwrap();
+
+    // The second node is not started yet, so the messages stay in the queue.
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.tcp_connect_retry_timeout = 100;
+    events.network_config.tcp_connect_max_retries = 50;
+    let e1 = events.spawn();
+    e1.send_to(second, raw_message(80, 100));
+    e1.send_to(second, raw_message(81, 100));
+    assert_eq!(e1.query_queue_depths().get(&second), Some(&2));
+
+    let mut e2 = TestEvents::with_addr(second).spawn();
+    assert_eq!(e2.wait_for_connect(), connect_message(first));
+    e2.wait_for_message();
+    e2.wait_for_message();
+    assert_eq!(e1.query_queue_depths().get(&second), Some(&0));
+}
+
+#[test]
+fn test_network_proxy_protocol() {
+    let first = "127.0.0.1:18051".parse().unwrap();
+    let second = "127.0.0.1:18052".parse().unwrap();
+    let client = "10.1.2.3:4567".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.proxy_protocol = true;
+    let mut e1 = events.spawn();
+
+    // The address of the client from the header is reported instead of the balancer's one.
+    let mut sock = connect_raw(first);
+    sock.write_all(&proxy_protocol::header(client, first)).unwrap();
+    sock.write_all(connect_message(second).raw().as_ref()).unwrap();
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerConnected(addr, connect)) => {
+            assert_eq!(addr, client);
+            assert_eq!(connect, connect_message(second));
+        }
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+
+    // The connection without the header is closed.
+    let mut sock = connect_raw(first);
+    sock.write_all(connect_message(second).raw().as_ref()).unwrap();
+    let mut buf = [0; 1];
+    assert!(sock.read(&mut buf).map(|len| len == 0).unwrap_or(true));
+}
+
+#[test]
+fn test_network_proxy_protocol_mixed_families() {
+    let first = "127.0.0.1:18062".parse().unwrap();
+    let second = "127.0.0.1:18063".parse().unwrap();
+    let third = "127.0.0.1:18064".parse().unwrap();
+    let ipv6_client: SocketAddr = "[2001:db8::1]:4567".parse().unwrap();
+    let ipv4_client: SocketAddr = "10.1.2.3:4567".parse().unwrap();
+    let ipv6_destination = "[2001:db8::2]:18062".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.proxy_protocol = true;
+    let mut e1 = events.spawn();
+
+    // The IPv4 destination is sent as the IPv4-mapped address.
+    let header = proxy_protocol::header(ipv6_client, first);
+    assert_eq!(header.len(), proxy_protocol::HEADER_LENGTH + 36);
+    let mut sock = connect_raw(first);
+    sock.write_all(&header).unwrap();
+    sock.write_all(connect_message(second).raw().as_ref()).unwrap();
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerConnected(addr, _)) => assert_eq!(addr, ipv6_client),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+
+    // The IPv4 source is reported as the IPv4-mapped address.
+    let mut sock = connect_raw(first);
+    sock.write_all(&proxy_protocol::header(ipv4_client, ipv6_destination)).unwrap();
+    sock.write_all(connect_message(third).raw().as_ref()).unwrap();
+    let mapped = SocketAddrV6::new(Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped(), 4567, 0, 0);
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerConnected(addr, _)) => assert_eq!(addr, SocketAddr::V6(mapped)),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[test]
+fn test_network_graceful_shutdown_flushes_queues() {
+    let first = "127.0.0.1:18053".parse().unwrap();
+    let second = "127.0.0.1:18054".parse().unwrap();
+
+    // The messages don't fit into the socket buffers, so the writer is still flushing
+    // them once the shutdown is requested.
+    let messages: Vec<_> = (0..50).map(|i| raw_message(i, 100_000)).collect();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.outgoing_message_queue_len = 64;
+    let mut e1 = events.spawn();
+    let mut e2 = TestEvents::with_addr(second).spawn();
+
+    e1.connect_with(second);
+    e2.wait_for_connect();
+
+    for msg in &messages {
+        e1.send_to(second, msg.clone());
+    }
+    e1.graceful_shutdown(10_000);
+
+    for msg in messages {
+        assert_eq!(e2.wait_for_message(), msg);
+    }
+}
+
+#[test]
+fn test_network_graceful_shutdown_during_reconnect() {
+    let first = "127.0.0.1:18055".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:18056".parse().unwrap();
+
+    let listener = TcpListener::bind(&second).unwrap();
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.outgoing_message_queue_len = 64;
+    let mut e1 = events.spawn();
+    e1.set_persistent_peers(vec![second]);
+
+    let connect = connect_message(first).raw().as_ref().to_vec();
+    let msg = raw_message(82, 1000);
+    e1.send_to(second, msg.clone());
+    let (mut sock, _) = listener.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut received = vec![0; connect.len() + msg.as_ref().len()];
+    sock.read_exact(&mut received).unwrap();
+    sock.write_all(&[0; 10]).unwrap();
+    assert_eq!(e1.wait_for_disconnect(), second);
+
+    // The messages are written to the reestablished connection before the node stops.
+    let messages: Vec<_> = (0..20).map(|i| raw_message(i, 100_000)).collect();
+    for msg in &messages {
+        e1.send_to(second, msg.clone());
+    }
+    let shutdown = thread::spawn(move || e1.graceful_shutdown(10_000));
+
+    let (mut sock, _) = listener.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut expected = connect;
+    for msg in &messages {
+        expected.extend_from_slice(msg.as_ref());
+    }
+    let mut received = vec![0; expected.len()];
+    sock.read_exact(&mut received).unwrap();
+    assert_eq!(received, expected);
+    shutdown.join().unwrap();
+}
+
+#[test]
+fn test_network_proxy_protocol_connections_limit() {
+    let first = "127.0.0.1:18057".parse().unwrap();
+    let second = "127.0.0.1:18058".parse().unwrap();
+    let client = "10.1.2.4:4567".parse().unwrap();
+
+    let mut events = TestEvents::with_addr(first);
+    events.network_config.proxy_protocol = true;
+    events.network_config.max_incoming_connections = 1;
+    let mut e1 = events.spawn();
+
+    // The socket which hasn't sent the header takes the only slot.
+    let idle = connect_raw(first);
+    thread::sleep(Duration::from_millis(100));
+    let mut sock = connect_raw(first);
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert!(sock.read(&mut buf).map(|len| len == 0).unwrap_or(true));
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    let mut sock = connect_raw(first);
+    sock.write_all(&proxy_protocol::header(client, first)).unwrap();
+    sock.write_all(connect_message(second).raw().as_ref()).unwrap();
+    match e1.wait_for_event() {
+        Ok(NetworkEvent::PeerConnected(addr, _)) => assert_eq!(addr, client),
+        other => panic!("Unexpected event received, {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_transport_stale_socket() {
+    let addr = "127.0.0.1:18059".parse().unwrap();
+    let path = ::std::env::temp_dir().join("exonum-test-18059.sock");
+    ::std::fs::remove_file(&path).ok();
+
+    // The socket file is left by the previous run.
+    drop(::std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let transport = UnixTransport::new(NetworkConfiguration::default())
+        .with_endpoint(addr, Endpoint::Unix(path.clone()));
+    let core = Core::new().unwrap();
+    assert!(transport.listen(addr, &core.handle()).is_ok());
+    ::std::fs::remove_file(&path).ok();
+
+    // The addresses of the live connections are not reused.
+    let mut peers = UnixPeers::default();
+    let first = peers.allocate().unwrap();
+    for _ in 0..u16::max_value() - 1 {
+        assert_ne!(peers.allocate(), Some(first));
+    }
+    assert_eq!(peers.allocate(), None);
+    peers.release(first);
+    assert_eq!(peers.allocate(), Some(first));
+}
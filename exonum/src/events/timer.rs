@@ -0,0 +1,147 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timers used by the network part for the timeouts and retries.
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+
+use super::error::other_error;
+
+/// Future that resolves once the delay is elapsed.
+pub type Delay = Box<Future<Item = (), Error = io::Error>>;
+
+/// Stream that yields an item every period.
+pub type Ticks = Box<Stream<Item = (), Error = io::Error>>;
+
+/// Source of the delays and intervals.
+pub trait Timer: fmt::Debug + Send {
+    /// Returns the future which resolves after the given duration.
+    fn delay(&self, duration: Duration, handle: &Handle) -> Delay;
+
+    /// Returns the stream which yields an item every `period`, starting after the first one.
+    fn interval(&self, period: Duration, handle: &Handle) -> Ticks;
+}
+
+/// Default timer which uses the wall-clock time of the event loop.
+#[derive(Debug, Clone, Default)]
+pub struct ReactorTimer;
+
+impl Timer for ReactorTimer {
+    fn delay(&self, duration: Duration, handle: &Handle) -> Delay {
+        match Timeout::new(duration, handle) {
+            Ok(timeout) => Box::new(timeout),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn interval(&self, period: Duration, handle: &Handle) -> Ticks {
+        match Interval::new(period, handle) {
+            Ok(interval) => Box::new(interval),
+            Err(e) => Box::new(future::err(e).into_stream()),
+        }
+    }
+}
+
+/// Timer with the virtual time which is advanced manually, intended for tests.
+#[derive(Debug, Clone, Default)]
+pub struct MockTimer {
+    inner: Arc<Mutex<MockClock>>,
+}
+
+#[derive(Debug, Default)]
+struct MockClock {
+    now: Duration,
+    entries: Vec<MockEntry>,
+}
+
+#[derive(Debug)]
+struct MockEntry {
+    deadline: Duration,
+    /// Is `None` for the delays.
+    period: Option<Duration>,
+    tx: mpsc::UnboundedSender<()>,
+}
+
+impl MockTimer {
+    pub fn new() -> MockTimer {
+        MockTimer::default()
+    }
+
+    /// Returns the virtual time elapsed since the timer is created.
+    pub fn now(&self) -> Duration {
+        self.inner.lock().unwrap().now
+    }
+
+    /// Advances the virtual time, firing the elapsed delays and intervals.
+    pub fn advance(&self, duration: Duration) {
+        let mut clock = self.inner.lock().unwrap();
+        clock.now += duration;
+        let now = clock.now;
+        clock.entries.retain(|entry| !entry.tx.is_closed());
+        for entry in &mut clock.entries {
+            while entry.deadline <= now {
+                entry.tx.unbounded_send(()).ok();
+                match entry.period {
+                    Some(period) if period > Duration::from_secs(0) => entry.deadline += period,
+                    _ => break,
+                }
+            }
+        }
+        clock.entries.retain(
+            |entry| entry.period.is_some() || entry.deadline > now,
+        );
+    }
+
+    fn register(
+        &self,
+        duration: Duration,
+        period: Option<Duration>,
+    ) -> mpsc::UnboundedReceiver<()> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut clock = self.inner.lock().unwrap();
+        let deadline = clock.now + duration;
+        clock.entries.push(MockEntry {
+            deadline,
+            period,
+            tx,
+        });
+        rx
+    }
+}
+
+impl Timer for MockTimer {
+    fn delay(&self, duration: Duration, _handle: &Handle) -> Delay {
+        let fut = self.register(duration, None)
+            .into_future()
+            .map_err(|_| other_error("mock timer error"))
+            .and_then(|(tick, _)| {
+                tick.ok_or_else(|| other_error("mock timer is dropped"))
+            });
+        Box::new(fut)
+    }
+
+    fn interval(&self, period: Duration, _handle: &Handle) -> Ticks {
+        let ticks = self.register(period, Some(period)).map_err(
+            |_| other_error("mock timer error"),
+        );
+        Box::new(ticks)
+    }
+}
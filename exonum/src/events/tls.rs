@@ -0,0 +1,129 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS encryption of the peer connections.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use futures::{future, Future};
+use native_tls::{self, Certificate, Identity};
+use tokio_tls::{TlsAcceptor, TlsConnector};
+
+use super::error::into_other;
+use super::transport::{PeerStream, PeerStreamFuture};
+
+/// TLS settings of the peer connections.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// Path to the PEM encoded certificate of the node.
+    pub cert_path: PathBuf,
+    /// Path to the PEM encoded PKCS #8 private key of the node.
+    pub key_path: PathBuf,
+    /// Path to the PEM encoded CA certificate used to verify the peers. If it is not set,
+    /// the traffic is encrypted, but the certificates of the peers are not verified.
+    pub ca_path: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let acceptor = native_tls::TlsAcceptor::new(self.identity()?).map_err(into_other)?;
+        Ok(TlsAcceptor::from(acceptor))
+    }
+
+    fn connector(&self) -> io::Result<TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.identity(self.identity()?);
+        match self.ca_path {
+            Some(ref ca_path) => {
+                let ca = Certificate::from_pem(&read_file(ca_path)?).map_err(into_other)?;
+                // Peers are addressed by the socket addresses, so only the CA signature
+                // is checked.
+                builder
+                    .add_root_certificate(ca)
+                    .disable_built_in_roots(true)
+                    .danger_accept_invalid_hostnames(true);
+            }
+            None => {
+                builder.danger_accept_invalid_certs(true);
+            }
+        }
+        let connector = builder.build().map_err(into_other)?;
+        Ok(TlsConnector::from(connector))
+    }
+
+    fn identity(&self) -> io::Result<Identity> {
+        let cert = read_file(&self.cert_path)?;
+        let key = read_file(&self.key_path)?;
+        Identity::from_pkcs8(&cert, &key).map_err(into_other)
+    }
+}
+
+/// Acceptor and connector created from the `TlsConfig`.
+#[derive(Clone)]
+pub(crate) struct TlsContext {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl TlsContext {
+    pub(crate) fn new(config: &TlsConfig) -> io::Result<TlsContext> {
+        Ok(TlsContext {
+            acceptor: config.acceptor()?,
+            connector: config.connector()?,
+        })
+    }
+}
+
+impl fmt::Debug for TlsContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsContext").finish()
+    }
+}
+
+/// Performs the server side of the TLS handshake if the encryption is enabled.
+pub(crate) fn accept(tls: Option<&TlsContext>, sock: Box<PeerStream>) -> PeerStreamFuture {
+    match tls {
+        Some(tls) => Box::new(tls.acceptor.accept(sock).map_err(into_other).map(
+            |stream| Box::new(stream) as Box<PeerStream>,
+        )),
+        None => Box::new(future::ok(sock)),
+    }
+}
+
+/// Performs the client side of the TLS handshake if the encryption is enabled.
+pub(crate) fn connect(
+    tls: Option<&TlsContext>,
+    peer: SocketAddr,
+    sock: Box<PeerStream>,
+) -> PeerStreamFuture {
+    match tls {
+        Some(tls) => {
+            let domain = peer.ip().to_string();
+            Box::new(tls.connector.connect(&domain, sock).map_err(into_other).map(
+                |stream| Box::new(stream) as Box<PeerStream>,
+            ))
+        }
+        None => Box::new(future::ok(sock)),
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
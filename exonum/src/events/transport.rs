@@ -0,0 +1,594 @@
+// Copyright 2017 The Exonum Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transports used to establish the peer connections.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::cell::RefCell;
+#[cfg(unix)]
+use std::collections::HashSet;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+#[cfg(target_os = "linux")]
+use libc;
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio_uds::{UnixListener, UnixStream};
+
+use super::error::other_error;
+use super::network::{NetworkConfiguration, TcpKeepAliveConfig};
+use super::socks5;
+
+/// Socket of the peer connection, either plain or encrypted.
+pub trait PeerStream: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> PeerStream for T {}
+
+/// Future that resolves into the established peer connection.
+pub type PeerStreamFuture = Box<Future<Item = Box<PeerStream>, Error = io::Error>>;
+
+/// Incoming connections together with the addresses of the peers.
+pub type IncomingConnections = Box<
+    Stream<Item = (Box<PeerStream>, SocketAddr), Error = io::Error>,
+>;
+
+/// Establishes the connections between the peers.
+pub trait Transport: fmt::Debug + Send {
+    /// Opens the outgoing connection with the peer. The address is the key of the connection
+    /// in the pool, so it should be used as is, e.g. keeping the scope id of the link-local
+    /// IPv6 addresses.
+    fn connect(&self, peer: SocketAddr, handle: &Handle) -> PeerStreamFuture;
+
+    /// Starts accepting the incoming connections on the given address,
+    /// also returns the actual address of the listener.
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        handle: &Handle,
+    ) -> io::Result<(IncomingConnections, SocketAddr)>;
+
+    /// Applies the updated configuration to the new connections, does nothing by default.
+    fn reconfigure(&self, _network_config: &NetworkConfiguration) {}
+}
+
+/// Default transport which uses TCP sockets configured by `NetworkConfiguration`.
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    network_config: Arc<Mutex<NetworkConfiguration>>,
+}
+
+impl TcpTransport {
+    pub fn new(network_config: NetworkConfiguration) -> TcpTransport {
+        TcpTransport { network_config: Arc::new(Mutex::new(network_config)) }
+    }
+
+    fn network_config(&self) -> NetworkConfiguration {
+        self.network_config.lock().unwrap().clone()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, peer: SocketAddr, handle: &Handle) -> PeerStreamFuture {
+        let network_config = self.network_config();
+        let proxy = network_config.proxy.clone();
+        let address = proxy.as_ref().map_or(peer, |proxy| proxy.address);
+        let fut = TcpStream::connect(&address, handle)
+            .and_then(move |sock| {
+                configure_socket(&sock, &network_config)?;
+                Ok(sock)
+            })
+            .and_then(move |sock| match proxy {
+                Some(proxy) => Either::A(socks5::connect(sock, peer, proxy.auth)),
+                None => Either::B(future::ok(sock)),
+            })
+            .map(|sock| Box::new(sock) as Box<PeerStream>);
+        Box::new(fut)
+    }
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        handle: &Handle,
+    ) -> io::Result<(IncomingConnections, SocketAddr)> {
+        let listener = bind_listener(addr, &self.network_config(), handle)?;
+        let local_addr = listener.local_addr()?;
+        let network_config = Arc::clone(&self.network_config);
+        let incoming = listener.incoming().map(move |(sock, addr)| {
+            set_buffer_sizes(&sock, &network_config.lock().unwrap());
+            (Box::new(sock) as Box<PeerStream>, addr)
+        });
+        Ok((Box::new(incoming), local_addr))
+    }
+
+    fn reconfigure(&self, network_config: &NetworkConfiguration) {
+        *self.network_config.lock().unwrap() = network_config.clone();
+    }
+}
+
+/// Endpoint on which the node accepts the peer connections.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// TCP socket address.
+    Tcp(SocketAddr),
+    /// Path of the Unix domain socket.
+    Unix(PathBuf),
+}
+
+/// Transport which uses Unix domain sockets for the nodes with `Endpoint::Unix` endpoints
+/// and TCP for the rest, intended for the nodes running on the same host.
+///
+/// The nodes are still identified by their socket addresses, which are mapped to the endpoints.
+/// Incoming Unix connections have no peer address, so the addresses `0.0.0.0:N` are assigned
+/// to them. The address is not reused while the connection is alive, the new connections
+/// are rejected if all the addresses are taken.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixTransport {
+    tcp: TcpTransport,
+    endpoints: HashMap<SocketAddr, Endpoint>,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub fn new(network_config: NetworkConfiguration) -> UnixTransport {
+        UnixTransport {
+            tcp: TcpTransport::new(network_config),
+            endpoints: HashMap::new(),
+        }
+    }
+
+    /// Sets the endpoint of the node with the given address, it is used both to connect
+    /// to the node and to listen on this address.
+    pub fn with_endpoint(mut self, addr: SocketAddr, endpoint: Endpoint) -> UnixTransport {
+        self.endpoints.insert(addr, endpoint);
+        self
+    }
+
+    fn endpoint(&self, addr: SocketAddr) -> Endpoint {
+        self.endpoints.get(&addr).cloned().unwrap_or(
+            Endpoint::Tcp(addr),
+        )
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn connect(&self, peer: SocketAddr, handle: &Handle) -> PeerStreamFuture {
+        match self.endpoint(peer) {
+            Endpoint::Tcp(addr) => self.tcp.connect(addr, handle),
+            Endpoint::Unix(path) => {
+                let sock = UnixStream::connect(&path, handle).map(|sock| {
+                    Box::new(sock) as Box<PeerStream>
+                });
+                Box::new(future::result(sock))
+            }
+        }
+    }
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        handle: &Handle,
+    ) -> io::Result<(IncomingConnections, SocketAddr)> {
+        let path = match self.endpoint(addr) {
+            Endpoint::Tcp(addr) => return self.tcp.listen(addr, handle),
+            Endpoint::Unix(path) => path,
+        };
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path, handle)?;
+        trace!("Listening on Unix socket path={}", path.display());
+        let peers: Rc<RefCell<UnixPeers>> = Rc::default();
+        let incoming = listener.incoming().filter_map(move |(sock, _)| {
+            let port = match peers.borrow_mut().allocate() {
+                Some(port) => port,
+                None => {
+                    warn!("Rejected incoming Unix connection, all peer addresses are taken.");
+                    return None;
+                }
+            };
+            let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+            let sock = UnixPeerStream {
+                sock,
+                port,
+                peers: Rc::clone(&peers),
+            };
+            Some((Box::new(sock) as Box<PeerStream>, peer))
+        });
+        Ok((Box::new(incoming), addr))
+    }
+
+    fn reconfigure(&self, network_config: &NetworkConfiguration) {
+        self.tcp.reconfigure(network_config);
+    }
+}
+
+/// Removes the socket file left by the previous run of the node, so it can be bound again.
+/// The socket which is still listened on is kept.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    match ::std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            trace!("Removing stale Unix socket path={}", path.display());
+            fs::remove_file(path)
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+/// Ports of the addresses `0.0.0.0:N` assigned to the live incoming Unix connections.
+#[cfg(unix)]
+#[derive(Debug, Default)]
+pub(crate) struct UnixPeers {
+    live: HashSet<u16>,
+    last: u16,
+}
+
+#[cfg(unix)]
+impl UnixPeers {
+    /// Returns the port which is not used by the live connections, if there is any.
+    pub(crate) fn allocate(&mut self) -> Option<u16> {
+        for _ in 0..u32::from(u16::max_value()) + 1 {
+            self.last = self.last.wrapping_add(1);
+            if self.last != 0 && self.live.insert(self.last) {
+                return Some(self.last);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn release(&mut self, port: u16) {
+        self.live.remove(&port);
+    }
+}
+
+/// Incoming Unix connection which releases its address once it is closed.
+#[cfg(unix)]
+struct UnixPeerStream {
+    sock: UnixStream,
+    port: u16,
+    peers: Rc<RefCell<UnixPeers>>,
+}
+
+#[cfg(unix)]
+impl Read for UnixPeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl Write for UnixPeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+#[cfg(unix)]
+impl AsyncRead for UnixPeerStream {}
+
+#[cfg(unix)]
+impl AsyncWrite for UnixPeerStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.sock)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixPeerStream {
+    fn drop(&mut self) {
+        self.peers.borrow_mut().release(self.port);
+    }
+}
+
+/// In-memory transport which connects the nodes sharing the same instance without
+/// any sockets, intended for tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTransport {
+    inner: Arc<Mutex<MemoryNetwork>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryNetwork {
+    listeners: HashMap<SocketAddr, mpsc::UnboundedSender<(MemoryStream, SocketAddr)>>,
+    /// Used to generate the addresses of the outgoing connections.
+    connections_count: u16,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect(&self, peer: SocketAddr, _handle: &Handle) -> PeerStreamFuture {
+        let mut network = self.inner.lock().unwrap();
+        network.connections_count = network.connections_count.wrapping_add(1);
+        let addr = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            network.connections_count,
+        );
+
+        let (local, remote) = MemoryStream::pair();
+        let accepted = match network.listeners.get(&peer) {
+            Some(listener) => listener.unbounded_send((remote, addr)).is_ok(),
+            None => false,
+        };
+        if accepted {
+            Box::new(future::ok(Box::new(local) as Box<PeerStream>))
+        } else {
+            let e = io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("there is no listener on {}", peer),
+            );
+            Box::new(future::err(e))
+        }
+    }
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        _handle: &Handle,
+    ) -> io::Result<(IncomingConnections, SocketAddr)> {
+        let mut network = self.inner.lock().unwrap();
+        if let Some(listener) = network.listeners.get(&addr) {
+            // The listener of the stopped node can be replaced.
+            if !listener.is_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("address {} is already in use", addr),
+                ));
+            }
+        }
+        let (listener, incoming) = mpsc::unbounded();
+        network.listeners.insert(addr, listener);
+        let incoming = incoming
+            .map(|(stream, peer)| (Box::new(stream) as Box<PeerStream>, peer))
+            .map_err(|_| other_error("memory listener is closed"));
+        Ok((Box::new(incoming), addr))
+    }
+}
+
+/// One side of the in-memory connection, the connection is closed once any side is dropped.
+#[derive(Debug)]
+pub struct MemoryStream {
+    /// Is `None` once the write half is shut down.
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Received bytes that haven't been read yet.
+    buffer: Vec<u8>,
+}
+
+impl MemoryStream {
+    fn pair() -> (MemoryStream, MemoryStream) {
+        let (first_tx, first_rx) = mpsc::unbounded();
+        let (second_tx, second_rx) = mpsc::unbounded();
+        let first = MemoryStream {
+            tx: Some(first_tx),
+            rx: second_rx,
+            buffer: Vec::new(),
+        };
+        let second = MemoryStream {
+            tx: Some(second_tx),
+            rx: first_rx,
+            buffer: Vec::new(),
+        };
+        (first, second)
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.buffer.is_empty() {
+            match self.rx.poll() {
+                Ok(Async::Ready(Some(data))) => self.buffer = data,
+                Ok(Async::Ready(None)) => return Ok(0),
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(()) => return Err(other_error("memory stream error")),
+            }
+        }
+        let len = cmp::min(buf.len(), self.buffer.len());
+        buf[..len].copy_from_slice(&self.buffer[..len]);
+        self.buffer.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sent = match self.tx {
+            Some(ref tx) => tx.unbounded_send(buf.to_vec()).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(buf.len())
+        } else {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MemoryStream {}
+
+impl AsyncWrite for MemoryStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.tx = None;
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Size of the queue of the incoming connections used by `TcpListener::bind`.
+const DEFAULT_LISTEN_BACKLOG: i32 = 1024;
+
+fn bind_listener(
+    addr: SocketAddr,
+    network_config: &NetworkConfiguration,
+    handle: &Handle,
+) -> io::Result<TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    set_reuse_options(&builder, network_config)?;
+    let backlog = network_config.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG);
+    let listener = builder.bind(addr)?.listen(backlog)?;
+    TcpListener::from_listener(listener, &addr, handle)
+}
+
+#[cfg(unix)]
+fn set_reuse_options(
+    builder: &TcpBuilder,
+    network_config: &NetworkConfiguration,
+) -> io::Result<()> {
+    builder.reuse_address(network_config.reuse_address)?;
+    builder.reuse_port(network_config.reuse_port)?;
+    Ok(())
+}
+
+/// `SO_REUSEADDR` allows to bind to the address in use on Windows, so it is not set.
+#[cfg(not(unix))]
+fn set_reuse_options(
+    _builder: &TcpBuilder,
+    network_config: &NetworkConfiguration,
+) -> io::Result<()> {
+    if network_config.reuse_port {
+        warn!("Reusing the listen port is not supported on this platform, ignoring.");
+    }
+    Ok(())
+}
+
+fn configure_socket(sock: &TcpStream, network_config: &NetworkConfiguration) -> io::Result<()> {
+    sock.set_nodelay(network_config.tcp_nodelay)?;
+    let keep_alive = network_config.tcp_keep_alive.as_ref();
+    sock.set_keepalive(keep_alive.map(|keep_alive| {
+        Duration::from_millis(keep_alive.idle)
+    }))?;
+    if let Some(keep_alive) = keep_alive {
+        set_keepalive_probes(sock, keep_alive)?;
+    }
+    set_buffer_sizes(sock, network_config);
+    Ok(())
+}
+
+/// Sets the interval and the number of the keepalive probes, which are not supported
+/// by `TcpStream::set_keepalive`.
+#[cfg(target_os = "linux")]
+fn set_keepalive_probes(sock: &TcpStream, keep_alive: &TcpKeepAliveConfig) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = sock.as_raw_fd();
+    if let Some(interval) = keep_alive.interval {
+        let secs = cmp::max(interval / 1000, 1) as libc::c_int;
+        set_tcp_option(fd, libc::TCP_KEEPINTVL, secs)?;
+    }
+    if let Some(retries) = keep_alive.retries {
+        set_tcp_option(fd, libc::TCP_KEEPCNT, retries as libc::c_int)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_keepalive_probes(_sock: &TcpStream, keep_alive: &TcpKeepAliveConfig) -> io::Result<()> {
+    if keep_alive.interval.is_some() || keep_alive.retries.is_some() {
+        warn!("Keepalive interval and retries are not supported on this platform, ignoring.");
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_option(
+    fd: ::std::os::unix::io::RawFd,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            ::std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Applies the configured socket buffer sizes, the failures are only logged
+/// because the connection is still usable with the default buffers.
+fn set_buffer_sizes(sock: &TcpStream, network_config: &NetworkConfiguration) {
+    if let Some(size) = network_config.socket_send_buffer {
+        let actual = sock.set_send_buffer_size(size).and_then(
+            |_| sock.send_buffer_size(),
+        );
+        check_buffer_size("send", size, actual);
+    }
+    if let Some(size) = network_config.socket_recv_buffer {
+        let actual = sock.set_recv_buffer_size(size).and_then(
+            |_| sock.recv_buffer_size(),
+        );
+        check_buffer_size("receive", size, actual);
+    }
+}
+
+fn check_buffer_size(kind: &str, requested: usize, actual: io::Result<usize>) {
+    match actual {
+        Ok(actual) if actual < requested => {
+            warn!(
+                "Socket {} buffer size is clamped by the OS, requested={}, actual={}",
+                kind,
+                requested,
+                actual
+            )
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Unable to set socket {} buffer size={}: {}", kind, requested, e),
+    }
+}
@@ -40,6 +40,9 @@ extern crate bit_vec;
 extern crate vec_map;
 #[cfg(test)]
 extern crate tempdir;
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
 extern crate env_logger;
 extern crate colored;
 extern crate term;
@@ -60,6 +63,14 @@ extern crate tokio_timer;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_retry;
+extern crate tokio_tls;
+extern crate native_tls;
+extern crate net2;
+extern crate lz4;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[cfg(unix)]
+extern crate libc;
 
 #[macro_use]
 pub mod encoding;
@@ -124,6 +124,9 @@ impl NodeHandler {
             }
         }
         self.state.add_peer(public_key, message.clone());
+        if self.state.find_validator(public_key).is_some() {
+            self.update_persistent_peers();
+        }
         info!(
             "Received Connect message from {}, {}",
             address,
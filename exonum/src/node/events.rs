@@ -40,9 +40,45 @@ impl NodeHandler {
     fn handle_network_event(&mut self, event: NetworkEvent) {
         match event {
             NetworkEvent::PeerConnected(peer, connect) => self.handle_connected(peer, connect),
-            NetworkEvent::PeerDisconnected(peer) => self.handle_disconnected(peer),
-            NetworkEvent::UnableConnectToPeer(peer) => self.handle_unable_to_connect(peer),
-            NetworkEvent::MessageReceived(peer, raw) => self.handle_message(peer, raw),
+            NetworkEvent::PeerDisconnected { addr, duration } => {
+                trace!("Disconnected from peer={} after {:?}", addr, duration);
+                self.handle_disconnected(addr)
+            }
+            NetworkEvent::UnableConnectToPeer(peer) |
+            NetworkEvent::ConnectFailed(peer, _) => self.handle_unable_to_connect(peer),
+            NetworkEvent::MessageReceived(peer, raw) |
+            NetworkEvent::FirstMessage(peer, raw) => self.handle_message(peer, raw),
+            NetworkEvent::MessagesReceived(peer, raws) => {
+                for raw in raws {
+                    self.handle_message(peer, raw);
+                }
+            }
+            NetworkEvent::PeerErrored(peer, reason) => {
+                warn!("Connection with peer={} is closed: {}", peer, reason)
+            }
+            NetworkEvent::PeerConnectedOutgoing { addr, connect_duration } => {
+                trace!("Connected with peer={} in {:?}", addr, connect_duration)
+            }
+            NetworkEvent::SendQueueOverflow(peer) => {
+                warn!("Outgoing queue of peer={} is overflowed", peer)
+            }
+            NetworkEvent::ConnectRetry { addr, attempt } => {
+                trace!("Retrying to connect with peer={}, attempt {}", addr, attempt)
+            }
+            NetworkEvent::MessageSent { addr, token } => {
+                trace!("Sent message to peer={}, token={}", addr, token)
+            }
+            NetworkEvent::OutgoingLimitReached => warn!("Outgoing connections limit is reached"),
+            NetworkEvent::OutgoingLimitRelieved => info!("Outgoing connections limit is relieved"),
+            NetworkEvent::PeerReconnected(peer) => info!("Peer={} has reconnected", peer),
+            NetworkEvent::UnknownPeerKey(key) => warn!("Peer with key={:?} is unknown", key),
+            NetworkEvent::UnresolvedHost(host) => warn!("Host={} has no addresses", host),
+            NetworkEvent::IncomingPeerDisconnected { addr, duration } => {
+                trace!("Incoming connection with peer={} is closed after {:?}", addr, duration)
+            }
+            NetworkEvent::PreConnectMessage(peer, raw) => {
+                trace!("Received message={:?} from peer={} before Connect", raw, peer)
+            }
         }
     }
 
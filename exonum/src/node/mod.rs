@@ -38,7 +38,7 @@ use tokio_core::reactor::Core;
 use crypto::{self, Hash, PublicKey, SecretKey};
 use blockchain::{Blockchain, GenesisConfig, Schema, SharedNodeState, Transaction, Service};
 use api::{private, public, Api};
-use messages::{Connect, Message, RawMessage};
+use messages::{Connect, Message, RawMessage, PROTOCOL_MAJOR_VERSION};
 use events::{NetworkRequest, TimeoutRequest, NetworkEvent, InternalRequest, InternalEvent,
              SyncSender, HandlerPart, NetworkConfiguration, NetworkPart, InternalPart};
 use events::error::{into_other, other_error, LogError, log_error};
@@ -529,6 +529,19 @@ impl NodeHandler {
         }
     }
 
+    /// Makes the connections with the validators persistent, so they are reestablished
+    /// by the network once failed.
+    pub fn update_persistent_peers(&mut self) {
+        let peers = self.state
+            .peers()
+            .iter()
+            .filter(|&(key, _)| self.state.find_validator(*key).is_some())
+            .map(|(_, connect)| connect.addr())
+            .collect();
+        let request = NetworkRequest::SetPersistentPeers(peers);
+        self.channel.network_requests.send(request).log_error();
+    }
+
     /// Performs connection to the specified network address.
     pub fn connect(&mut self, address: &SocketAddr) {
         let connect = self.state.our_connect_message().clone();
@@ -788,7 +801,7 @@ impl Node {
         };
         let api_state = SharedNodeState::new(node_cfg.api.state_update_timeout as u64);
         let system_state = Box::new(DefaultSystemState(node_cfg.listen_address));
-        let network_config = config.network;
+        let network_config = config.network.clone();
         let handler = NodeHandler::new(
             blockchain,
             external_address,
@@ -819,7 +832,7 @@ impl Node {
             core.handle().spawn(
                 timeouts_part.run(handle).map_err(log_error),
             );
-            let network_handler = network_part.run(&core.handle());
+            let network_handler = network_part.into_future(core.handle());
             core.run(network_handler).map(drop).map_err(|e| {
                 other_error(&format!("An error in the `Network` thread occurred: {}", e))
             })
@@ -889,22 +902,37 @@ impl Node {
         let connect_message = self.state().our_connect_message().clone();
         let (network_tx, network_rx) = self.channel.network_events;
         let internal_requests_rx = self.channel.internal_requests.1;
+        let network_requests_tx = self.channel.network_requests.0.clone();
         let network_part = NetworkPart {
             our_connect_message: connect_message,
-            listen_address: self.handler.system_state.listen_address(),
+            protocol_version: PROTOCOL_MAJOR_VERSION,
+            listen_addresses: vec![self.handler.system_state.listen_address()],
             network_requests: self.channel.network_requests,
+            control_requests: None,
             network_tx: network_tx,
             network_config: self.network_config,
             max_message_len: self.max_message_len,
+            local_addresses_tx: None,
+            ready_tx: None,
+            connection_filter: None,
+            message_verifier: None,
+            shutdown_rx: None,
+            transport: None,
+            timer: None,
+            metrics: None,
+            codec_factory: None,
+            subscribers: Vec::new(),
+            running: None,
         };
 
         let (internal_tx, internal_rx) = self.channel.internal_events;
-        let handler_part = HandlerPart {
-            handler: self.handler,
+        let handler_part = HandlerPart::new(
+            self.handler,
             internal_rx,
             network_rx,
-            api_rx: self.channel.api_requests.1,
-        };
+            self.channel.api_requests.1,
+            network_requests_tx,
+        );
 
         let timeouts_part = InternalPart {
             internal_tx,
@@ -38,7 +38,7 @@ use exonum::crypto::{gen_keypair_from_seed, CryptoHash, Hash, PublicKey, SecretK
 use exonum::crypto::gen_keypair;
 use exonum::helpers::{Height, Milliseconds, Round, ValidatorId};
 use exonum::events::{Event, InternalEvent, EventHandler, NetworkEvent, NetworkRequest,
-                     TimeoutRequest, InternalRequest};
+                     TimeoutRequest, InternalRequest, SendResult};
 use exonum::events::network::NetworkConfiguration;
 
 use timestamping::TimestampingService;
@@ -92,8 +92,37 @@ impl SandboxInner {
         let network_getter = futures::lazy(|| -> Result<(), ()> {
             while let Async::Ready(Some(network)) = self.network_requests_rx.poll()? {
                 match network {
-                    NetworkRequest::SendMessage(peer, msg) => self.sent.push_back((peer, msg)),
+                    NetworkRequest::SendMessage(peer, msg) |
+                    NetworkRequest::SendMessagePriority(peer, msg, _) |
+                    NetworkRequest::SendMessageWithPolicy(peer, msg, _) |
+                    NetworkRequest::SendMessageTracked(peer, msg, _) |
+                    NetworkRequest::SendMessageAndDisconnect(peer, msg) => {
+                        self.sent.push_back((peer, msg))
+                    }
+                    NetworkRequest::SendMessages(peer, msgs) => {
+                        self.sent.extend(msgs.into_iter().map(|msg| (peer, msg)))
+                    }
+                    NetworkRequest::SendMessageAck(peer, msg, result_tx) => {
+                        self.sent.push_back((peer, msg));
+                        result_tx.send(SendResult::Queued).ok();
+                    }
+                    NetworkRequest::SendMessageToKey(..) |
+                    NetworkRequest::SendMessageToHost(..) |
+                    NetworkRequest::Connect(_) |
+                    NetworkRequest::RegisterSinkPeer(..) |
+                    NetworkRequest::SetPersistentPeers(..) |
                     NetworkRequest::DisconnectWithPeer(_) |
+                    NetworkRequest::DisconnectWithPeerGraceful(_) |
+                    NetworkRequest::DisconnectAll { .. } |
+                    NetworkRequest::StopSending(_) |
+                    NetworkRequest::QueryConnections(_) |
+                    NetworkRequest::IsConnected(..) |
+                    NetworkRequest::QuerySendCounts(_) |
+                    NetworkRequest::QueryTraffic(_) |
+                    NetworkRequest::QueryLastReceived(_) |
+                    NetworkRequest::QueryQueueDepths(_) |
+                    NetworkRequest::Reconfigure(_) |
+                    NetworkRequest::GracefulShutdown { .. } |
                     NetworkRequest::Shutdown => {}
                 }
             }